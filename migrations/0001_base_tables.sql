@@ -0,0 +1,21 @@
+-- The base tables. Databases from before migrations already have `users` and
+-- `documents`, with shares in the `documents.shared_with` CSV column, so they
+-- only gain `document_shares`.
+CREATE TABLE IF NOT EXISTS users (
+    uid TEXT PRIMARY KEY,
+    key_blob BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS documents (
+    doc_id TEXT PRIMARY KEY,
+    name TEXT,
+    user_id TEXT,
+    shared_with TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(uid)
+);
+CREATE TABLE IF NOT EXISTS document_shares (
+    doc_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    PRIMARY KEY (doc_id, user_id),
+    FOREIGN KEY (doc_id) REFERENCES documents(doc_id),
+    FOREIGN KEY (user_id) REFERENCES users(uid)
+);
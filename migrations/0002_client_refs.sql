@@ -0,0 +1,3 @@
+-- Per-owner client references for retry-safe document creation.
+ALTER TABLE documents ADD COLUMN client_ref TEXT;
+CREATE UNIQUE INDEX documents_client_ref ON documents(user_id, client_ref);
@@ -0,0 +1,11 @@
+-- Modification tracking and the audit log.
+ALTER TABLE documents ADD COLUMN last_updated TEXT;
+ALTER TABLE documents ADD COLUMN last_modified_by TEXT;
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TEXT NOT NULL,
+    action TEXT NOT NULL,
+    actor_key_id TEXT NOT NULL,
+    target TEXT,
+    result TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Co-owners. `documents.user_id` stays as the primary owner.
+CREATE TABLE document_owners (
+    doc_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    PRIMARY KEY (doc_id, user_id),
+    FOREIGN KEY (doc_id) REFERENCES documents(doc_id),
+    FOREIGN KEY (user_id) REFERENCES users(uid)
+);
+INSERT INTO document_owners (doc_id, user_id)
+    SELECT doc_id, user_id FROM documents WHERE user_id IN (SELECT uid FROM users);
@@ -0,0 +1,8 @@
+-- Deletions, kept around for /sync.
+CREATE TABLE tombstones (
+    doc_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    deleted_at TEXT NOT NULL,
+    PRIMARY KEY (doc_id, user_id)
+);
+CREATE INDEX tombstones_user_id ON tombstones(user_id, deleted_at);
@@ -0,0 +1,8 @@
+-- The server's own keys. The current one has no `retired_at`.
+CREATE TABLE server_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    secret_key BLOB NOT NULL,
+    public_key BLOB NOT NULL,
+    created_at TEXT NOT NULL,
+    retired_at TEXT
+);
@@ -0,0 +1,2 @@
+-- Document bodies.
+ALTER TABLE documents ADD COLUMN content BLOB;
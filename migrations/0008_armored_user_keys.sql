@@ -0,0 +1,3 @@
+-- Users' keys are kept armored so the database can be read with ordinary
+-- tools. `armor_user_keys` converts rows stored in binary.
+ALTER TABLE users RENAME COLUMN key_blob TO public_key;
@@ -0,0 +1,2 @@
+-- What each share grants. Existing shares were all read-only.
+ALTER TABLE document_shares ADD COLUMN permission TEXT NOT NULL DEFAULT 'read';
@@ -0,0 +1,2 @@
+-- `/audit` pages through one actor's entries by time.
+CREATE INDEX audit_log_actor_ts ON audit_log(actor_key_id, ts);
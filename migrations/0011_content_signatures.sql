@@ -0,0 +1,2 @@
+-- Armored detached signatures over `content`, when uploaded with one.
+ALTER TABLE documents ADD COLUMN content_signature TEXT;
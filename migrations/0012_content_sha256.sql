@@ -0,0 +1,3 @@
+-- Hex SHA-256 of `content`, set on upload. Older content has none until it's
+-- next uploaded.
+ALTER TABLE documents ADD COLUMN content_sha256 TEXT;
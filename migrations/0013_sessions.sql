@@ -0,0 +1,10 @@
+-- Bearer-token sessions, kept so revocation survives restarts.
+CREATE TABLE sessions (
+    token_id TEXT PRIMARY KEY,
+    token_sha256 TEXT NOT NULL UNIQUE,
+    key_id TEXT NOT NULL REFERENCES users(uid),
+    issued_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    revoked_at TEXT
+);
+CREATE INDEX sessions_key_id ON sessions(key_id);
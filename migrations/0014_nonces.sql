@@ -0,0 +1,7 @@
+-- Single-use nonces for signed requests.
+CREATE TABLE nonces (
+    nonce TEXT PRIMARY KEY,
+    issued_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL
+);
+CREATE INDEX nonces_expires_at ON nonces(expires_at);
@@ -0,0 +1,2 @@
+-- Documents whose content must always be uploaded signed.
+ALTER TABLE documents ADD COLUMN require_signed_content INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- The primary user id's address, for email lookup and WKD. Accounts registered
+-- before this aren't indexed.
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users ADD COLUMN email_domain TEXT;
+ALTER TABLE users ADD COLUMN wkd_hash TEXT;
+CREATE INDEX users_email ON users(email);
+CREATE INDEX users_wkd ON users(email_domain, wkd_hash);
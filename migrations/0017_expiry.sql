@@ -0,0 +1,5 @@
+-- Optional expiry for documents and shares, enforced by the sweeper.
+ALTER TABLE documents ADD COLUMN expires_at TEXT;
+ALTER TABLE document_shares ADD COLUMN expires_at TEXT;
+CREATE INDEX documents_expires_at ON documents(expires_at);
+CREATE INDEX document_shares_expires_at ON document_shares(expires_at);
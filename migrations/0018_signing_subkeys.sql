@@ -0,0 +1,10 @@
+-- Signing subkeys, so requests they sign find their account, and an optional
+-- subkey an account insists on. Accounts registered before this can only sign
+-- with their primary key.
+CREATE TABLE user_subkeys (
+    key_id TEXT PRIMARY KEY,
+    uid TEXT NOT NULL REFERENCES users(uid),
+    fingerprint TEXT NOT NULL
+);
+CREATE INDEX user_subkeys_uid ON user_subkeys(uid);
+ALTER TABLE users ADD COLUMN required_signing_subkey TEXT;
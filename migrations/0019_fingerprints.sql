@@ -0,0 +1,4 @@
+-- Full fingerprints, so a user can be named without the ambiguity of a 64-bit
+-- key id; filled in for existing rows by `fingerprint_users`.
+ALTER TABLE users ADD COLUMN fingerprint TEXT;
+CREATE UNIQUE INDEX users_fingerprint ON users(fingerprint);
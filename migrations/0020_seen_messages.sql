@@ -0,0 +1,6 @@
+-- Signed messages already acted on, for those without a nonce.
+CREATE TABLE seen_messages (
+    digest TEXT PRIMARY KEY,
+    expires_at TEXT NOT NULL
+);
+CREATE INDEX seen_messages_expires_at ON seen_messages(expires_at);
@@ -0,0 +1,3 @@
+-- Creation times. Older documents only have their last change to go by.
+ALTER TABLE documents ADD COLUMN created_at TEXT;
+UPDATE documents SET created_at = last_updated;
@@ -0,0 +1,4 @@
+-- Listings go by user. `documents.user_id` is already covered by
+-- `documents_client_ref`; the join tables are keyed by document first.
+CREATE INDEX document_owners_user_id ON document_owners(user_id);
+CREATE INDEX document_shares_user_id ON document_shares(user_id);
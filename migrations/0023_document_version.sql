@@ -0,0 +1,2 @@
+-- Bumped by each content upload, for uploads conditional on it.
+ALTER TABLE documents ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
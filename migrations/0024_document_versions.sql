@@ -0,0 +1,17 @@
+-- Every uploaded content, newest also kept in `documents.content`.
+CREATE TABLE document_versions (
+    doc_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    content_sha256 TEXT NOT NULL,
+    content_signature TEXT,
+    signer_key_id TEXT,
+    author_key_id TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (doc_id, version)
+);
+INSERT INTO document_versions (doc_id, version, content, content_sha256,
+    content_signature, author_key_id, updated_at)
+SELECT doc_id, version, content, coalesce(content_sha256, ''), content_signature,
+    coalesce(last_modified_by, user_id), coalesce(last_updated, '')
+FROM documents WHERE content IS NOT NULL;
@@ -0,0 +1,2 @@
+-- When each account was registered. Older accounts never recorded it.
+ALTER TABLE users ADD COLUMN created_at TEXT;
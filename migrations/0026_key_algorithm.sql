@@ -0,0 +1,3 @@
+-- The OpenPGP algorithm id of each primary key, so listings can report it
+-- without parsing keys; filled in by `fingerprint_users`.
+ALTER TABLE users ADD COLUMN key_algorithm INTEGER;
@@ -0,0 +1,3 @@
+-- Shares live in `document_shares`, where `migrate_legacy_shares` copies any
+-- left in the CSV column just before this runs.
+ALTER TABLE documents DROP COLUMN shared_with;
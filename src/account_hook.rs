@@ -0,0 +1,189 @@
+use pgp::types::KeyId;
+use std::{path::PathBuf, process::Command, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::key_id_to_text;
+
+/// A newly registered account, as handed to the provisioning hook.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewAccount {
+    pub key_id: KeyId,
+    pub user_id: Option<String>,
+}
+
+/// Something to run after an account is created, such as setting up quotas
+/// or syncing an external directory. Hooks run off the request path and may
+/// block.
+pub trait AccountHook: Send + Sync + 'static {
+    fn account_created(&self, account: &NewAccount) -> anyhow::Result<()>;
+}
+
+/// Runs an operator-supplied program with `MDPGP_KEY_ID` and, if the key has
+/// one, `MDPGP_USER_ID` in its environment. A non-zero exit is a failure.
+pub struct CommandHook {
+    pub program: PathBuf,
+}
+
+impl AccountHook for CommandHook {
+    fn account_created(&self, account: &NewAccount) -> anyhow::Result<()> {
+        let mut command = Command::new(&self.program);
+        command.env("MDPGP_KEY_ID", key_id_to_text(&account.key_id));
+        if let Some(user_id) = &account.user_id {
+            command.env("MDPGP_USER_ID", user_id);
+        }
+        let status = command.status()?;
+        anyhow::ensure!(status.success(), "{:?} exited with {status}", self.program);
+        Ok(())
+    }
+}
+
+/// Queues new accounts for a hook, so registration never waits on it.
+#[derive(Clone)]
+pub struct AccountHooks {
+    queue: mpsc::UnboundedSender<NewAccount>,
+}
+
+impl AccountHooks {
+    /// Starts the task that feeds queued accounts to `hook`. A failing call is
+    /// retried up to `retries` times, waiting `backoff` and then twice as long
+    /// after each further failure.
+    pub fn spawn(hook: Arc<dyn AccountHook>, retries: u32, backoff: Duration) -> Self {
+        let (queue, accounts) = mpsc::unbounded_channel();
+        tokio::spawn(run_hook(hook, accounts, retries, backoff));
+        AccountHooks { queue }
+    }
+
+    pub fn account_created(&self, account: NewAccount) {
+        if self.queue.send(account).is_err() {
+            tracing::error!("account hook task has stopped");
+        }
+    }
+}
+
+async fn run_hook(
+    hook: Arc<dyn AccountHook>,
+    mut accounts: mpsc::UnboundedReceiver<NewAccount>,
+    retries: u32,
+    backoff: Duration,
+) {
+    while let Some(account) = accounts.recv().await {
+        let key_id = key_id_to_text(&account.key_id);
+        let mut delay = backoff;
+        for attempt in 0..=retries {
+            let (hook, account) = (hook.clone(), account.clone());
+            let result = tokio::task::spawn_blocking(move || hook.account_created(&account))
+                .await
+                .unwrap_or_else(|panic| Err(panic.into()));
+            match result {
+                Ok(()) => break,
+                Err(error) if attempt < retries => {
+                    tracing::warn!(key_id, attempt, %error, "account hook failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(error) => {
+                    tracing::error!(key_id, %error, "account hook failed, giving up");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use pgp::{ser::Serialize, types::KeyDetails};
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        AppState,
+        config::Config,
+        init_db,
+        test_util::{generate_key, memory_pool, post, sign},
+    };
+
+    /// Records every call, failing the first `failures` of them.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<NewAccount>>,
+        failures: usize,
+    }
+
+    impl AccountHook for Recorder {
+        fn account_created(&self, account: &NewAccount) -> anyhow::Result<()> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(account.clone());
+            anyhow::ensure!(calls.len() > self.failures, "not yet");
+            Ok(())
+        }
+    }
+
+    /// Waits for the hook task to have made `count` calls.
+    async fn wait_for_calls(recorder: &Recorder, count: usize) -> Vec<NewAccount> {
+        for _ in 0..200 {
+            if recorder.calls.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        recorder.calls.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_hook_fires_once_per_new_account() {
+        let pool = memory_pool().await;
+        init_db(&pool).await.unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let mut state = AppState::new(pool, Config::default());
+        state.account_hooks = Some(AccountHooks::spawn(recorder.clone(), 0, Duration::ZERO));
+        let app = crate::app(state);
+
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        let create = |skey| sign(skey, &skey.signed_public_key().to_bytes().unwrap());
+        for (skey, expected) in [
+            (&alice, StatusCode::OK),
+            (&alice, StatusCode::CONFLICT),
+            (&bob, StatusCode::OK),
+        ] {
+            let (status, _) = post(&app, "/create_account", create(skey)).await;
+            assert_eq!(status, expected);
+        }
+
+        let calls = wait_for_calls(&recorder, 2).await;
+        assert_eq!(
+            calls,
+            vec![
+                NewAccount {
+                    key_id: alice.key_id(),
+                    user_id: Some("alice <alice@example.com>".to_string()),
+                },
+                NewAccount {
+                    key_id: bob.key_id(),
+                    user_id: Some("bob <bob@example.com>".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_hooks_are_retried() {
+        let recorder = Arc::new(Recorder {
+            failures: 2,
+            ..Recorder::default()
+        });
+        let hooks = AccountHooks::spawn(recorder.clone(), 5, Duration::ZERO);
+        let account = NewAccount {
+            key_id: KeyId::new([1; 8]),
+            user_id: None,
+        };
+        hooks.account_created(account.clone());
+
+        let calls = wait_for_calls(&recorder, 3).await;
+        // two failures, then one success and no more
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(calls, vec![account.clone(), account.clone(), account]);
+        assert_eq!(recorder.calls.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,293 @@
+use axum::{Json, extract::State};
+use chrono::{DateTime, SecondsFormat, Utc};
+use pgp::types::KeyId;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqliteExecutor, SqlitePool};
+
+use crate::{
+    auth::{SignedQuery, SignedRequest},
+    error::{AppError, FieldErrors, Validate},
+    field_errors, internal_error, key_id_to_text, now_timestamp,
+};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// Records a mutating action. Pass the transaction the action ran in, so the
+/// log can't disagree with the data it describes.
+pub async fn record(
+    conn: impl SqliteExecutor<'_>,
+    actor: &KeyId,
+    action: &str,
+    target: &str,
+    result: &str,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"insert into audit_log (ts, action, actor_key_id, target, result) values (?, ?, ?, ?, ?)"#,
+    )
+    .bind(now_timestamp())
+    .bind(action)
+    .bind(key_id_to_text(actor))
+    .bind(target)
+    .bind(result)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// A page request for the signer's own audit log. Entries come newest first;
+/// pass the previous page's `next_cursor` as `cursor` to continue.
+#[derive(Deserialize)]
+pub struct ListAudit {
+    action: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<u32>,
+    cursor: Option<i64>,
+}
+
+impl Validate for ListAudit {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::new();
+        if let Some(limit) = self.limit
+            && !(1..=MAX_PAGE_SIZE).contains(&limit)
+        {
+            errors.insert("limit", format!("must be between 1 and {MAX_PAGE_SIZE}"));
+        }
+        if let (Some(since), Some(until)) = (self.since, self.until)
+            && since > until
+        {
+            errors.insert("until", "must not be before since".to_string());
+        }
+        field_errors(errors)
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    id: i64,
+    ts: String,
+    action: String,
+    target: Option<String>,
+    result: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    entries: Vec<AuditEntry>,
+    /// Where the next page starts, or `None` on the last page.
+    next_cursor: Option<i64>,
+}
+
+pub async fn handle_list_audit(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ListAudit>,
+) -> Result<Json<AuditPage>, AppError> {
+    let filter = request.payload;
+    filter.validate().map_err(AppError::BadRequest)?;
+    let page = list(&pool, &request.key_id, &filter)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(page))
+}
+
+/// As `handle_list_audit`, signed in the query string.
+pub async fn handle_get_audit(
+    State(pool): State<SqlitePool>,
+    SignedQuery(request): SignedQuery<ListAudit>,
+) -> Result<Json<AuditPage>, AppError> {
+    handle_list_audit(State(pool), request).await
+}
+
+async fn list(pool: &SqlitePool, actor: &KeyId, filter: &ListAudit) -> sqlx::Result<AuditPage> {
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "select id, ts, action, target, result from audit_log where actor_key_id = ",
+    );
+    query.push_bind(key_id_to_text(actor));
+    if let Some(action) = &filter.action {
+        query.push(" and action = ").push_bind(action.clone());
+    }
+    // stored timestamps share this format, so they compare as strings
+    if let Some(since) = filter.since {
+        query.push(" and ts >= ").push_bind(timestamp(since));
+    }
+    if let Some(until) = filter.until {
+        query.push(" and ts < ").push_bind(timestamp(until));
+    }
+    if let Some(cursor) = filter.cursor {
+        query.push(" and id < ").push_bind(cursor);
+    }
+    // one extra row tells us whether there's another page
+    query
+        .push(" order by id desc limit ")
+        .push_bind(i64::from(limit) + 1);
+
+    let mut entries: Vec<AuditEntry> = query.build_query_as().fetch_all(pool).await?;
+    let next_cursor = if entries.len() > limit as usize {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(AuditPage {
+        entries,
+        next_cursor,
+    })
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use pgp::types::KeyDetails;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_util::{TestClient, generate_key, get, post, register, sign_json, test_app},
+    };
+
+    async fn record_at(pool: &SqlitePool, actor: &KeyId, action: &str, ts: &str) {
+        sqlx::query(
+            r#"insert into audit_log (ts, action, actor_key_id, target, result)
+            values (?, ?, ?, 'doc', 'ok')"#,
+        )
+        .bind(ts)
+        .bind(action)
+        .bind(key_id_to_text(actor))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_filters_and_pages() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+        for day in 1..=5 {
+            let ts = format!("2026-01-0{day}T00:00:00.000Z");
+            record_at(&pool, &alice.key_id(), "rename", &ts).await;
+            record_at(&pool, &alice.key_id(), "delete", &ts).await;
+        }
+        record_at(&pool, &bob.key_id(), "rename", "2026-01-03T00:00:00.000Z").await;
+
+        let list = |payload: Value| {
+            let body = sign_json(&alice, payload);
+            let app = app.clone();
+            async move {
+                let (status, body) = post(&app, "/audit", body).await;
+                assert_eq!(status, StatusCode::OK, "{body}");
+                serde_json::from_str::<Value>(&body).unwrap()
+            }
+        };
+        let timestamps = |page: &Value| -> Vec<String> {
+            page["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["ts"].as_str().unwrap()[..10].to_string())
+                .collect()
+        };
+
+        let renames = list(json!({
+            "action": "rename",
+            "since": "2026-01-02T00:00:00Z",
+            "until": "2026-01-05T00:00:00Z",
+        }))
+        .await;
+        assert_eq!(
+            timestamps(&renames),
+            ["2026-01-04", "2026-01-03", "2026-01-02"]
+        );
+        assert!(
+            renames["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .all(|entry| entry["action"] == "rename")
+        );
+        assert_eq!(renames["next_cursor"], Value::Null);
+
+        let mut seen = Vec::new();
+        let mut cursor = Value::Null;
+        for expected_len in [4, 4, 2] {
+            let page = list(json!({ "limit": 4, "cursor": cursor })).await;
+            assert_eq!(page["entries"].as_array().unwrap().len(), expected_len);
+            seen.extend(timestamps(&page));
+            cursor = page["next_cursor"].clone();
+        }
+        assert_eq!(cursor, Value::Null);
+        assert_eq!(seen.len(), 10);
+        assert!(seen.is_sorted_by(|a, b| a >= b));
+
+        let (status, _) = post(&app, "/audit", sign_json(&alice, json!({ "limit": 0 }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_actions_are_audited() {
+        let config = Config {
+            require_signed_reads: false,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        let bob = TestClient::new(&app, "bob <bob@example.com>");
+        alice.create_account().await;
+        bob.create_account().await;
+        let doc_id = alice.create_shared_document("notes", &[&bob]).await;
+        let (status, _) = alice
+            .post(
+                "/documents/rename",
+                json!({ "doc_id": doc_id, "name": "minutes" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let bob_id = key_id_to_text(&bob.key_id());
+        let (status, _) = alice
+            .post(
+                "/documents/unshare",
+                json!({ "doc_id": doc_id, "key_id": bob_id }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let signature = hex::encode(sign_json(&alice.key, json!({})));
+        let alice_id = key_id_to_text(&alice.key_id());
+        let uri = format!("/audit?key_id={alice_id}&signature={signature}");
+        let (status, body) = get(&app, &uri).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let page: Value = serde_json::from_str(&body).unwrap();
+        let entries: Vec<(&str, &str)> = page["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["action"].as_str().unwrap(),
+                    entry["target"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        let shared = format!("{doc_id} {bob_id}");
+        let doc_id = doc_id.to_string();
+        assert_eq!(
+            entries,
+            [
+                ("unshare_document", shared.as_str()),
+                ("rename_document", doc_id.as_str()),
+                ("share_document", shared.as_str()),
+                ("create_document", doc_id.as_str()),
+                ("create_account", alice_id.as_str()),
+            ]
+        );
+    }
+}
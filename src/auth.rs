@@ -0,0 +1,491 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, MatchedPath, Query, Request},
+    http::{StatusCode, header, request::Parts},
+};
+use pgp::{
+    composed::SignedPublicKey,
+    packet::Signature,
+    types::{Fingerprint, KeyDetails, KeyId},
+};
+use serde::{
+    Deserialize, Deserializer,
+    de::{self, DeserializeOwned, Error as _, Visitor},
+    forward_to_deserialize_any,
+};
+use sqlx::SqlitePool;
+use std::{cell::Cell, time::Duration};
+
+use crate::{
+    AppError, AppState, internal_error, key_id_from_text, key_id_to_text,
+    keys::signing_subkeys,
+    nonce, parse_stored_key, rate_limit, request_log,
+    session::Session,
+    signature::{
+        FreshMessage, MAX_CLOCK_SKEW, SignatureError, check_not_future, fresh_message,
+        message_keyid, parse_message, verify_signed_by,
+    },
+};
+
+/// A request body that is an OpenPGP signed message from a registered user.
+///
+/// The signed plaintext is a JSON object carrying a unix `timestamp` next to
+/// the endpoint's own fields. The timestamp is part of the authenticated
+/// payload, so unlike the signature creation time it can't be swapped out
+/// without re-signing, and replays of old requests are rejected as stale.
+/// A payload may also carry a `nonce` from `/nonce`, which is used up by the
+/// request so it can't be replayed at all.
+pub struct SignedRequest<T> {
+    pub key_id: KeyId,
+    pub payload: T,
+}
+
+#[derive(Deserialize)]
+struct Envelope<T> {
+    timestamp: i64,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    payload: T,
+}
+
+impl<T: DeserializeOwned + Send> FromRequest<AppState> for SignedRequest<T> {
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let (request, _) = SignedRequest::from_body(req, state, false).await?;
+        Ok(request)
+    }
+}
+
+impl<T: DeserializeOwned + Send> SignedRequest<T> {
+    async fn from_body(
+        req: Request,
+        state: &AppState,
+        single_use: bool,
+    ) -> Result<(Self, Option<FreshMessage>), AppError> {
+        let route = req.extensions().get::<MatchedPath>().cloned();
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|error| (error.status(), error.body_text()))?;
+        let (request, message) = SignedRequest::check(&body, state, single_use).await?;
+        if let Some(route) = route {
+            rate_limit::check_key(state, route.as_str(), &request.key_id)?;
+        }
+        Ok((request, message))
+    }
+
+    /// Checks a signed envelope that arrived somewhere other than the body.
+    pub async fn verify(message: &[u8], state: &AppState) -> Result<Self, AppError> {
+        let (request, _) = SignedRequest::check(message, state, false).await?;
+        Ok(request)
+    }
+
+    /// Checks a signed envelope, and for a `single_use` one without a nonce
+    /// names the message, for the caller to record.
+    async fn check(
+        message: &[u8],
+        state: &AppState,
+        single_use: bool,
+    ) -> Result<(Self, Option<FreshMessage>), AppError> {
+        let (signature, plaintext) = parse_message(message).map_err(rejection)?;
+        let (key, fingerprint) = authenticate_signer(&state.pool, &signature, &plaintext).await?;
+        check_not_future(&signature, chrono::Utc::now(), MAX_CLOCK_SKEW).map_err(rejection)?;
+        let key_id = key.key_id();
+        request_log::record_caller(&key_id);
+
+        if state.config.strict_payloads
+            && let Some(field) = unknown_field::<T>(&plaintext)
+        {
+            return Err(AppError::Status(
+                StatusCode::BAD_REQUEST,
+                format!("Bad signed request:\nunknown field {field:?}"),
+            ));
+        }
+        let envelope: Envelope<T> = serde_json::from_slice(&plaintext).map_err(|error| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Bad signed request:\n{error}"),
+            )
+        })?;
+        if !is_fresh(
+            envelope.timestamp,
+            chrono::Utc::now().timestamp(),
+            state.config.freshness_window,
+        ) {
+            return Err(AppError::Status(
+                StatusCode::UNAUTHORIZED,
+                "stale request".to_string(),
+            ));
+        }
+        let mut fresh = None;
+        if let Some(nonce) = &envelope.nonce {
+            nonce::consume(&state.pool, nonce, chrono::Utc::now()).await?;
+        } else if single_use {
+            let window = state.config.freshness_window;
+            let mut message =
+                fresh_message(&signature, &fingerprint, &plaintext, window).map_err(rejection)?;
+            // the envelope may be dated ahead of the signature, and passes as
+            // fresh until its own window is up
+            if let Some(dated) = chrono::DateTime::from_timestamp(envelope.timestamp, 0) {
+                message.fresh_until = message.fresh_until.max(dated + window);
+            }
+            fresh = Some(message);
+        }
+
+        let request = SignedRequest {
+            key_id,
+            payload: envelope.payload,
+        };
+        Ok((request, fresh))
+    }
+}
+
+/// A `SignedRequest` that may only be served once, for endpoints where a
+/// replay would do harm even inside the freshness window. A `nonce` in the
+/// payload is used up as usual; without one `message` names the request, and
+/// the handler records it with `nonce::record_message` in the transaction
+/// that acts on it, so a request that fails or is turned away isn't used up.
+pub struct SingleUse<T> {
+    pub request: SignedRequest<T>,
+    pub message: Option<FreshMessage>,
+}
+
+impl<T: DeserializeOwned + Send> FromRequest<AppState> for SingleUse<T> {
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let (request, message) = SignedRequest::from_body(req, state, true).await?;
+        Ok(SingleUse { request, message })
+    }
+}
+
+/// The account a request signature speaks for.
+struct Signer {
+    key: SignedPublicKey,
+    /// Fingerprint of the only subkey the account accepts requests from.
+    required_subkey: Option<String>,
+}
+
+/// Finds the account that made `signature` over `data` and checks it against
+/// the account's key: its primary key or any registered signing subkey, or
+/// only its designated subkey if it requires one. Returns the account's key
+/// and the fingerprint of the part that signed.
+pub async fn authenticate_signer(
+    pool: &SqlitePool,
+    signature: &Signature,
+    data: &[u8],
+) -> Result<(SignedPublicKey, Fingerprint), AppError> {
+    let issuer = message_keyid(signature).map_err(rejection)?;
+    let signer = match find_signer(pool, &issuer).await {
+        Ok(Some(signer)) => signer,
+        Ok(None) => {
+            return Err(AppError::Status(
+                StatusCode::UNAUTHORIZED,
+                "unknown signer".to_string(),
+            ));
+        }
+        Err(error) => return Err(internal_error(error).into()),
+    };
+    let key = signer.key;
+    if key.key_id() != issuer && !signing_subkeys(&key).any(|subkey| subkey.key.key_id() == issuer)
+    {
+        return Err(AppError::Status(
+            StatusCode::UNAUTHORIZED,
+            "unknown signer".to_string(),
+        ));
+    }
+    let fingerprint = verify_signed_by(signature, &key, data).map_err(rejection)?;
+    if let Some(required) = &signer.required_subkey
+        && !fingerprint.to_string().eq_ignore_ascii_case(required)
+    {
+        return Err(AppError::Status(
+            StatusCode::UNAUTHORIZED,
+            "account requires its designated signing subkey".to_string(),
+        ));
+    }
+    Ok((key, fingerprint))
+}
+
+/// Finds the account whose primary key or registered signing subkey is
+/// `issuer`.
+async fn find_signer(pool: &SqlitePool, issuer: &KeyId) -> anyhow::Result<Option<Signer>> {
+    let row: Option<(String, Option<String>)> = sqlx::query_as(
+        r#"select public_key, required_signing_subkey from users
+        where uid = ?1 or uid = (select uid from user_subkeys where key_id = ?1)"#,
+    )
+    .bind(key_id_to_text(issuer))
+    .fetch_optional(pool)
+    .await?;
+    let Some((public_key, required_subkey)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(Signer {
+        key: parse_stored_key(&public_key)?,
+        required_subkey,
+    }))
+}
+
+/// The first field of a JSON object payload that `T` doesn't declare. Only
+/// plain structs are checked; `deny_unknown_fields` can't be used on them
+/// because they're flattened into `Envelope`.
+fn unknown_field<T: DeserializeOwned>(plaintext: &[u8]) -> Option<String> {
+    let fields = Cell::new(None);
+    let _ = T::deserialize(FieldNames(&fields));
+    let fields = fields.get()?;
+    let serde_json::Value::Object(payload) = serde_json::from_slice(plaintext).ok()? else {
+        return None;
+    };
+    payload
+        .keys()
+        .find(|key| {
+            !["timestamp", "nonce"].contains(&key.as_str()) && !fields.contains(&key.as_str())
+        })
+        .cloned()
+}
+
+/// A deserializer that only records the field names a struct asks for.
+struct FieldNames<'a>(&'a Cell<Option<&'static [&'static str]>>);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(Self::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.set(Some(fields));
+        Err(Self::Error::custom("fields recorded"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// A `SignedRequest` carried in the query string of a `GET`, as
+/// `?key_id=...&signature=<hex>`, where the signature must come from
+/// `key_id`. Only accepted when `require_signed_reads` is off: signatures in
+/// URLs end up in logs and browser history, where they can be replayed until
+/// they go stale.
+///
+/// A `GET` with `Authorization: Bearer <token>` for a live session is taken
+/// as from the session's key instead, its fields read from the query string
+/// unsigned. Tokens travel in a header, not the URL, so they're accepted
+/// whatever `require_signed_reads` says.
+pub struct SignedQuery<T>(pub SignedRequest<T>);
+
+#[derive(Deserialize)]
+struct QueryEnvelope {
+    key_id: String,
+    signature: String,
+}
+
+impl<T: DeserializeOwned + Send> FromRequestParts<AppState> for SignedQuery<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(header::AUTHORIZATION) {
+            let session = Session::from_request_parts(parts, state).await?;
+            let Query(payload) = Query::<T>::from_request_parts(parts, state)
+                .await
+                .map_err(|error| (error.status(), error.body_text()))?;
+            return Ok(SignedQuery(SignedRequest {
+                key_id: session.key_id,
+                payload,
+            }));
+        }
+        if state.config.require_signed_reads {
+            return Err(AppError::Status(
+                StatusCode::UNAUTHORIZED,
+                "signed request body required".to_string(),
+            ));
+        }
+        let Query(query) = Query::<QueryEnvelope>::from_request_parts(parts, state)
+            .await
+            .map_err(|error| (error.status(), error.body_text()))?;
+        let key_id = key_id_from_text(&query.key_id)
+            .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+        let message = hex::decode(query.signature.trim())
+            .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+
+        let request = SignedRequest::verify(&message, state).await?;
+        if request.key_id != key_id {
+            return Err(AppError::Status(
+                StatusCode::UNAUTHORIZED,
+                "signature does not match key_id".to_string(),
+            ));
+        }
+        Ok(SignedQuery(request))
+    }
+}
+
+/// A `SignedRequest` from one of the configured admin keys.
+pub struct AdminRequest<T>(pub SignedRequest<T>);
+
+impl<T: DeserializeOwned + Send> FromRequest<AppState> for AdminRequest<T> {
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let request = SignedRequest::from_request(req, state).await?;
+        if !state.config.admin_key_ids.contains(&request.key_id) {
+            return Err(AppError::Status(
+                StatusCode::FORBIDDEN,
+                "admin only".to_string(),
+            ));
+        }
+        Ok(AdminRequest(request))
+    }
+}
+
+/// Malformed messages are the client's mistake; signatures that don't hold up
+/// mean the caller isn't authenticated.
+pub fn rejection(error: SignatureError) -> AppError {
+    match error {
+        SignatureError::NotSigned | SignatureError::BadIssuers(_) | SignatureError::Parse(_) => {
+            AppError::Status(
+                StatusCode::BAD_REQUEST,
+                format!("Bad signed request:\n{error}"),
+            )
+        }
+        SignatureError::Verify(_) => {
+            AppError::Status(StatusCode::UNAUTHORIZED, "invalid signature".to_string())
+        }
+        SignatureError::WeakHash(_)
+        | SignatureError::Expired
+        | SignatureError::Stale
+        | SignatureError::FutureDated(_) => {
+            AppError::Status(StatusCode::UNAUTHORIZED, error.to_string())
+        }
+    }
+}
+
+fn is_fresh(timestamp: i64, now: i64, window: Duration) -> bool {
+    timestamp.abs_diff(now) <= window.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_util::{
+        error_message, generate_key, post, register, sign, sign_json, test_app,
+    };
+
+    #[test]
+    fn test_is_fresh() {
+        let window = Duration::from_secs(300);
+        assert!(is_fresh(1_000, 1_000, window));
+        assert!(is_fresh(700, 1_000, window));
+        assert!(is_fresh(1_300, 1_000, window));
+        assert!(!is_fresh(699, 1_000, window));
+        assert!(!is_fresh(1_301, 1_000, window));
+    }
+
+    #[tokio::test]
+    async fn test_backdated_timestamp_rejected() {
+        let (app, pool) = test_app(Config::default()).await;
+        let skey = generate_key("alice <alice@example.com>");
+        register(&pool, &skey).await;
+
+        let backdated = chrono::Utc::now().timestamp() - 60 * 60;
+        let body = sign(
+            &skey,
+            json!({ "name": "notes", "timestamp": backdated })
+                .to_string()
+                .as_bytes(),
+        );
+        let (status, message) = post(&app, "/create_document", body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error_message(&message), "stale request");
+
+        let body = sign_json(&skey, json!({ "name": "notes" }));
+        let (status, _) = post(&app, "/create_document", body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_single_use_requests_cant_be_replayed() {
+        let (app, pool) = test_app(Config::default()).await;
+        let skey = generate_key("alice <alice@example.com>");
+        register(&pool, &skey).await;
+
+        let body = sign_json(&skey, json!({}));
+        let (status, _) = post(&app, "/sessions/new", body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, message) = post(&app, "/sessions/new", body).await;
+        assert_eq!(
+            (status, error_message(&message).as_str()),
+            (StatusCode::UNAUTHORIZED, "replayed message")
+        );
+
+        // a body dated ahead of its signature is remembered until the envelope
+        // goes stale, not just the signature
+        let window = Config::default().freshness_window;
+        let now = chrono::Utc::now();
+        let ahead = now.timestamp() + 200;
+        let body = sign(&skey, json!({ "timestamp": ahead }).to_string().as_bytes());
+        let (status, _) = post(&app, "/sessions/new", body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        nonce::purge_expired(&pool, now + window + Duration::from_secs(1), 100)
+            .await
+            .unwrap();
+        let (status, message) = post(&app, "/sessions/new", body).await;
+        assert_eq!(
+            (status, error_message(&message).as_str()),
+            (StatusCode::UNAUTHORIZED, "replayed message")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strict_payloads_reject_unknown_fields() {
+        for strict_payloads in [false, true] {
+            let config = Config {
+                strict_payloads,
+                ..Config::default()
+            };
+            let (app, pool) = test_app(config).await;
+            let skey = generate_key("alice <alice@example.com>");
+            register(&pool, &skey).await;
+
+            let body = sign_json(&skey, json!({ "name": "notes", "nme": "typo" }));
+            let (status, message) = post(&app, "/create_document", body).await;
+            if strict_payloads {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(
+                    error_message(&message).contains("unknown field \"nme\""),
+                    "{message}"
+                );
+            } else {
+                assert_eq!(status, StatusCode::OK);
+            }
+
+            // declared but omitted fields are fine either way
+            let body = sign_json(&skey, json!({ "name": "notes", "client_ref": "a" }));
+            let (status, _) = post(&app, "/create_document", body).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_signer_rejected() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let skey = generate_key("mallory <mallory@example.com>");
+
+        let body = sign_json(&skey, json!({ "name": "notes" }));
+        let (status, _) = post(&app, "/create_document", body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,118 @@
+use axum::{
+    body::{self, Body},
+    extract::{MatchedPath, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{AppError, AppState};
+
+/// Caps request bodies per route, so a route that expects a few bytes can't
+/// be made to buffer megabytes. Replaces axum's single global limit.
+pub async fn body_limit(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit = state
+        .config
+        .body_limits
+        .get(matched_path.as_str())
+        .copied()
+        .unwrap_or(state.config.default_body_limit);
+
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return too_large();
+    }
+
+    let (parts, body) = request.into_parts();
+    match body::to_bytes(body, limit).await {
+        Ok(bytes) => {
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        Err(_) => too_large(),
+    }
+}
+
+fn too_large() -> Response {
+    AppError::Status(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "request body too large".to_string(),
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use pgp::{
+        composed::DetachedSignature,
+        crypto::hash::HashAlgorithm,
+        types::{KeyDetails, Password},
+    };
+    use rand::thread_rng;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        create_document, key_id_to_text,
+        test_util::{generate_key, post, register, send, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_body_limits_are_per_route() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        register(&pool, &owner).await;
+        let doc_id = create_document(&pool, &owner.key_id(), "big", None).await;
+
+        let padding = "x".repeat(256 * 1024);
+        let policy = Request::get("/policy")
+            .body(Body::from(padding.clone()))
+            .unwrap();
+        let (status, _) = send(&app, policy).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let sync = sign_json(&owner, json!({ "padding": padding }));
+        let (status, _) = post(&app, "/sync", sync).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let account = Request::post("/create_account")
+            .body(Body::from(padding.clone()))
+            .unwrap();
+        let (status, _) = send(&app, account).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // well past axum's own 2 MB default
+        let content = "x".repeat(3 * 1024 * 1024);
+        let upload = sign_json(&owner, json!({ "doc_id": doc_id, "content": content }));
+        let (status, _) = post(&app, "/documents/content/upload", upload).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let signature = DetachedSignature::sign_binary_data(
+            thread_rng(),
+            &owner.primary_key,
+            &Password::empty(),
+            HashAlgorithm::Sha256,
+            content.as_bytes(),
+        )
+        .unwrap();
+        let upload = sign_json(
+            &owner,
+            json!({
+                "doc_id": doc_id,
+                "content": content,
+                "signature": signature.to_armored_string(Default::default()).unwrap(),
+                "signer_key_id": key_id_to_text(&owner.key_id()),
+            }),
+        );
+        let (status, body) = post(&app, "/documents/content/upload_signed", upload).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
@@ -0,0 +1,99 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use crate::AppState;
+
+/// The address of the client that sent a request. Behind one of the configured
+/// trusted proxies this comes from `X-Forwarded-For`; otherwise it's the peer.
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        Ok(ClientIp(client_ip(parts, &state.config.trusted_proxies)))
+    }
+}
+
+pub fn client_ip(parts: &Parts, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let forwarded_for = parts
+        .headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    resolve(peer, forwarded_for, trusted_proxies)
+}
+
+fn resolve(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> IpAddr {
+    // only requests made through tests lack a peer address
+    let Some(mut ip) = peer else {
+        return IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    };
+    // each proxy appends the address it saw, so walk back from the right
+    // until reaching one that isn't ours
+    let hops = forwarded_for.into_iter().flat_map(|list| list.rsplit(','));
+    for hop in hops {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(addr) => ip = addr,
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        // untrusted peers can't spoof their address
+        assert_eq!(
+            resolve(Some(ip("1.2.3.4")), Some("5.6.7.8"), &proxies),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            resolve(Some(ip("10.0.0.1")), Some("5.6.7.8"), &proxies),
+            ip("5.6.7.8")
+        );
+        // a client-supplied entry left of a real hop is ignored
+        assert_eq!(
+            resolve(
+                Some(ip("10.0.0.1")),
+                Some("9.9.9.9, 5.6.7.8, 10.0.0.2"),
+                &proxies
+            ),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            resolve(Some(ip("10.0.0.1")), Some("garbage"), &proxies),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            resolve(Some(ip("10.0.0.1")), None, &proxies),
+            ip("10.0.0.1")
+        );
+    }
+}
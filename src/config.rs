@@ -0,0 +1,341 @@
+use anyhow::Context;
+use axum::http::HeaderValue;
+use pgp::types::KeyId;
+use std::{
+    collections::HashMap, env, fmt::Display, net::IpAddr, path::PathBuf, str::FromStr,
+    time::Duration,
+};
+
+use crate::{SharePermission, key_id_from_text};
+
+/// Server settings, read once at startup from `MDPGP_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Address the server listens on, as `host:port`.
+    pub bind_addr: String,
+    /// The SQLite database file, created if missing.
+    pub db_path: PathBuf,
+    /// Most database connections held open at once.
+    pub max_connections: u32,
+    /// How far the `timestamp` inside a signed payload may be from the server
+    /// clock, in either direction, before the request is rejected as stale.
+    pub freshness_window: Duration,
+    /// How many users a single document may be shared with.
+    pub max_shares_per_document: u32,
+    /// How long deletions are remembered for `/sync`. Clients that last synced
+    /// longer ago than this have to fetch everything again.
+    pub sync_retention: Duration,
+    /// Keys allowed to call `/admin/*` endpoints.
+    pub admin_key_ids: Vec<KeyId>,
+    /// How long a rotated-out server key is still published at `/server-key`.
+    pub server_key_grace: Duration,
+    /// Requests per minute allowed per client address, and again per signing
+    /// key, on routes without their own limit.
+    pub default_rate_limit: u32,
+    /// Per-route requests per minute, keyed by route path.
+    pub rate_limits: HashMap<String, u32>,
+    /// Reverse proxies whose `X-Forwarded-For` header is believed.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// How many accounts one client address may create per `account_creation_window`.
+    pub max_accounts_per_ip: u32,
+    pub account_creation_window: Duration,
+    /// Whether document listings must carry their signature in the request
+    /// body. Turning this off also allows `GET /documents` with the signed
+    /// envelope in the query string, which is for local development only.
+    pub require_signed_reads: bool,
+    /// Largest request body accepted on routes without their own limit, in bytes.
+    pub default_body_limit: usize,
+    /// Per-route body limits in bytes, keyed by route path.
+    pub body_limits: HashMap<String, usize>,
+    /// Requests taking longer than this are logged as warnings.
+    pub slow_request_threshold: Duration,
+    /// Whether to round-trip a signature through the crypto code at startup.
+    /// On by default in debug builds.
+    pub crypto_self_test: bool,
+    /// What a share grants when the request doesn't say.
+    pub default_share_permission: SharePermission,
+    /// Program run after each new account, with `MDPGP_KEY_ID` and
+    /// `MDPGP_USER_ID` set. Registration doesn't wait for it.
+    pub account_hook_command: Option<PathBuf>,
+    /// How many times a failed account hook is retried before giving up.
+    pub account_hook_retries: u32,
+    /// Whether signed payloads carrying fields the endpoint doesn't know are
+    /// rejected, rather than the fields being ignored.
+    pub strict_payloads: bool,
+    /// Most rows a listing returns in one response. Longer listings are
+    /// truncated with a cursor to continue from.
+    pub max_listing_rows: u32,
+    /// Whether downloads re-hash content and refuse to serve it if it no
+    /// longer matches the hash stored at upload.
+    pub verify_content_hashes: bool,
+    /// How long shutdown waits for requests in flight before cutting them off.
+    pub shutdown_drain_timeout: Duration,
+    /// How long a session token from `/sessions/new` stays valid.
+    pub session_lifetime: Duration,
+    /// How long a nonce from `/nonce` can be used for.
+    pub nonce_ttl: Duration,
+    /// How often expired documents, shares, tombstones and nonces are
+    /// deleted.
+    pub sweep_interval: Duration,
+    /// Most rows of one kind deleted per transaction while sweeping.
+    pub sweep_batch_size: u32,
+    /// Whether email lookups ignore case in the local part as well as the
+    /// domain. Most mail servers do, but the standard doesn't promise it.
+    pub lowercase_email_local_part: bool,
+    /// How long `/health` waits on the database before calling it down.
+    pub health_check_timeout: Duration,
+    /// Origins of browser clients allowed to call the API, like
+    /// `https://notes.example.com`. Empty allows none.
+    pub cors_allowed_origins: Vec<String>,
+    /// Where `/metrics` is served instead of on `bind_addr`, so it can be
+    /// kept off the public interface.
+    pub metrics_addr: Option<String>,
+    /// Most content versions kept per document for `/documents/history`.
+    /// Older ones are dropped as new ones are uploaded.
+    pub max_document_versions: u32,
+    /// How many documents an account may own before it can't create more.
+    /// Co-owned ones count.
+    pub max_documents_per_user: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: "localhost:8000".to_string(),
+            db_path: PathBuf::from("data.db"),
+            max_connections: 5,
+            freshness_window: Duration::from_secs(5 * 60),
+            max_shares_per_document: 100,
+            sync_retention: Duration::from_secs(30 * 24 * 60 * 60),
+            admin_key_ids: Vec::new(),
+            server_key_grace: Duration::from_secs(7 * 24 * 60 * 60),
+            default_rate_limit: 120,
+            rate_limits: HashMap::from([
+                ("/create_account".to_string(), 10),
+                ("/create_document".to_string(), 30),
+                ("/documents/create".to_string(), 30),
+            ]),
+            trusted_proxies: Vec::new(),
+            max_accounts_per_ip: 5,
+            account_creation_window: Duration::from_secs(24 * 60 * 60),
+            require_signed_reads: true,
+            default_body_limit: 64 * 1024,
+            body_limits: HashMap::from([
+                ("/documents/content/upload".to_string(), 16 * 1024 * 1024),
+                (
+                    "/documents/content/upload_signed".to_string(),
+                    16 * 1024 * 1024,
+                ),
+            ]),
+            slow_request_threshold: Duration::from_secs(1),
+            crypto_self_test: cfg!(debug_assertions),
+            default_share_permission: SharePermission::Read,
+            account_hook_command: None,
+            account_hook_retries: 5,
+            strict_payloads: false,
+            max_listing_rows: 1000,
+            verify_content_hashes: false,
+            shutdown_drain_timeout: Duration::from_secs(30),
+            session_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            nonce_ttl: Duration::from_secs(5 * 60),
+            sweep_interval: Duration::from_secs(60),
+            sweep_batch_size: 500,
+            lowercase_email_local_part: false,
+            health_check_timeout: Duration::from_secs(2),
+            cors_allowed_origins: Vec::new(),
+            metrics_addr: None,
+            max_document_versions: 50,
+            max_documents_per_user: 10_000,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        if let Some(addr) = env_var("MDPGP_BIND_ADDR")? {
+            config.bind_addr = addr;
+        }
+        if let Some(path) = env_var("MDPGP_DB_PATH")? {
+            config.db_path = path;
+        }
+        if let Some(max) = env_var("MDPGP_MAX_CONNECTIONS")? {
+            anyhow::ensure!(
+                max > 0,
+                "Invalid value for MDPGP_MAX_CONNECTIONS: must be at least 1"
+            );
+            config.max_connections = max;
+        }
+        if let Some(secs) = env_var("MDPGP_FRESHNESS_WINDOW_SECS")? {
+            config.freshness_window = Duration::from_secs(secs);
+        }
+        if let Some(max) = env_var("MDPGP_MAX_SHARES_PER_DOCUMENT")? {
+            config.max_shares_per_document = max;
+        }
+        if let Some(secs) = env_var("MDPGP_SYNC_RETENTION_SECS")? {
+            config.sync_retention = Duration::from_secs(secs);
+        }
+        if let Some(ids) = env_var::<String>("MDPGP_ADMIN_KEY_IDS")? {
+            config.admin_key_ids = ids
+                .split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(key_id_from_text)
+                .collect::<anyhow::Result<_>>()
+                .context("Invalid value for MDPGP_ADMIN_KEY_IDS")?;
+        }
+        if let Some(secs) = env_var("MDPGP_SERVER_KEY_GRACE_SECS")? {
+            config.server_key_grace = Duration::from_secs(secs);
+        }
+        if let Some(limit) = env_var("MDPGP_DEFAULT_RATE_LIMIT")? {
+            config.default_rate_limit = limit;
+        }
+        if let Some(limits) = env_var::<String>("MDPGP_RATE_LIMITS")? {
+            config.rate_limits.extend(
+                parse_route_limits(&limits).context("Invalid value for MDPGP_RATE_LIMITS")?,
+            );
+        }
+        if let Some(proxies) = env_var::<String>("MDPGP_TRUSTED_PROXIES")? {
+            config.trusted_proxies = proxies
+                .split(',')
+                .filter(|ip| !ip.trim().is_empty())
+                .map(|ip| ip.trim().parse())
+                .collect::<Result<_, _>>()
+                .context("Invalid value for MDPGP_TRUSTED_PROXIES")?;
+        }
+        if let Some(max) = env_var("MDPGP_MAX_ACCOUNTS_PER_IP")? {
+            config.max_accounts_per_ip = max;
+        }
+        if let Some(secs) = env_var("MDPGP_ACCOUNT_CREATION_WINDOW_SECS")? {
+            config.account_creation_window = Duration::from_secs(secs);
+        }
+        if let Some(required) = env_var("MDPGP_REQUIRE_SIGNED_READS")? {
+            config.require_signed_reads = required;
+        }
+        if let Some(limit) = env_var("MDPGP_DEFAULT_BODY_LIMIT")? {
+            config.default_body_limit = limit;
+        }
+        if let Some(limits) = env_var::<String>("MDPGP_BODY_LIMITS")? {
+            config.body_limits.extend(
+                parse_route_limits(&limits).context("Invalid value for MDPGP_BODY_LIMITS")?,
+            );
+        }
+        if let Some(millis) = env_var("MDPGP_SLOW_REQUEST_THRESHOLD_MS")? {
+            config.slow_request_threshold = Duration::from_millis(millis);
+        }
+        if let Some(enabled) = env_var("MDPGP_CRYPTO_SELF_TEST")? {
+            config.crypto_self_test = enabled;
+        }
+        if let Some(permission) = env_var("MDPGP_DEFAULT_SHARE_PERMISSION")? {
+            config.default_share_permission = permission;
+        }
+        if let Some(program) = env_var("MDPGP_ACCOUNT_HOOK_COMMAND")? {
+            config.account_hook_command = Some(program);
+        }
+        if let Some(retries) = env_var("MDPGP_ACCOUNT_HOOK_RETRIES")? {
+            config.account_hook_retries = retries;
+        }
+        if let Some(strict) = env_var("MDPGP_STRICT_PAYLOADS")? {
+            config.strict_payloads = strict;
+        }
+        if let Some(max) = env_var("MDPGP_MAX_LISTING_ROWS")? {
+            config.max_listing_rows = max;
+        }
+        if let Some(verify) = env_var("MDPGP_VERIFY_CONTENT_HASHES")? {
+            config.verify_content_hashes = verify;
+        }
+        if let Some(secs) = env_var("MDPGP_SHUTDOWN_DRAIN_TIMEOUT_SECS")? {
+            config.shutdown_drain_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("MDPGP_SESSION_LIFETIME_SECS")? {
+            config.session_lifetime = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("MDPGP_NONCE_TTL_SECS")? {
+            config.nonce_ttl = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("MDPGP_SWEEP_INTERVAL_SECS")? {
+            config.sweep_interval = Duration::from_secs(secs);
+        }
+        if let Some(size) = env_var("MDPGP_SWEEP_BATCH_SIZE")? {
+            config.sweep_batch_size = size;
+        }
+        if let Some(lowercase) = env_var("MDPGP_LOWERCASE_EMAIL_LOCAL_PART")? {
+            config.lowercase_email_local_part = lowercase;
+        }
+        if let Some(millis) = env_var("MDPGP_HEALTH_CHECK_TIMEOUT_MILLIS")? {
+            config.health_check_timeout = Duration::from_millis(millis);
+        }
+        if let Some(origins) = env_var::<String>("MDPGP_CORS_ALLOWED_ORIGINS")? {
+            config.cors_allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(|origin| {
+                    HeaderValue::from_str(origin)?;
+                    Ok(origin.to_string())
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("Invalid value for MDPGP_CORS_ALLOWED_ORIGINS")?;
+        }
+        if let Some(addr) = env_var("MDPGP_METRICS_ADDR")? {
+            config.metrics_addr = Some(addr);
+        }
+        if let Some(max) = env_var("MDPGP_MAX_DOCUMENT_VERSIONS")? {
+            anyhow::ensure!(
+                max > 0,
+                "Invalid value for MDPGP_MAX_DOCUMENT_VERSIONS: must be at least 1"
+            );
+            config.max_document_versions = max;
+        }
+        if let Some(max) = env_var("MDPGP_MAX_DOCS_PER_USER")? {
+            config.max_documents_per_user = max;
+        }
+        Ok(config)
+    }
+}
+
+/// Parses `path=limit` pairs separated by commas.
+fn parse_route_limits<T>(text: &str) -> anyhow::Result<HashMap<String, T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    text.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (path, limit) = entry
+                .split_once('=')
+                .with_context(|| format!("Expected path=limit, got {entry:?}"))?;
+            Ok((path.trim().to_string(), limit.trim().parse()?))
+        })
+        .collect()
+}
+
+fn env_var<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|error| anyhow::anyhow!("{error}"))
+            .with_context(|| format!("Invalid value for {name}: {value:?}")),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(error) => Err(error).with_context(|| format!("Invalid value for {name}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let limits = parse_route_limits::<u32>("/create_account=5, /policy=100,").unwrap();
+        assert_eq!(limits["/create_account"], 5);
+        assert_eq!(limits["/policy"], 100);
+        assert!(parse_route_limits::<u32>("/create_account").is_err());
+        assert!(parse_route_limits::<u32>("/create_account=lots").is_err());
+    }
+}
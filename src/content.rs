@@ -0,0 +1,1068 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use pgp::{
+    composed::{Deserializable, DetachedSignature},
+    ser::Serialize as _,
+    types::{KeyDetails, KeyId},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::ops::Range;
+use uuid::Uuid;
+
+use crate::{
+    AppError, AppState, audit,
+    auth::{SignedQuery, SignedRequest},
+    begin_write,
+    events::EventKind,
+    get_signer_key, internal_error, key_id_from_text, key_id_to_text,
+    keys::signing_subkeys,
+    now_timestamp, owner_status, require_reader, require_writer,
+    signature::{message_keyid, parse_detached, parse_message, verify_signed_by},
+    storage::Storage,
+    touch_document,
+};
+
+const CONTENT_TYPE: &str = "text/markdown; charset=utf-8";
+const CONTENT_SIGNATURE: &str = "x-content-signature";
+const CONTENT_SHA256: &str = "x-content-sha256";
+const CONTENT_VERSION: &str = "x-content-version";
+
+#[derive(Deserialize)]
+pub struct UploadContent {
+    doc_id: Uuid,
+    content: String,
+    /// Only upload if the document is still at this version, as read from
+    /// `X-Content-Version` or the listing. Otherwise `409`.
+    expected_version: Option<i64>,
+}
+
+pub async fn handle_upload_content(
+    State(state): State<AppState>,
+    request: SignedRequest<UploadContent>,
+) -> Result<Response, AppError> {
+    let payload = request.payload;
+    let version = upload_content(
+        &state,
+        &payload.doc_id,
+        &request.key_id,
+        payload.content.as_bytes(),
+        None,
+        payload.expected_version,
+    )
+    .await?;
+    Ok(([(CONTENT_VERSION, version.to_string())], "ok").into_response())
+}
+
+/// Content with an armored detached signature from a registered key, for
+/// clients that don't want to re-sign the whole document inline.
+#[derive(Deserialize)]
+pub struct UploadSignedContent {
+    doc_id: Uuid,
+    content: String,
+    signature: String,
+    signer_key_id: String,
+    /// As `UploadContent::expected_version`.
+    expected_version: Option<i64>,
+}
+
+pub async fn handle_upload_signed_content(
+    State(state): State<AppState>,
+    request: SignedRequest<UploadSignedContent>,
+) -> Result<Response, AppError> {
+    let payload = request.payload;
+    let bad_signature = |error: String| AppError::Status(StatusCode::BAD_REQUEST, error);
+    let signer = key_id_from_text(&payload.signer_key_id)
+        .map_err(|error| bad_signature(error.to_string()))?;
+    let (signature, content) =
+        parse_detached(payload.signature.as_bytes(), payload.content.as_bytes())
+            .map_err(|error| bad_signature(format!("Bad detached signature:\n{error}")))?;
+    let key = state
+        .storage
+        .get_user_key(&signer)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| bad_signature("signer is not registered".to_string()))?;
+    let issuer = message_keyid(&signature).ok();
+    if issuer != Some(signer)
+        && !signing_subkeys(&key).any(|subkey| Some(subkey.key.key_id()) == issuer)
+    {
+        return Err(bad_signature(
+            "signature was not made by signer_key_id".to_string(),
+        ));
+    }
+    verify_signed_by(&signature, &key, &content)
+        .map_err(|error| bad_signature(error.to_string()))?;
+
+    let version = upload_content(
+        &state,
+        &payload.doc_id,
+        &request.key_id,
+        payload.content.as_bytes(),
+        Some((&payload.signature, &signer)),
+        payload.expected_version,
+    )
+    .await?;
+    Ok(([(CONTENT_VERSION, version.to_string())], "ok").into_response())
+}
+
+/// Replaces the document's content, keeping it as a new version, and tells
+/// subscribers. Returns the new version.
+async fn upload_content(
+    state: &AppState,
+    doc_id: &Uuid,
+    caller: &KeyId,
+    content: &[u8],
+    signature: Option<(&str, &KeyId)>,
+    expected_version: Option<i64>,
+) -> Result<i64, AppError> {
+    let mut tx = begin_write(&state.pool).await.map_err(internal_error)?;
+    require_writer(&mut *tx, doc_id, caller).await?;
+
+    let require_signed: bool =
+        sqlx::query_scalar(r#"select require_signed_content from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    if require_signed {
+        let Some((_, signer)) = signature else {
+            return Err(AppError::Status(
+                StatusCode::BAD_REQUEST,
+                "document requires signed content".to_string(),
+            ));
+        };
+        if owner_status(&mut *tx, doc_id, signer)
+            .await
+            .map_err(internal_error)?
+            != Some(true)
+        {
+            return Err(AppError::Status(
+                StatusCode::BAD_REQUEST,
+                "content must be signed by an owner of the document".to_string(),
+            ));
+        }
+    }
+
+    // a plain upload drops any signature over the old content
+    let version: Option<i64> = sqlx::query_scalar(
+        r#"update documents
+        set content = ?, content_sha256 = ?, content_signature = ?, version = version + 1
+        where doc_id = ? and (?5 is null or version = ?5)
+        returning version"#,
+    )
+    .bind(content)
+    .bind(sha256_hex(content))
+    .bind(signature.map(|(armored, _)| armored))
+    .bind(doc_id.to_string())
+    .bind(expected_version)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    let Some(version) = version else {
+        let current = sqlx::query_scalar(r#"select version from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+        return Err(AppError::VersionConflict(current));
+    };
+    sqlx::query(
+        r#"insert into document_versions (doc_id, version, content, content_sha256,
+            content_signature, signer_key_id, author_key_id, updated_at)
+        values (?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(version)
+    .bind(content)
+    .bind(sha256_hex(content))
+    .bind(signature.map(|(armored, _)| armored))
+    .bind(signature.map(|(_, signer)| key_id_to_text(signer)))
+    .bind(key_id_to_text(caller))
+    .bind(now_timestamp())
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    sqlx::query(r#"delete from document_versions where doc_id = ? and version <= ?"#)
+        .bind(doc_id.to_string())
+        .bind(version - i64::from(state.config.max_document_versions))
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+    touch_document(&mut *tx, doc_id, caller)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &mut *tx,
+        caller,
+        "upload_content",
+        &doc_id.to_string(),
+        "ok",
+    )
+    .await
+    .map_err(internal_error)?;
+
+    tx.commit().await.map_err(internal_error)?;
+    state.events.publish(*doc_id, EventKind::Content);
+    Ok(version)
+}
+
+#[derive(Deserialize)]
+pub struct ContentHistory {
+    doc_id: Uuid,
+}
+
+/// One kept version of a document's content.
+#[derive(Debug, Serialize)]
+pub struct ContentVersion {
+    version: i64,
+    content_sha256: String,
+    author_key_id: String,
+    updated_at: String,
+    /// Whether it was uploaded with a detached signature.
+    signed: bool,
+}
+
+/// Lists the kept versions of a document's content, newest first, to an
+/// owner or sharee.
+pub async fn handle_content_history(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ContentHistory>,
+) -> Result<Json<Vec<ContentVersion>>, AppError> {
+    content_history(&pool, request).await
+}
+
+/// As `handle_content_history`, signed in the query string.
+pub async fn handle_get_content_history(
+    State(pool): State<SqlitePool>,
+    SignedQuery(request): SignedQuery<ContentHistory>,
+) -> Result<Json<Vec<ContentVersion>>, AppError> {
+    content_history(&pool, request).await
+}
+
+async fn content_history(
+    pool: &SqlitePool,
+    request: SignedRequest<ContentHistory>,
+) -> Result<Json<Vec<ContentVersion>>, AppError> {
+    let doc_id = &request.payload.doc_id;
+    require_reader(pool, doc_id, &request.key_id).await?;
+    let rows: Vec<(i64, String, String, String, bool)> = sqlx::query_as(
+        r#"select version, content_sha256, author_key_id, updated_at,
+            content_signature is not null
+        from document_versions where doc_id = ? order by version desc"#,
+    )
+    .bind(doc_id.to_string())
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+    let versions = rows
+        .into_iter()
+        .map(
+            |(version, content_sha256, author_key_id, updated_at, signed)| ContentVersion {
+                version,
+                content_sha256,
+                author_key_id,
+                updated_at,
+                signed,
+            },
+        )
+        .collect();
+    Ok(Json(versions))
+}
+
+#[derive(Deserialize)]
+pub struct RevertContent {
+    doc_id: Uuid,
+    /// The kept version to restore.
+    version: i64,
+    /// As `UploadContent::expected_version`.
+    expected_version: Option<i64>,
+}
+
+/// Restores an earlier version's content, and its signature if it had one,
+/// as a new version. Owners and `write` sharees only, like any upload.
+pub async fn handle_revert_content(
+    State(state): State<AppState>,
+    request: SignedRequest<RevertContent>,
+) -> Result<Response, AppError> {
+    let payload = request.payload;
+    let doc_id = &payload.doc_id;
+    require_writer(&state.pool, doc_id, &request.key_id).await?;
+    let kept: Option<(Vec<u8>, Option<String>, Option<String>)> = sqlx::query_as(
+        r#"select content, content_signature, signer_key_id from document_versions
+        where doc_id = ? and version = ?"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(payload.version)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal_error)?;
+    let Some((content, signature, signer)) = kept else {
+        return Err(AppError::NotFound("version not found".to_string()));
+    };
+    let signer = signer.as_deref().map(key_id_from_text).transpose();
+    let signer = signer.map_err(internal_error)?;
+    let signature = signature.as_deref().zip(signer.as_ref());
+
+    let version = upload_content(
+        &state,
+        doc_id,
+        &request.key_id,
+        &content,
+        signature,
+        payload.expected_version,
+    )
+    .await?;
+    Ok(([(CONTENT_VERSION, version.to_string())], "ok").into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DownloadContent {
+    doc_id: Uuid,
+}
+
+/// Returns the document's content to an owner or sharee. A single
+/// `Range: bytes=...` is honored with `206`; anything else gets the whole
+/// document. `X-Content-SHA256` carries the hash of the whole document as
+/// uploaded, so clients can spot storage corruption, and `X-Content-Version`
+/// the version to make a conditional upload against. Content uploaded with a
+/// detached signature comes with it, hex-encoded, in `X-Content-Signature`.
+pub async fn handle_download_content(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: SignedRequest<DownloadContent>,
+) -> Result<Response, AppError> {
+    let pool = &state.pool;
+    let doc_id = &request.payload.doc_id;
+    let content = readable_content(pool, doc_id, &request.key_id).await?;
+    let len = content.len();
+
+    let (stored_hash, version): (Option<String>, i64) =
+        sqlx::query_as(r#"select content_sha256, version from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(pool)
+            .await
+            .map_err(internal_error)?;
+    if state.config.verify_content_hashes
+        && let Some(stored) = &stored_hash
+        && *stored != sha256_hex(&content)
+    {
+        tracing::error!(%doc_id, "stored content does not match its hash");
+        return Err(internal_error("stored content does not match its hash").into());
+    }
+    let hash = stored_hash.unwrap_or_else(|| sha256_hex(&content));
+
+    let mut extra_headers = HeaderMap::new();
+    extra_headers.insert(
+        CONTENT_SHA256,
+        HeaderValue::try_from(hash).map_err(internal_error)?,
+    );
+    extra_headers.insert(CONTENT_VERSION, HeaderValue::from(version));
+    if let Some(signature) = content_signature(pool, doc_id).await? {
+        extra_headers.insert(
+            CONTENT_SIGNATURE,
+            HeaderValue::try_from(signature).map_err(internal_error)?,
+        );
+    }
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Ok(None), |value| parse_range(value, len));
+
+    match range {
+        Ok(Some(range)) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                extra_headers,
+                [
+                    (header::CONTENT_TYPE, CONTENT_TYPE.to_string()),
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::CONTENT_RANGE, content_range),
+                ],
+                content[range].to_vec(),
+            )
+                .into_response())
+        }
+        Ok(None) => Ok((
+            StatusCode::OK,
+            extra_headers,
+            [
+                (header::CONTENT_TYPE, CONTENT_TYPE),
+                (header::ACCEPT_RANGES, "bytes"),
+            ],
+            content,
+        )
+            .into_response()),
+        Err(RangeNotSatisfiable) => Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+        )
+            .into_response()),
+    }
+}
+
+/// The stored detached signature in binary form, hex-encoded.
+async fn content_signature(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let armored: Option<String> =
+        sqlx::query_scalar(r#"select content_signature from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(pool)
+            .await
+            .map_err(internal_error)?;
+    let Some(armored) = armored else {
+        return Ok(None);
+    };
+    let (signature, _) =
+        DetachedSignature::from_armor_single(armored.as_bytes()).map_err(internal_error)?;
+    let bytes = signature.to_bytes().map_err(internal_error)?;
+    Ok(Some(hex::encode(bytes)))
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+async fn readable_content(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    require_reader(pool, doc_id, caller).await?;
+    let content: Option<Vec<u8>> =
+        sqlx::query_scalar(r#"select content from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(pool)
+            .await
+            .map_err(internal_error)?;
+    Ok(content.unwrap_or_default())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyContent {
+    doc_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyContentQuery {}
+
+/// Whether a document's stored content is a message validly signed by a
+/// registered key.
+#[derive(Debug, Serialize)]
+pub struct ContentVerification {
+    signer_key_id: Option<String>,
+    valid: bool,
+    /// Why the content didn't verify.
+    error: Option<String>,
+}
+
+/// Checks the signature on content that clients uploaded already signed,
+/// armored or not, so they don't each have to.
+pub async fn handle_verify_content(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<VerifyContent>,
+) -> Result<Json<ContentVerification>, AppError> {
+    let content = readable_content(&pool, &request.payload.doc_id, &request.key_id).await?;
+    Ok(Json(verify_content(&pool, &content).await?))
+}
+
+/// As `handle_verify_content`, signed in the query string.
+pub async fn handle_get_verify_content(
+    State(pool): State<SqlitePool>,
+    Path(doc_id): Path<Uuid>,
+    SignedQuery(request): SignedQuery<VerifyContentQuery>,
+) -> Result<Json<ContentVerification>, AppError> {
+    let content = readable_content(&pool, &doc_id, &request.key_id).await?;
+    Ok(Json(verify_content(&pool, &content).await?))
+}
+
+async fn verify_content(
+    pool: &SqlitePool,
+    content: &[u8],
+) -> Result<ContentVerification, (StatusCode, String)> {
+    let parsed = parse_message(content);
+    let failed = |signer_key_id, error: String| ContentVerification {
+        signer_key_id,
+        valid: false,
+        error: Some(error),
+    };
+
+    let (signature, data) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => return Ok(failed(None, error.to_string())),
+    };
+    let signer = match message_keyid(&signature) {
+        Ok(signer) => signer,
+        Err(error) => return Ok(failed(None, error.to_string())),
+    };
+    let Some(key) = get_signer_key(pool, &signer)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok(failed(
+            Some(key_id_to_text(&signer)),
+            "signer is not registered".to_string(),
+        ));
+    };
+    // a signing subkey signs for its account
+    let signer_key_id = Some(key_id_to_text(&key.key_id()));
+    Ok(match verify_signed_by(&signature, &key, &data) {
+        Ok(_) => ContentVerification {
+            signer_key_id,
+            valid: true,
+            error: None,
+        },
+        Err(error) => failed(signer_key_id, error.to_string()),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct RangeNotSatisfiable;
+
+/// Interprets a `Range` header against a body of `len` bytes. Headers we
+/// don't understand (other units, several ranges, bad syntax) are ignored
+/// with `Ok(None)`, as RFC 9110 allows.
+fn parse_range(value: &str, len: usize) -> Result<Option<Range<usize>>, RangeNotSatisfiable> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=a-b
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(len),
+        // bytes=a-
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        // bytes=-n, the last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(RangeNotSatisfiable);
+            }
+            len.saturating_sub(suffix)..len
+        }
+        _ => return Ok(None),
+    };
+
+    if range.start >= len {
+        return Err(RangeNotSatisfiable);
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use pgp::{
+        composed::{
+            KeyType, MessageBuilder, SecretKeyParamsBuilder, SignedSecretKey, SubkeyParamsBuilder,
+        },
+        crypto::hash::HashAlgorithm,
+        types::{KeyDetails, Password},
+    };
+    use rand::thread_rng;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        SharePermission,
+        config::Config,
+        create_document, share_document,
+        test_util::{
+            TestClient, error_message, generate_key, post, register, send, sign, sign_json,
+            test_app,
+        },
+    };
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=2-4", 10), Ok(Some(2..5)));
+        assert_eq!(parse_range("bytes=8-20", 10), Ok(Some(8..10)));
+        assert_eq!(parse_range("bytes=7-", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some(0..10)));
+        assert_eq!(parse_range("bytes=10-", 10), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=-0", 10), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=4-2", 10), Ok(None));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("lines=0-1", 10), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_download_byte_range() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let reader = generate_key("reader <reader@example.com>");
+        let stranger = generate_key("stranger <stranger@example.com>");
+        for key in [&owner, &reader, &stranger] {
+            register(&pool, key).await;
+        }
+        let doc_id = create_document(&pool, &owner.key_id(), "notes", None).await;
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &reader.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+
+        let content = "# Notes\n\nsome markdown worth resuming\n";
+        let upload = sign_json(&owner, json!({ "doc_id": doc_id, "content": content }));
+        let (status, _) = post(&app, "/documents/content/upload", upload).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let download = |key, range: Option<&str>| {
+            let mut request = Request::post("/documents/content");
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+            let body = sign_json(key, json!({ "doc_id": doc_id }));
+            request.body(Body::from(body)).unwrap()
+        };
+
+        let (status, body) = send(&app, download(&reader, None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, content);
+
+        let (status, body) = send(&app, download(&reader, Some("bytes=9-12"))).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, &content[9..13]);
+
+        let (status, _) = send(&app, download(&reader, Some("bytes=1000-"))).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let (status, _) = send(&app, download(&stranger, Some("bytes=9-12"))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // sharees can read but not write
+        let upload = sign_json(&reader, json!({ "doc_id": doc_id, "content": "" }));
+        let (status, _) = post(&app, "/documents/content/upload", upload).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_detached_signature_upload() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let doc_id = alice.create_document("notes").await;
+
+        let content = "# Notes\n\nlong enough that re-signing inline hurts\n";
+        let signature = DetachedSignature::sign_binary_data(
+            thread_rng(),
+            &alice.key.primary_key,
+            &Password::empty(),
+            HashAlgorithm::Sha256,
+            content.as_bytes(),
+        )
+        .unwrap();
+        let armored = signature.to_armored_string(Default::default()).unwrap();
+        let upload = |content: &str| {
+            alice.post(
+                "/documents/content/upload_signed",
+                json!({
+                    "doc_id": doc_id,
+                    "content": content,
+                    "signature": armored,
+                    "signer_key_id": key_id_to_text(&alice.key_id()),
+                }),
+            )
+        };
+
+        let (status, body) = upload("# Tampered\n").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(alice.download_content(doc_id).await.1, "");
+
+        let extra_headers = || async {
+            let download = Request::post("/documents/content")
+                .body(Body::from(sign_json(
+                    &alice.key,
+                    json!({ "doc_id": doc_id }),
+                )))
+                .unwrap();
+            let response = app.clone().oneshot(download).await.unwrap();
+            let header = response.headers().get(CONTENT_SIGNATURE);
+            header.map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let (status, body) = upload(content).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            extra_headers().await,
+            Some(hex::encode(signature.to_bytes().unwrap()))
+        );
+
+        // replacing the content without a signature drops the old one
+        alice.upload_content(doc_id, "unsigned").await;
+        assert_eq!(extra_headers().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stale_upload_conflicts() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let doc_id = alice.create_document("notes").await;
+        assert_eq!(alice.list_documents().await[0]["version"], 0);
+        let upload = |content: &str, expected_version: Option<i64>| {
+            alice.post(
+                "/documents/content/upload",
+                json!({
+                    "doc_id": doc_id,
+                    "content": content,
+                    "expected_version": expected_version,
+                }),
+            )
+        };
+
+        // two editors both start from version 0
+        let (status, _) = upload("mine", Some(0)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = upload("theirs", Some(0)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "error": "version conflict", "code": "conflict", "current_version": 1 })
+        );
+        assert_eq!(
+            alice.download_content(doc_id).await,
+            (StatusCode::OK, "mine".to_string())
+        );
+
+        let download = Request::post("/documents/content")
+            .body(Body::from(sign_json(
+                &alice.key,
+                json!({ "doc_id": doc_id }),
+            )))
+            .unwrap();
+        let response = app.clone().oneshot(download).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_VERSION], "1");
+        let (status, _) = upload("merged", Some(1)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // unconditional uploads still go through
+        let (status, _) = upload("forced", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(alice.list_documents().await[0]["version"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_history_and_revert() {
+        let config = Config {
+            max_document_versions: 3,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let [alice, bob, mallory] = ["alice", "bob", "mallory"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        alice.create_account().await;
+        bob.create_account().await;
+        mallory.create_account().await;
+        let doc_id = alice.create_shared_document("notes", &[&bob]).await;
+        for content in ["one", "two", "three", "four"] {
+            assert_eq!(alice.upload_content(doc_id, content).await, StatusCode::OK);
+        }
+        let history = async |client: &TestClient| {
+            client
+                .post("/documents/history", json!({ "doc_id": doc_id }))
+                .await
+        };
+
+        let (status, body) = history(&bob).await;
+        assert_eq!(status, StatusCode::OK);
+        let versions: Value = serde_json::from_str(&body).unwrap();
+        let numbers: Vec<_> = versions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|version| version["version"].as_i64().unwrap())
+            .collect();
+        assert_eq!(numbers, [4, 3, 2]);
+        assert_eq!(versions[0]["content_sha256"], sha256_hex(b"four"));
+        assert_eq!(
+            versions[0]["author_key_id"],
+            key_id_to_text(&alice.key_id())
+        );
+        assert_eq!(history(&mallory).await.0, StatusCode::NOT_FOUND);
+
+        let revert = async |client: &TestClient, version: i64| {
+            client
+                .post(
+                    "/documents/revert",
+                    json!({ "doc_id": doc_id, "version": version }),
+                )
+                .await
+        };
+        assert_eq!(revert(&bob, 2).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(revert(&alice, 1).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(revert(&alice, 2).await.0, StatusCode::OK);
+        assert_eq!(
+            bob.download_content(doc_id).await,
+            (StatusCode::OK, "two".to_string())
+        );
+        let (_, body) = history(&alice).await;
+        let versions: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(versions[0]["version"], 5);
+        assert_eq!(versions[0]["content_sha256"], sha256_hex(b"two"));
+        assert_eq!(versions.as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_signed_content_can_be_required() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        let mallory = TestClient::new(&app, "mallory <mallory@example.com>");
+        alice.create_account().await;
+        mallory.create_account().await;
+        let (status, body) = alice
+            .post(
+                "/create_document",
+                json!({ "name": "ledger", "require_signed_content": true }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let doc_id = Uuid::parse_str(&body).unwrap();
+
+        let (status, body) = alice
+            .post(
+                "/documents/content/upload",
+                json!({ "doc_id": doc_id, "content": "unsigned" }),
+            )
+            .await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::BAD_REQUEST, "document requires signed content")
+        );
+
+        let content = "# Ledger\n";
+        let upload_signed_by = |signer: &TestClient| {
+            let signature = DetachedSignature::sign_binary_data(
+                thread_rng(),
+                &signer.key.primary_key,
+                &Password::empty(),
+                HashAlgorithm::Sha256,
+                content.as_bytes(),
+            )
+            .unwrap();
+            alice.post(
+                "/documents/content/upload_signed",
+                json!({
+                    "doc_id": doc_id,
+                    "content": content,
+                    "signature": signature.to_armored_string(Default::default()).unwrap(),
+                    "signer_key_id": key_id_to_text(&signer.key_id()),
+                }),
+            )
+        };
+        let (status, _) = upload_signed_by(&mallory).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(alice.download_content(doc_id).await.1, "");
+
+        let (status, body) = upload_signed_by(&alice).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(alice.download_content(doc_id).await.1, content);
+    }
+
+    #[tokio::test]
+    async fn test_content_hash_detects_corruption() {
+        for verify_content_hashes in [false, true] {
+            let config = Config {
+                verify_content_hashes,
+                ..Config::default()
+            };
+            let (app, pool) = test_app(config).await;
+            let alice = TestClient::new(&app, "alice <alice@example.com>");
+            alice.create_account().await;
+            let doc_id = alice.create_document("notes").await;
+            alice.upload_content(doc_id, "# Notes\n").await;
+
+            let download = || async {
+                let request = Request::post("/documents/content")
+                    .body(Body::from(sign_json(
+                        &alice.key,
+                        json!({ "doc_id": doc_id }),
+                    )))
+                    .unwrap();
+                app.clone().oneshot(request).await.unwrap()
+            };
+            let expected = sha256_hex(b"# Notes\n");
+            let response = download().await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_SHA256], expected.as_str());
+            assert_eq!(
+                alice.list_documents().await[0]["content_sha256"],
+                expected.as_str()
+            );
+
+            sqlx::query(r#"update documents set content = 'bit rot' where doc_id = ?"#)
+                .bind(doc_id.to_string())
+                .execute(&pool)
+                .await
+                .unwrap();
+            let response = download().await;
+            if verify_content_hashes {
+                assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            } else {
+                // the client sees the mismatch itself
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[CONTENT_SHA256], expected.as_str());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_content_signature() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let mallory = generate_key("mallory <mallory@example.com>");
+        register(&pool, &alice).await;
+        let doc_id = create_document(&pool, &alice.key_id(), "notes", None).await;
+
+        let store = |content: Vec<u8>| {
+            sqlx::query(r#"update documents set content = ? where doc_id = ?"#)
+                .bind(content)
+                .bind(doc_id.to_string())
+                .execute(&pool)
+        };
+        let verify = || async {
+            let verify = sign_json(&alice, json!({ "doc_id": doc_id }));
+            let (status, body) = post(&app, "/documents/content/verify", verify).await;
+            assert_eq!(status, StatusCode::OK);
+            serde_json::from_str::<Value>(&body).unwrap()
+        };
+        let armored_by = |skey: &SignedSecretKey, text: &str| {
+            let mut builder = MessageBuilder::from_bytes("", text.as_bytes().to_vec());
+            builder.sign(&skey.primary_key, Password::empty(), HashAlgorithm::Sha256);
+            builder
+                .to_armored_string(thread_rng(), Default::default())
+                .unwrap()
+        };
+
+        let upload = sign_json(
+            &alice,
+            json!({ "doc_id": doc_id, "content": armored_by(&alice, "# Notes\n") }),
+        );
+        let (status, _) = post(&app, "/documents/content/upload", upload).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            verify().await,
+            json!({
+                "signer_key_id": key_id_to_text(&alice.key_id()),
+                "valid": true,
+                "error": null,
+            })
+        );
+
+        // the literal data sits in the clear in an uncompressed binary message
+        let mut tampered = sign(&alice, b"hello");
+        let at = tampered
+            .windows(5)
+            .position(|window| window == b"hello")
+            .unwrap();
+        tampered[at] = b'j';
+        store(tampered).await.unwrap();
+        let result = verify().await;
+        assert_eq!(result["signer_key_id"], key_id_to_text(&alice.key_id()));
+        assert_eq!(result["valid"], false);
+
+        store(armored_by(&mallory, "# Notes\n").into_bytes())
+            .await
+            .unwrap();
+        let result = verify().await;
+        assert_eq!(result["signer_key_id"], key_id_to_text(&mallory.key_id()));
+        assert_eq!(result["valid"], false);
+        assert_eq!(result["error"], "signer is not registered");
+
+        store(b"just markdown".to_vec()).await.unwrap();
+        let result = verify().await;
+        assert_eq!(result["signer_key_id"], Value::Null);
+        assert_eq!(result["valid"], false);
+    }
+
+    #[tokio::test]
+    async fn test_content_signed_by_subkey() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let params = SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("alice <alice@example.com>".into())
+            .subkey(
+                SubkeyParamsBuilder::default()
+                    .key_type(KeyType::Ed25519Legacy)
+                    .can_sign(true)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let key = params
+            .generate(thread_rng())
+            .unwrap()
+            .sign(thread_rng(), &Password::empty())
+            .unwrap();
+        let alice = TestClient {
+            app: app.clone(),
+            key,
+        };
+        alice.create_account().await;
+        let doc_id = alice.create_document("notes").await;
+        let subkey = &alice.key.secret_subkeys[0].key;
+
+        let mut builder = MessageBuilder::from_bytes("", b"# Notes\n".to_vec());
+        builder.sign(subkey, Password::empty(), HashAlgorithm::Sha256);
+        let signed = builder
+            .to_armored_string(thread_rng(), Default::default())
+            .unwrap();
+        alice.upload_content(doc_id, &signed).await;
+        let (status, body) = alice
+            .post("/documents/content/verify", json!({ "doc_id": doc_id }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({
+                "signer_key_id": key_id_to_text(&alice.key_id()),
+                "valid": true,
+                "error": null,
+            })
+        );
+
+        let content = "# Notes\n\nsigned apart\n";
+        let signature = DetachedSignature::sign_binary_data(
+            thread_rng(),
+            subkey,
+            &Password::empty(),
+            HashAlgorithm::Sha256,
+            content.as_bytes(),
+        )
+        .unwrap();
+        let upload = json!({
+            "doc_id": doc_id,
+            "content": content,
+            "signature": signature.to_armored_string(Default::default()).unwrap(),
+            "signer_key_id": key_id_to_text(&alice.key_id()),
+        });
+        let (status, body) = alice.post("/documents/content/upload_signed", upload).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(alice.download_content(doc_id).await.1, content);
+    }
+}
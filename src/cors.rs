@@ -0,0 +1,91 @@
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::Config;
+
+/// Lets browser clients served from `cors_allowed_origins` call the API.
+/// With no origins configured no CORS headers are sent, so browsers keep
+/// every cross-origin request blocked.
+pub fn layer(config: &Config) -> CorsLayer {
+    let origins = config
+        .cors_allowed_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok());
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::RANGE])
+        .expose_headers([
+            header::CONTENT_RANGE,
+            header::RETRY_AFTER,
+            HeaderName::from_static("x-content-signature"),
+            HeaderName::from_static("x-content-sha256"),
+            HeaderName::from_static("x-content-version"),
+            HeaderName::from_static("x-truncated"),
+            HeaderName::from_static("x-next-cursor"),
+        ])
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_util::test_app;
+
+    async fn preflight(app: &Router, origin: &str) -> (StatusCode, Option<String>) {
+        let request = Request::options("/documents")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let allowed = response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), allowed)
+    }
+
+    #[tokio::test]
+    async fn test_only_listed_origins_are_allowed() {
+        let (app, _pool) = test_app(Config::default()).await;
+        assert_eq!(
+            preflight(&app, "https://notes.example.com").await,
+            (StatusCode::OK, None)
+        );
+
+        let config = Config {
+            cors_allowed_origins: vec!["https://notes.example.com".to_string()],
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        assert_eq!(
+            preflight(&app, "https://notes.example.com").await,
+            (
+                StatusCode::OK,
+                Some("https://notes.example.com".to_string())
+            )
+        );
+        assert_eq!(
+            preflight(&app, "https://evil.example.com").await,
+            (StatusCode::OK, None)
+        );
+
+        let request = Request::get("/health")
+            .header(header::ORIGIN, "https://notes.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://notes.example.com"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{collections::BTreeMap, time::Duration};
+
+/// An error response, rendered as the JSON envelope
+/// `{ "error": "...", "code": "..." }`. `error` is for people; `code` is
+/// one of a fixed set clients can match on.
+///
+/// Document endpoints answer `404 "document not found"` both for documents
+/// that don't exist and for ones the caller can neither own nor read, so a
+/// response never confirms that someone else's document exists. Only
+/// callers who can already see a document (sharees) get a `403` when they
+/// try something reserved for owners.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AppError {
+    NotFound(String),
+    /// A payload that parsed but failed validation, rendered as
+    /// `{ "error": "validation", "fields": { "<field>": "<problem>" } }`.
+    BadRequest(FieldErrors),
+    Status(StatusCode, String),
+    /// `429`, telling the client how long to wait in `Retry-After`.
+    RateLimited(Duration),
+    /// `409` for a conditional update made against an old version, rendered
+    /// with the `current_version` to merge against.
+    VersionConflict(i64),
+}
+
+/// Validation problems, keyed by the payload field they belong to.
+pub type FieldErrors = BTreeMap<&'static str, String>;
+
+/// Field-level checks on a deserialized request payload.
+pub trait Validate {
+    fn validate(&self) -> Result<(), FieldErrors>;
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Status(status, _) => *status,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::VersionConflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    /// What kind of error this is, for clients, by status.
+    pub fn code(&self) -> &'static str {
+        match self.status() {
+            StatusCode::BAD_REQUEST => "bad_request",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::CONFLICT => "conflict",
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+            StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+            StatusCode::NOT_IMPLEMENTED => "not_implemented",
+            status if status.is_server_error() => "internal",
+            _ => "bad_request",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            AppError::NotFound(message) | AppError::Status(_, message) => message,
+            AppError::BadRequest(_) => "validation",
+            AppError::RateLimited(_) => "rate limit exceeded",
+            AppError::VersionConflict(_) => "version conflict",
+        }
+    }
+}
+
+impl From<(StatusCode, String)> for AppError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        AppError::Status(status, message)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (error, code) = (self.message(), self.code());
+        let body = match &self {
+            AppError::BadRequest(fields) => {
+                json!({ "error": error, "code": code, "fields": fields })
+            }
+            AppError::VersionConflict(current) => {
+                json!({ "error": error, "code": code, "current_version": current })
+            }
+            _ => json!({ "error": error, "code": code }),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let AppError::RateLimited(retry_after) = self {
+            let secs = retry_after.as_secs().max(1);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+pub async fn handle_unknown_route(uri: Uri) -> AppError {
+    tracing::debug!(%uri, "unknown route");
+    AppError::NotFound("not found".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        internal_error,
+        test_util::{get, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_unknown_route_returns_json_404() {
+        let (app, _pool) = test_app(Config::default()).await;
+
+        let (status, body) = get(&app, "/does/not/exist").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "not found", "code": "not_found" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internal_errors_hide_their_detail() {
+        let response =
+            AppError::from(internal_error("database disk image is malformed")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "internal error", "code": "internal" })
+        );
+    }
+}
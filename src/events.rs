@@ -0,0 +1,205 @@
+use axum::{
+    extract::{FromRef, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use pgp::types::KeyId;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::{SignedQuery, SignedRequest},
+    require_reader,
+};
+
+/// Events a slow subscriber may fall behind by before it's told it missed
+/// some.
+const CAPACITY: usize = 1024;
+
+/// Changes to documents, fanned out to `/documents/events` subscribers.
+#[derive(Clone)]
+pub struct DocumentEvents(broadcast::Sender<DocumentEvent>);
+
+impl Default for DocumentEvents {
+    fn default() -> Self {
+        DocumentEvents(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl FromRef<AppState> for DocumentEvents {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl DocumentEvents {
+    /// Tells whoever is listening and can see `doc_id` that it changed.
+    pub fn publish(&self, doc_id: Uuid, kind: EventKind) {
+        // no subscribers is not an error
+        let _ = self.0.send(DocumentEvent { doc_id, kind });
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DocumentEvent {
+    doc_id: Uuid,
+    kind: EventKind,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Created,
+    Renamed,
+    Content,
+}
+
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Renamed => "renamed",
+            EventKind::Content => "content",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Subscribe {}
+
+/// Streams changes to the documents the signer owns or has been shared, as
+/// server-sent events named by `EventKind`. A `lagged` event means some were
+/// dropped and the client should list its documents again.
+pub async fn handle_document_events(
+    State(state): State<AppState>,
+    request: SignedRequest<Subscribe>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    subscribe(&state, request.key_id)
+}
+
+/// As `handle_document_events`, signed in the query string, which is all an
+/// `EventSource` can send.
+pub async fn handle_get_document_events(
+    State(state): State<AppState>,
+    SignedQuery(request): SignedQuery<Subscribe>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    subscribe(&state, request.key_id)
+}
+
+fn subscribe(
+    state: &AppState,
+    key_id: KeyId,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>> + use<>> {
+    let subscriber = (state.events.0.subscribe(), state.pool.clone(), key_id);
+    let drain = state.drain.clone();
+    let events = stream::unfold(subscriber, |(mut receiver, pool, key_id)| async move {
+        let event = next_visible(&mut receiver, &pool, &key_id).await?;
+        Some((event, (receiver, pool, key_id)))
+    })
+    // an open stream would otherwise hold up shutdown forever
+    .take_until(async move { drain.closed().await });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Waits for the next event about a document `key_id` can read. Access is
+/// checked as each event arrives, so shares made or revoked mid-stream count.
+async fn next_visible(
+    receiver: &mut broadcast::Receiver<DocumentEvent>,
+    pool: &SqlitePool,
+    key_id: &KeyId,
+) -> Option<Result<Event, axum::Error>> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if require_reader(pool, &event.doc_id, key_id).await.is_ok() {
+                    return Some(Event::default().event(event.kind.name()).json_data(&event));
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                return Some(Ok(Event::default()
+                    .event("lagged")
+                    .data(missed.to_string())));
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use futures_util::StreamExt;
+    use serde_json::{Value, json};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    use crate::{
+        config::Config,
+        test_util::{TestClient, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_collaborators_see_changes() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let [alice, bob, carol] = ["alice", "bob", "carol"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        alice.create_account().await;
+        bob.create_account().await;
+        carol.create_account().await;
+        let doc_id = alice.create_shared_document("notes", &[&bob]).await;
+
+        let request = Request::post("/documents/events")
+            .body(Body::from(sign_json(&bob.key, json!({}))))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut frames = response.into_body().into_data_stream();
+        let mut next_event = async || {
+            let frame = tokio::time::timeout(Duration::from_secs(1), frames.next())
+                .await
+                .expect("no event within a second")
+                .unwrap()
+                .unwrap();
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            let field = |name: &str| {
+                frame
+                    .lines()
+                    .find_map(|line| line.strip_prefix(&format!("{name}:")))
+                    .unwrap()
+                    .trim()
+                    .to_string()
+            };
+            (
+                field("event"),
+                serde_json::from_str::<Value>(&field("data")).unwrap(),
+            )
+        };
+
+        // carol's document is none of bob's business
+        carol.create_document("private").await;
+        alice.upload_content(doc_id, "# Notes\n").await;
+        let (event, data) = next_event().await;
+        assert_eq!(event, "content");
+        assert_eq!(data, json!({ "doc_id": doc_id, "kind": "content" }));
+
+        let (status, _) = alice
+            .post(
+                "/documents/rename",
+                json!({ "doc_id": doc_id, "name": "minutes" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(next_event().await.0, "renamed");
+
+        let shared = alice.create_shared_document("agenda", &[&bob]).await;
+        let (event, data) = next_event().await;
+        assert_eq!(event, "created");
+        assert_eq!(data["doc_id"], shared.to_string());
+    }
+}
@@ -0,0 +1,924 @@
+use axum::{
+    Json,
+    extract::State,
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+};
+use pgp::{composed::SignedPublicKey, types::KeyId};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool, sqlite::SqliteRow};
+use uuid::Uuid;
+
+use crate::{
+    Access, AppError, AppState,
+    auth::{SignedQuery, SignedRequest},
+    internal_error, key_id_to_text, parse_stored_key,
+    storage::Storage,
+    wkd::EmailIndex,
+};
+
+/// One entry in a document listing. Listings are always arrays of these,
+/// ordered by `doc_id` unless asked otherwise, so clients can diff
+/// successive responses.
+#[derive(Debug, Serialize)]
+pub struct DocumentSummary {
+    pub doc_id: Uuid,
+    pub name: String,
+    pub owner_key_id: String,
+    /// The address in the owner's primary user id, when it has one.
+    pub owner_email: Option<String>,
+    /// Unknown for documents made before creation times were kept.
+    pub created_at: Option<String>,
+    /// When the document, its content or who it's shared with last changed.
+    pub last_updated: Option<String>,
+    /// Hex SHA-256 of the content as last uploaded.
+    pub content_sha256: Option<String>,
+    /// How many times content has been uploaded, for conditional uploads.
+    pub version: i64,
+    /// Only filled in when asked for with `include_owner_key`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_key: Option<OwnerKey>,
+}
+
+/// Enough about the owner's key for a client to pin it.
+#[derive(Debug, Serialize)]
+pub struct OwnerKey {
+    pub fingerprint: String,
+    /// The OpenPGP public-key algorithm id (RFC 9580, section 9.1), such as
+    /// 1 for RSA or 22 for the legacy EdDSA ed25519 keys.
+    pub algorithm: u8,
+}
+
+/// A listing cut off at `max_listing_rows`. The rows are still a plain
+/// array; when there are more, the response says so with `X-Truncated: true`
+/// and an `X-Next-Cursor` to pass back as `after`.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Uuid>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` rows, the extra one only
+    /// showing that there's more.
+    fn from_rows(mut items: Vec<T>, limit: u32, doc_id: impl Fn(&T) -> Uuid) -> Self {
+        let next_cursor = if items.len() > limit as usize {
+            items.truncate(limit as usize);
+            items.last().map(doc_id)
+        } else {
+            None
+        };
+        Page { items, next_cursor }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Page<T> {
+    fn into_response(self) -> Response {
+        let mut response = Json(self.items).into_response();
+        if let Some(cursor) = self.next_cursor {
+            let headers = response.headers_mut();
+            headers.insert("x-truncated", HeaderValue::from_static("true"));
+            headers.insert(
+                "x-next-cursor",
+                HeaderValue::try_from(cursor.to_string()).expect("uuids are valid headers"),
+            );
+        }
+        response
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListDocuments {
+    /// Continue after this `doc_id`, from a previous page's `X-Next-Cursor`.
+    after: Option<Uuid>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    /// Rows per page, at most `max_listing_rows`, which is also the default.
+    limit: Option<u32>,
+    /// Rows to skip, counted after `after` if both are given. Pages found by
+    /// offset can shift when documents come and go; `after` doesn't.
+    #[serde(default)]
+    offset: u32,
+    /// Only documents whose name contains this, ignoring ASCII case. A search
+    /// also covers documents shared with the signer.
+    q: Option<String>,
+}
+
+/// What a listing of one's own documents is ordered by. Ties go by
+/// `doc_id`, so the order is total and `after` can page through it.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    DocId,
+    Name,
+    LastUpdated,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How `get_user_docs` orders and pages its rows.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListingOrder {
+    pub key: SortKey,
+    pub order: SortOrder,
+}
+
+impl ListingOrder {
+    /// The sort expression for `documents`. Missing names and times sort as
+    /// empty, before everything else.
+    fn key_sql(self) -> &'static str {
+        match self.key {
+            SortKey::DocId => "documents.doc_id",
+            SortKey::Name => "coalesce(documents.name, '')",
+            SortKey::LastUpdated => "coalesce(documents.last_updated, '')",
+        }
+    }
+
+    /// `order by` terms, and the condition for rows that come after the
+    /// `doc_id` bound to `cursor`.
+    fn sql(self, cursor: &str) -> (String, String) {
+        let key = self.key_sql();
+        let (direction, comparison) = match self.order {
+            SortOrder::Asc => ("asc", ">"),
+            SortOrder::Desc => ("desc", "<"),
+        };
+        let order_by = format!("{key} {direction}, documents.doc_id {direction}");
+        let after = match self.key {
+            SortKey::DocId => format!("documents.doc_id {comparison} {cursor}"),
+            _ => format!(
+                "({key}, documents.doc_id) {comparison} \
+                (select {key}, documents.doc_id from documents where doc_id = {cursor})"
+            ),
+        };
+        (order_by, after)
+    }
+}
+
+/// Lists the signer's documents.
+pub async fn handle_list_documents(
+    State(state): State<AppState>,
+    request: SignedRequest<ListDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    list_documents(&state, request).await
+}
+
+/// Lists the signer's documents, signed in the query string.
+pub async fn handle_get_documents(
+    State(state): State<AppState>,
+    SignedQuery(request): SignedQuery<ListDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    list_documents(&state, request).await
+}
+
+async fn list_documents(
+    state: &AppState,
+    request: SignedRequest<ListDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    let payload = request.payload;
+    let max = state.config.max_listing_rows;
+    let limit = payload.limit.map_or(max, |limit| limit.clamp(1, max));
+    let ordering = ListingOrder {
+        key: payload.sort,
+        order: payload.order,
+    };
+    let search = payload.q.as_deref().map(like_pattern);
+    let docs = state
+        .storage
+        .get_user_docs(
+            &request.key_id,
+            payload.after,
+            limit,
+            payload.offset,
+            ordering,
+            search.as_deref(),
+        )
+        .await
+        .map_err(internal_error)?;
+    Ok(Page::from_rows(docs, limit, |doc| doc.doc_id))
+}
+
+/// A document a key can see, and what it may do with it.
+#[derive(Debug, Serialize)]
+pub struct DocumentAccess {
+    pub doc_id: Uuid,
+    pub permission: Access,
+}
+
+#[derive(Deserialize)]
+pub struct ListAccess {
+    /// As `ListDocuments::after`.
+    after: Option<Uuid>,
+}
+
+/// Everything the signer can see, owned or shared, in one list.
+pub async fn handle_list_access(
+    State(state): State<AppState>,
+    request: SignedRequest<ListAccess>,
+) -> Result<Page<DocumentAccess>, AppError> {
+    list_access(&state, request).await
+}
+
+/// As `handle_list_access`, signed in the query string.
+pub async fn handle_get_access(
+    State(state): State<AppState>,
+    SignedQuery(request): SignedQuery<ListAccess>,
+) -> Result<Page<DocumentAccess>, AppError> {
+    list_access(&state, request).await
+}
+
+async fn list_access(
+    state: &AppState,
+    request: SignedRequest<ListAccess>,
+) -> Result<Page<DocumentAccess>, AppError> {
+    let limit = state.config.max_listing_rows;
+    let access = get_access(&state.pool, &request.key_id, request.payload.after, limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Page::from_rows(access, limit, |entry| entry.doc_id))
+}
+
+/// The effective permission `key_id` has on each document it can see, after
+/// the `after` cursor. Returns up to `limit + 1` rows, for `Page::from_rows`.
+/// Owning a document trumps also having it shared, and a `write` share
+/// trumps a `read` one.
+pub async fn get_access(
+    pool: &SqlitePool,
+    key_id: &KeyId,
+    after: Option<Uuid>,
+    limit: u32,
+) -> sqlx::Result<Vec<DocumentAccess>> {
+    let rows = sqlx::query(
+        r#"select doc_id, max(rank) as rank from (
+            select doc_id, 2 as rank from document_owners where user_id = ?1
+            union all
+            select doc_id, case permission when 'write' then 1 else 0 end as rank
+            from document_shares where user_id = ?1
+        )
+        where ?2 is null or doc_id > ?2
+        group by doc_id
+        order by doc_id
+        limit ?3"#,
+    )
+    .bind(key_id_to_text(key_id))
+    .bind(after.map(|doc_id| doc_id.to_string()))
+    .bind(i64::from(limit) + 1)
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let doc_id: String = row.get("doc_id");
+            Ok(DocumentAccess {
+                doc_id: Uuid::parse_str(&doc_id)
+                    .map_err(|error| sqlx::Error::Decode(error.into()))?,
+                permission: match row.get::<i64, _>("rank") {
+                    2 => Access::Own,
+                    1 => Access::Write,
+                    _ => Access::Read,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Documents `key_id` owns, including ones it co-owns, paged like
+/// `get_access` but in `ordering`, skipping the first `offset` rows after
+/// the cursor. Under any ordering but `doc_id`, a cursor naming a document
+/// that has since gone ends the listing. With `search`, a `LIKE` pattern
+/// escaped with `\`, only documents named to match are listed, and shared
+/// ones are too.
+pub async fn get_user_docs(
+    pool: &SqlitePool,
+    key_id: &KeyId,
+    after: Option<Uuid>,
+    limit: u32,
+    offset: u32,
+    ordering: ListingOrder,
+    search: Option<&str>,
+) -> anyhow::Result<Vec<DocumentSummary>> {
+    let (order_by, after_cursor) = ordering.sql("?2");
+    let rows = sqlx::query(&format!(
+        r#"select documents.doc_id, documents.name, documents.user_id,
+            documents.created_at, documents.last_updated, documents.content_sha256,
+            documents.version,
+            users.email as owner_email,
+            case when users.email is null then users.public_key end as owner_public_key
+        from documents
+        join users on users.uid = documents.user_id
+        where (documents.doc_id in (select doc_id from document_owners where user_id = ?1)
+            or ?4 is not null
+                and documents.doc_id in (select doc_id from document_shares where user_id = ?1))
+            and (?4 is null or documents.name like ?4 escape '\')
+            and (?2 is null or {after_cursor})
+        order by {order_by}
+        limit ?3 offset ?5"#,
+    ))
+    .bind(key_id_to_text(key_id))
+    .bind(after.map(|doc_id| doc_id.to_string()))
+    .bind(i64::from(limit) + 1)
+    .bind(search)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| document_summary(row, false))
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct ListSharedDocuments {
+    /// As `ListDocuments::after`.
+    after: Option<Uuid>,
+    /// Include each owner's key fingerprint and algorithm.
+    #[serde(default)]
+    include_owner_key: bool,
+}
+
+/// Lists the documents shared with the signer, each with its owner's key id.
+pub async fn handle_list_shared_documents(
+    State(state): State<AppState>,
+    request: SignedRequest<ListSharedDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    list_shared_documents(&state, request).await
+}
+
+/// As `handle_list_shared_documents`, signed in the query string.
+pub async fn handle_get_shared_documents(
+    State(state): State<AppState>,
+    SignedQuery(request): SignedQuery<ListSharedDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    list_shared_documents(&state, request).await
+}
+
+async fn list_shared_documents(
+    state: &AppState,
+    request: SignedRequest<ListSharedDocuments>,
+) -> Result<Page<DocumentSummary>, AppError> {
+    let limit = state.config.max_listing_rows;
+    let payload = request.payload;
+    let docs = state
+        .storage
+        .get_shared_docs(
+            &request.key_id,
+            payload.after,
+            limit,
+            payload.include_owner_key,
+        )
+        .await
+        .map_err(internal_error)?;
+    Ok(Page::from_rows(docs, limit, |doc| doc.doc_id))
+}
+
+/// Documents shared with `key_id`, along with who shared them, paged like
+/// `get_access`.
+pub async fn get_shared_docs(
+    pool: &SqlitePool,
+    key_id: &KeyId,
+    after: Option<Uuid>,
+    limit: u32,
+    include_owner_key: bool,
+) -> anyhow::Result<Vec<DocumentSummary>> {
+    let rows = sqlx::query(
+        r#"select documents.doc_id, documents.name, documents.user_id,
+            documents.created_at, documents.last_updated, documents.content_sha256,
+            documents.version,
+            users.email as owner_email,
+            users.fingerprint as owner_fingerprint,
+            users.key_algorithm as owner_key_algorithm,
+            case when users.email is null then users.public_key end as owner_public_key
+        from document_shares
+        join documents on documents.doc_id = document_shares.doc_id
+        join users on users.uid = documents.user_id
+        where document_shares.user_id = ?1 and (?2 is null or documents.doc_id > ?2)
+        order by documents.doc_id
+        limit ?3"#,
+    )
+    .bind(key_id_to_text(key_id))
+    .bind(after.map(|doc_id| doc_id.to_string()))
+    .bind(i64::from(limit) + 1)
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| document_summary(row, include_owner_key))
+        .collect()
+}
+
+/// A `LIKE` pattern matching names that contain `text` literally, with
+/// `\` as the escape character.
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// The listing entry for a row. `owner_key` is only filled in when
+/// `include_owner_key` asks for it, from the stored fingerprint and
+/// algorithm. The owner's stored key is only read for the address of an
+/// account registered before addresses were stored; one that can't be read
+/// leaves it out rather than failing the listing.
+fn document_summary(row: SqliteRow, include_owner_key: bool) -> anyhow::Result<DocumentSummary> {
+    let doc_id: String = row.get("doc_id");
+    let mut owner_email: Option<String> = row.get("owner_email");
+    if let Some(armored) = row.get::<Option<&str>, _>("owner_public_key") {
+        match parse_stored_key(armored) {
+            Ok(key) => {
+                owner_email = primary_user_id(&key)
+                    .as_deref()
+                    .and_then(EmailIndex::from_user_id)
+                    .map(|index| index.email);
+            }
+            Err(error) => {
+                let owner: &str = row.get("user_id");
+                tracing::warn!(owner, %error, "listing a document without its owner's address");
+            }
+        }
+    }
+    let owner_key = if include_owner_key {
+        let fingerprint: Option<String> = row.get("owner_fingerprint");
+        let algorithm: Option<u8> = row.get("owner_key_algorithm");
+        fingerprint
+            .zip(algorithm)
+            .map(|(fingerprint, algorithm)| OwnerKey {
+                fingerprint,
+                algorithm,
+            })
+    } else {
+        None
+    };
+    Ok(DocumentSummary {
+        doc_id: Uuid::parse_str(&doc_id)?,
+        name: row.get("name"),
+        owner_key_id: row.get("user_id"),
+        owner_email,
+        created_at: row.get("created_at"),
+        last_updated: row.get("last_updated"),
+        content_sha256: row.get("content_sha256"),
+        version: row.get("version"),
+        owner_key,
+    })
+}
+
+/// The user id marked primary, falling back to the first one.
+pub fn primary_user_id(key: &SignedPublicKey) -> Option<String> {
+    let users = &key.details.users;
+    users
+        .iter()
+        .find(|user| user.is_primary())
+        .or(users.first())
+        .and_then(|user| user.id.as_str())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Request, StatusCode},
+    };
+    use pgp::{composed::SignedSecretKey, types::KeyDetails};
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        SharePermission,
+        config::Config,
+        create_document, share_document,
+        test_util::{TestClient, generate_key, get, post, register, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_shared_docs_include_owner() {
+        let (_app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let recipient = generate_key("recipient <recipient@example.com>");
+        register(&pool, &owner).await;
+        register(&pool, &recipient).await;
+
+        let doc_id = create_document(&pool, &owner.key_id(), "notes", None).await;
+        create_document(&pool, &owner.key_id(), "private", None).await;
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &recipient.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+
+        let shared = get_shared_docs(&pool, &recipient.key_id(), None, 10, false)
+            .await
+            .unwrap();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].doc_id, doc_id);
+        assert_eq!(shared[0].name, "notes");
+        assert_eq!(shared[0].owner_key_id, key_id_to_text(&owner.key_id()));
+        assert_eq!(shared[0].owner_email.as_deref(), Some("owner@example.com"));
+
+        // accounts from before addresses were stored have theirs read from
+        // the key, and a key that can't be read doesn't sink the listing
+        let owner_uid = key_id_to_text(&owner.key_id());
+        sqlx::query("update users set email = null where uid = ?")
+            .bind(&owner_uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let shared = get_shared_docs(&pool, &recipient.key_id(), None, 10, true)
+            .await
+            .unwrap();
+        assert_eq!(shared[0].owner_email.as_deref(), Some("owner@example.com"));
+        assert!(shared[0].owner_key.is_some());
+        sqlx::query("update users set public_key = 'not a key' where uid = ?")
+            .bind(&owner_uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let shared = get_shared_docs(&pool, &recipient.key_id(), None, 10, true)
+            .await
+            .unwrap();
+        assert_eq!(shared[0].doc_id, doc_id);
+        assert_eq!(shared[0].owner_email, None);
+        // the key's fingerprint and algorithm are stored, not read from it
+        let stored = shared[0].owner_key.as_ref().unwrap();
+        assert_eq!(stored.fingerprint, owner.fingerprint().to_string());
+
+        assert!(
+            get_shared_docs(&pool, &owner.key_id(), None, 10, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_listing_includes_owner_key_on_request() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        let recipient = TestClient::new(&app, "recipient <recipient@example.com>");
+        owner.create_account().await;
+        recipient.create_account().await;
+        let doc_id = owner.create_shared_document("notes", &[&recipient]).await;
+
+        let shared = recipient.post_json("/documents/shared", json!({})).await;
+        assert_eq!(shared[0]["doc_id"], json!(doc_id));
+        assert!(shared[0].get("owner_key").is_none());
+        let owned = owner.list_documents().await;
+        assert_eq!(owned[0]["doc_id"], json!(doc_id));
+        assert!(owned[0].get("owner_key").is_none());
+
+        let shared = recipient
+            .post_json("/documents/shared", json!({ "include_owner_key": true }))
+            .await;
+        let public_key = owner.key.signed_public_key();
+        assert_eq!(
+            shared[0]["owner_key"],
+            json!({
+                "fingerprint": public_key.fingerprint().to_string(),
+                // EdDSA, as `generate_key` makes
+                "algorithm": 22,
+            })
+        );
+
+        // accounts from before algorithms were stored get theirs at startup
+        sqlx::query("update users set key_algorithm = null")
+            .execute(&pool)
+            .await
+            .unwrap();
+        crate::init_db(&pool).await.unwrap();
+        let shared = recipient
+            .post_json("/documents/shared", json!({ "include_owner_key": true }))
+            .await;
+        assert_eq!(shared[0]["owner_key"]["algorithm"], 22);
+    }
+
+    #[tokio::test]
+    async fn test_document_listing_is_stably_ordered() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        register(&pool, &owner).await;
+        for name in ["b", "c", "a", "b"] {
+            create_document(&pool, &owner.key_id(), name, None).await;
+        }
+
+        let list = || post(&app, "/documents", sign_json(&owner, json!({})));
+        let (status, first) = list().await;
+        assert_eq!(status, StatusCode::OK);
+        for _ in 0..3 {
+            assert_eq!(list().await.1, first);
+        }
+
+        let docs: Vec<Value> = serde_json::from_str(&first).unwrap();
+        assert_eq!(docs.len(), 4);
+        let doc_ids: Vec<&str> = docs
+            .iter()
+            .map(|doc| doc["doc_id"].as_str().unwrap())
+            .collect();
+        let mut sorted = doc_ids.clone();
+        sorted.sort();
+        assert_eq!(doc_ids, sorted);
+    }
+
+    fn list_uri(key_id: &KeyId, signed_by: &SignedSecretKey) -> String {
+        signed_uri("/documents", key_id, signed_by)
+    }
+
+    fn signed_uri(path: &str, key_id: &KeyId, signed_by: &SignedSecretKey) -> String {
+        let signature = hex::encode(sign_json(signed_by, json!({})));
+        format!(
+            "{path}?key_id={}&signature={signature}",
+            key_id_to_text(key_id)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_documents_shared_with_me() {
+        let config = Config {
+            require_signed_reads: false,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        let reader = TestClient::new(&app, "reader <reader@example.com>");
+        owner.create_account().await;
+        reader.create_account().await;
+        let doc_id = owner.create_shared_document("notes", &[&reader]).await;
+        owner.create_document("private").await;
+
+        let uri = signed_uri("/documents/shared", &reader.key_id(), &reader.key);
+        let (status, body) = get(&app, &uri).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let shared: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(shared.as_array().unwrap().len(), 1);
+        assert_eq!(shared[0]["doc_id"], json!(doc_id));
+        assert_eq!(shared[0]["owner_key_id"], key_id_to_text(&owner.key_id()));
+
+        // the owner's own listing is unchanged
+        let owned = owner.list_documents().await;
+        assert_eq!(owned.as_array().unwrap().len(), 2);
+        assert_eq!(reader.list_documents().await, json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_query_signed_reads_only_when_allowed() {
+        for require_signed_reads in [true, false] {
+            let config = Config {
+                require_signed_reads,
+                ..Config::default()
+            };
+            let (app, pool) = test_app(config).await;
+            let owner = generate_key("owner <owner@example.com>");
+            register(&pool, &owner).await;
+            create_document(&pool, &owner.key_id(), "notes", None).await;
+
+            let (status, _) = get(&app, &list_uri(&owner.key_id(), &owner)).await;
+            if require_signed_reads {
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+            } else {
+                assert_eq!(status, StatusCode::OK);
+            }
+
+            // signed listings work either way
+            let (status, body) = post(&app, "/documents", sign_json(&owner, json!({}))).await;
+            assert_eq!(status, StatusCode::OK);
+            let docs: Vec<Value> = serde_json::from_str(&body).unwrap();
+            assert_eq!(docs[0]["name"], "notes");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cannot_list_someone_elses_documents() {
+        let config = Config {
+            require_signed_reads: false,
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let snoop = generate_key("snoop <snoop@example.com>");
+        register(&pool, &owner).await;
+        register(&pool, &snoop).await;
+        create_document(&pool, &owner.key_id(), "private", None).await;
+
+        let (status, _) = get(&app, &list_uri(&owner.key_id(), &snoop)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let unsigned = format!("/documents?key_id={}", key_id_to_text(&owner.key_id()));
+        let (status, _) = get(&app, &unsigned).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_listings_are_capped() {
+        let config = Config {
+            max_listing_rows: 2,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let mut created = Vec::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            created.push(alice.create_document(name).await);
+        }
+        created.sort();
+
+        let mut listed = Vec::new();
+        let mut after = Value::Null;
+        let mut pages = 0;
+        loop {
+            let request = Request::post("/documents")
+                .body(Body::from(sign_json(&alice.key, json!({ "after": after }))))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let headers = response.headers().clone();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let docs: Vec<Value> = serde_json::from_slice(&body).unwrap();
+            assert!(docs.len() <= 2);
+            listed.extend(
+                docs.iter()
+                    .map(|doc| doc["doc_id"].as_str().unwrap().to_string()),
+            );
+            pages += 1;
+
+            let Some(cursor) = headers.get("x-next-cursor") else {
+                assert!(headers.get("x-truncated").is_none());
+                break;
+            };
+            assert_eq!(headers["x-truncated"], "true");
+            after = cursor.to_str().unwrap().into();
+        }
+        assert_eq!(pages, 3);
+        let created: Vec<String> = created.iter().map(Uuid::to_string).collect();
+        assert_eq!(listed, created);
+    }
+
+    #[tokio::test]
+    async fn test_listing_sort_and_limit() {
+        let config = Config {
+            max_listing_rows: 3,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let mut created = Vec::new();
+        for name in ["b", "d", "a", "c", "b"] {
+            created.push(alice.create_document(name).await);
+        }
+        // the first "b" changes last
+        assert_eq!(
+            alice.upload_content(created[0], "edit").await,
+            StatusCode::OK
+        );
+
+        let names = |docs: &Value| -> Vec<String> {
+            docs.as_array()
+                .unwrap()
+                .iter()
+                .map(|doc| doc["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let mut listed = Vec::new();
+        let mut after = Value::Null;
+        loop {
+            let request = json!({ "sort": "name", "order": "desc", "limit": 2, "after": after });
+            let docs = alice.post_json("/documents", request).await;
+            listed.extend(names(&docs));
+            let Some(last) = docs.as_array().unwrap().last() else {
+                break;
+            };
+            after = last["doc_id"].clone();
+        }
+        assert_eq!(listed, ["d", "c", "b", "b", "a"]);
+
+        let docs = alice
+            .post_json(
+                "/documents",
+                json!({ "sort": "last_updated", "order": "desc" }),
+            )
+            .await;
+        assert_eq!(docs[0]["doc_id"], json!(created[0]));
+        // asking for more than the cap gets the cap
+        let docs = alice
+            .post_json("/documents", json!({ "sort": "name", "limit": 100 }))
+            .await;
+        assert_eq!(names(&docs), ["a", "b", "b"]);
+        // or skip ahead by offset
+        let docs = alice
+            .post_json(
+                "/documents",
+                json!({ "sort": "name", "limit": 2, "offset": 2 }),
+            )
+            .await;
+        assert_eq!(names(&docs), ["b", "c"]);
+
+        let (status, _) = alice.post("/documents", json!({ "sort": "size" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_by_name() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let [alice, bob, carol] = ["alice", "bob", "carol"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        alice.create_account().await;
+        bob.create_account().await;
+        carol.create_account().await;
+        for name in [
+            "Meeting Notes",
+            "50% off",
+            "5000 off",
+            "a_b",
+            "axb",
+            "c:\\temp",
+        ] {
+            alice.create_document(name).await;
+        }
+        bob.create_shared_document("bob's notes", &[&alice]).await;
+        carol.create_document("carol's notes").await;
+
+        let search = async |q: &str| -> Vec<String> {
+            let docs = alice
+                .post_json("/documents", json!({ "q": q, "sort": "name" }))
+                .await;
+            docs.as_array()
+                .unwrap()
+                .iter()
+                .map(|doc| doc["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(search("NOTES").await, ["Meeting Notes", "bob's notes"]);
+        assert_eq!(search("50%").await, ["50% off"]);
+        assert_eq!(search("a_b").await, ["a_b"]);
+        assert_eq!(search("c:\\").await, ["c:\\temp"]);
+        assert_eq!(search("%").await, ["50% off"]);
+        // without a search, the listing is still just alice's own
+        assert_eq!(alice.list_documents().await.as_array().unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_effective_access() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+
+        let owned = create_document(&pool, &bob.key_id(), "bob's", None).await;
+        let shared = create_document(&pool, &alice.key_id(), "alice's", None).await;
+        share_document(
+            &pool,
+            &shared,
+            &alice.key_id(),
+            &bob.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+        // co-owned and also shared: ownership wins
+        let both = create_document(&pool, &alice.key_id(), "joint", None).await;
+        crate::add_owner(&pool, &both, &alice.key_id(), &bob.key_id())
+            .await
+            .unwrap();
+        share_document(
+            &pool,
+            &both,
+            &alice.key_id(),
+            &bob.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+        create_document(&pool, &alice.key_id(), "private", None).await;
+
+        let (status, body) = post(&app, "/access", sign_json(&bob, json!({}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let access: Value = serde_json::from_str(&body).unwrap();
+        let mut expected = vec![
+            json!({ "doc_id": owned, "permission": "owner" }),
+            json!({ "doc_id": shared, "permission": "read" }),
+            json!({ "doc_id": both, "permission": "owner" }),
+        ];
+        expected.sort_by_key(|entry| entry["doc_id"].as_str().unwrap().to_string());
+        assert_eq!(access, Value::Array(expected));
+    }
+}
@@ -0,0 +1,238 @@
+use axum::{Json, extract::State, http::StatusCode};
+use pgp::{
+    composed::{Deserializable, SignedPublicKey},
+    types::KeyDetails,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::Acquire;
+
+use crate::{
+    AppError, AppState, account_hook::NewAccount, audit, auth::AdminRequest, begin_write,
+    get_documents, insert_user_rows, internal_error, is_unique_violation, key_id_to_text, keys,
+};
+
+const ARMOR_HEADER: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+#[derive(Deserialize)]
+pub struct ImportAccounts {
+    /// Armored public keys, one block after another or as a single exported
+    /// keyring.
+    keys: String,
+    #[serde(default)]
+    all_or_nothing: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    imported: Vec<String>,
+    existing: Vec<String>,
+    failed: Vec<ImportFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportFailure {
+    /// Missing when the key couldn't be parsed far enough to have one.
+    key_id: Option<String>,
+    error: String,
+}
+
+/// Registers accounts for many keys at once, as an admin onboarding a team.
+/// Each key must be usable, as at `/create_account`, but needn't sign
+/// anything itself. Keys that fail are reported and the rest imported, unless
+/// `all_or_nothing` is set, in which case any failure imports nothing and the
+/// summary comes back with a 400.
+pub async fn handle_import_accounts(
+    State(state): State<AppState>,
+    AdminRequest(request): AdminRequest<ImportAccounts>,
+) -> Result<(StatusCode, Json<ImportSummary>), AppError> {
+    let now = chrono::Utc::now();
+    let mut summary = ImportSummary::default();
+    let mut accounts = Vec::new();
+    // pgp's key iterator isn't Send, so it can't be held across an await;
+    // the body limit already bounds how many keys this can be
+    let parsed: Vec<_> = parse_keys(&request.payload.keys).collect();
+    let mut tx = begin_write(&state.pool).await.map_err(internal_error)?;
+    for key in parsed {
+        let key = match key {
+            Ok(key) => key,
+            Err(error) => {
+                summary.failed.push(ImportFailure {
+                    key_id: None,
+                    error: format!("could not parse key: {error}"),
+                });
+                continue;
+            }
+        };
+        let key_id = key_id_to_text(&key.key_id());
+        if let Err(error) = keys::check_usable(&key, now) {
+            summary.failed.push(ImportFailure {
+                key_id: Some(key_id),
+                error: error.to_string(),
+            });
+            continue;
+        }
+        let exists: bool =
+            sqlx::query_scalar(r#"select exists(select 1 from users where uid = ?)"#)
+                .bind(&key_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+        if exists {
+            summary.existing.push(key_id);
+            continue;
+        }
+
+        // a savepoint, so a key that clashes halfway leaves nothing behind
+        let mut savepoint = tx.begin().await.map_err(internal_error)?;
+        match insert_user_rows(&mut savepoint, &key).await {
+            Ok(()) => {
+                savepoint.commit().await.map_err(internal_error)?;
+                accounts.push(NewAccount {
+                    key_id: key.key_id(),
+                    user_id: get_documents::primary_user_id(&key),
+                });
+                summary.imported.push(key_id);
+            }
+            Err(error) if error.downcast_ref().is_some_and(is_unique_violation) => {
+                savepoint.rollback().await.map_err(internal_error)?;
+                summary.failed.push(ImportFailure {
+                    key_id: Some(key_id),
+                    error: "a subkey is already registered to another account".to_string(),
+                });
+            }
+            Err(error) => return Err(internal_error(error).into()),
+        }
+    }
+
+    if request.payload.all_or_nothing && !summary.failed.is_empty() {
+        tx.rollback().await.map_err(internal_error)?;
+        summary.imported.clear();
+        return Ok((StatusCode::BAD_REQUEST, Json(summary)));
+    }
+    let target = format!(
+        "{} imported, {} existing, {} failed",
+        summary.imported.len(),
+        summary.existing.len(),
+        summary.failed.len()
+    );
+    audit::record(&mut *tx, &request.key_id, "import_accounts", &target, "ok")
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+    if let Some(hooks) = &state.account_hooks {
+        for account in accounts {
+            hooks.account_created(account);
+        }
+    }
+    Ok((StatusCode::OK, Json(summary)))
+}
+
+/// Each key in each armored block of `text`. A block that stops parsing
+/// partway yields its error and nothing after it, since what follows can't
+/// be trusted to line up with a key boundary; later blocks are still read.
+fn parse_keys(text: &str) -> impl Iterator<Item = pgp::errors::Result<SignedPublicKey>> + '_ {
+    let starts: Vec<usize> = text
+        .match_indices(ARMOR_HEADER)
+        .map(|(start, _)| start)
+        .collect();
+    let ends = starts.iter().skip(1).copied().chain([text.len()]);
+    let blocks: Vec<&str> = starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| &text[start..end])
+        .collect();
+    let blocks = if blocks.is_empty() {
+        vec![text]
+    } else {
+        blocks
+    };
+    blocks.into_iter().flat_map(|block| {
+        let keys: Box<dyn Iterator<Item = _>> = match SignedPublicKey::from_string_many(block) {
+            Ok((keys, _)) => keys,
+            Err(error) => Box::new(std::iter::once(Err(error))),
+        };
+        let mut failed = false;
+        keys.take_while(move |key| {
+            let keep = !failed;
+            failed |= key.is_err();
+            keep
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use pgp::types::KeyDetails;
+    use serde_json::{Value, json};
+
+    use crate::{
+        config::Config,
+        key_id_to_text,
+        test_util::{TestClient, generate_key, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_import_accounts() {
+        let admin_key = generate_key("admin <admin@example.com>");
+        let config = Config {
+            admin_key_ids: vec![admin_key.key_id()],
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let admin = TestClient {
+            app: app.clone(),
+            key: admin_key,
+        };
+        admin.create_account().await;
+        let [alice, bob, carol] = ["alice", "bob", "carol"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        alice.create_account().await;
+        let armored = |client: &TestClient| {
+            client
+                .key
+                .signed_public_key()
+                .to_armored_string(Default::default())
+                .unwrap()
+        };
+        let keys = format!(
+            "{}{}\n-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nnot a key\n",
+            armored(&alice),
+            armored(&bob)
+        );
+        let id = |client: &TestClient| key_id_to_text(&client.key_id());
+
+        let (status, body) = alice
+            .post("/accounts/import", json!({ "keys": keys }))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+
+        let (status, body) = admin
+            .post(
+                "/accounts/import",
+                json!({ "keys": keys, "all_or_nothing": true }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        let summary: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(summary["imported"], json!([]));
+        assert_eq!(summary["failed"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            bob.post("/documents", json!({})).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let keys = format!("{keys}{}", armored(&carol));
+        let (status, body) = admin
+            .post("/accounts/import", json!({ "keys": keys }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let summary: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(summary["imported"], json!([id(&bob), id(&carol)]));
+        assert_eq!(summary["existing"], json!([id(&alice)]));
+        assert_eq!(summary["failed"][0]["key_id"], Value::Null);
+        for client in [&bob, &carol] {
+            assert_eq!(client.post("/documents", json!({})).await.0, StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,809 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use pgp::{
+    composed::{Deserializable, SignedKeyDetails, SignedPublicKey, SignedPublicSubKey},
+    types::{KeyDetails, PublicKeyTrait, SignedUser, SignedUserAttribute, Tag},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::{
+    AppError,
+    auth::{SignedQuery, SignedRequest},
+    get_user_key, internal_error, key_id_from_text, key_id_to_text, parse_stored_key, resolve_user,
+};
+
+/// Subkeys that may sign requests for the account: marked for signing and
+/// properly bound to the primary key.
+pub fn signing_subkeys(key: &SignedPublicKey) -> impl Iterator<Item = &SignedPublicSubKey> {
+    key.public_subkeys.iter().filter(|subkey| {
+        subkey
+            .signatures
+            .iter()
+            .any(|signature| signature.key_flags().sign())
+            && subkey.verify(&key.primary_key).is_ok()
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RequireSigningSubkey {
+    /// A signing subkey of the account, or `None` to accept any of its
+    /// signing keys again.
+    fingerprint: Option<String>,
+}
+
+/// Restricts the signer's account to requests signed by one designated
+/// subkey. Once set, only that subkey can change or clear it.
+pub async fn handle_require_signing_subkey(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<RequireSigningSubkey>,
+) -> Result<String, AppError> {
+    let account = key_id_to_text(&request.key_id);
+    let fingerprint = request.payload.fingerprint.map(|fp| fp.to_lowercase());
+    if let Some(fingerprint) = &fingerprint {
+        let known: bool = sqlx::query_scalar(
+            r#"select exists(select 1 from user_subkeys where uid = ? and fingerprint = ?)"#,
+        )
+        .bind(&account)
+        .bind(fingerprint)
+        .fetch_one(&pool)
+        .await
+        .map_err(internal_error)?;
+        if !known {
+            return Err(AppError::Status(
+                StatusCode::BAD_REQUEST,
+                "not a signing subkey of this account".to_string(),
+            ));
+        }
+    }
+    sqlx::query(r#"update users set required_signing_subkey = ? where uid = ?"#)
+        .bind(&fingerprint)
+        .bind(&account)
+        .execute(&pool)
+        .await
+        .map_err(internal_error)?;
+    Ok("ok".to_string())
+}
+
+/// Most key ids one `/keys/batch` request may ask for.
+const MAX_KEY_BATCH: usize = 100;
+
+/// A key as the client holds it, or just its id.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CheckKey {
+    Key { key: String },
+    KeyId { key_id: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyCheck {
+    registered: bool,
+    revoked: bool,
+    expired: bool,
+    fingerprint: Option<String>,
+}
+
+/// Reports what the server has stored for a key, so clients can spot a stale
+/// local copy before encrypting to it.
+pub async fn handle_check_key(
+    State(pool): State<SqlitePool>,
+    Json(request): Json<CheckKey>,
+) -> Result<Json<KeyCheck>, AppError> {
+    let key_id = match request {
+        CheckKey::Key { key } => {
+            let (key, _) = SignedPublicKey::from_armor_single(key.as_bytes())
+                .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+            key.key_id()
+        }
+        CheckKey::KeyId { key_id } => key_id_from_text(&key_id)
+            .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?,
+    };
+
+    let check = match get_user_key(&pool, &key_id).await.map_err(internal_error)? {
+        Some(stored) => KeyCheck {
+            registered: true,
+            revoked: is_revoked(&stored),
+            expired: expires_at(&stored).is_some_and(|expiry| expiry <= Utc::now()),
+            fingerprint: Some(stored.fingerprint().to_string()),
+        },
+        None => KeyCheck {
+            registered: false,
+            revoked: false,
+            expired: false,
+            fingerprint: None,
+        },
+    };
+    Ok(Json(check))
+}
+
+#[derive(Deserialize)]
+pub struct FetchKeys {
+    key_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FetchedKeys {
+    /// Armored public keys by key id.
+    keys: BTreeMap<String, String>,
+    /// Requested ids with no registered key.
+    unknown: Vec<String>,
+}
+
+/// Fetches many public keys at once, for encrypting to every recipient of a
+/// share without a round-trip each.
+pub async fn handle_fetch_keys(
+    State(pool): State<SqlitePool>,
+    Json(request): Json<FetchKeys>,
+) -> Result<Json<FetchedKeys>, AppError> {
+    if request.key_ids.len() > MAX_KEY_BATCH {
+        return Err(AppError::Status(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_KEY_BATCH} key ids per request"),
+        ));
+    }
+    let key_ids = request
+        .key_ids
+        .iter()
+        .map(|key_id| key_id_from_text(key_id).map(|key_id| key_id_to_text(&key_id)))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    if key_ids.is_empty() {
+        return Ok(Json(FetchedKeys {
+            keys: BTreeMap::new(),
+            unknown: Vec::new(),
+        }));
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new("select uid, public_key from users where uid in (");
+    let mut separated = query.separated(", ");
+    for key_id in &key_ids {
+        separated.push_bind(key_id);
+    }
+    separated.push_unseparated(")");
+    let keys: BTreeMap<String, String> = query
+        .build_query_as::<(String, String)>()
+        .fetch_all(&pool)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .collect();
+    let mut unknown: Vec<String> = key_ids
+        .into_iter()
+        .filter(|key_id| !keys.contains_key(key_id))
+        .collect();
+    unknown.dedup();
+    Ok(Json(FetchedKeys { keys, unknown }))
+}
+
+#[derive(Deserialize)]
+pub struct ContactKeys {}
+
+/// The public keys of everyone the signer has shared a document with, as one
+/// armored keyring, for encrypting to them offline.
+pub async fn handle_contact_keys(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ContactKeys>,
+) -> Result<impl IntoResponse, AppError> {
+    contact_keyring(&pool, request).await
+}
+
+/// As `handle_contact_keys`, signed in the query string.
+pub async fn handle_get_contact_keys(
+    State(pool): State<SqlitePool>,
+    SignedQuery(request): SignedQuery<ContactKeys>,
+) -> Result<impl IntoResponse, AppError> {
+    contact_keyring(&pool, request).await
+}
+
+async fn contact_keyring(
+    pool: &SqlitePool,
+    request: SignedRequest<ContactKeys>,
+) -> Result<impl IntoResponse + use<>, AppError> {
+    let keys: Vec<String> = sqlx::query_scalar(
+        r#"select users.public_key from users
+        where users.uid != ?1 and users.uid in (
+            select document_shares.user_id from document_shares
+            join document_owners on document_owners.doc_id = document_shares.doc_id
+            where document_owners.user_id = ?1
+        )
+        order by users.uid"#,
+    )
+    .bind(key_id_to_text(&request.key_id))
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+    let keyring = keys
+        .iter()
+        .map(|key| key.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(([(header::CONTENT_TYPE, "application/pgp-keys")], keyring))
+}
+
+#[derive(Deserialize)]
+pub struct HkpLookup {
+    search: String,
+    op: String,
+}
+
+/// The HKP key lookup, as `gpg --keyserver` speaks it: `op=get` with a key id
+/// or fingerprint returns that user's armored key. Other operations, and
+/// searching by anything else, aren't supported.
+pub async fn handle_hkp_lookup(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<HkpLookup>,
+) -> Result<impl IntoResponse, AppError> {
+    if query.op != "get" {
+        return Err(AppError::Status(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("unsupported op {:?}", query.op),
+        ));
+    }
+    let key_id = resolve_user(&pool, &query.search).await?;
+    let public_key: Option<String> =
+        sqlx::query_scalar(r#"select public_key from users where uid = ?"#)
+            .bind(key_id_to_text(&key_id))
+            .fetch_optional(&pool)
+            .await
+            .map_err(internal_error)?;
+    let public_key = public_key.ok_or((StatusCode::NOT_FOUND, "user not found".to_string()))?;
+    Ok(([(header::CONTENT_TYPE, "application/pgp-keys")], public_key))
+}
+
+#[derive(Deserialize)]
+pub struct AccountKeyQuery {
+    key_id: String,
+}
+
+#[derive(Serialize)]
+struct AccountKey {
+    key_id: String,
+    fingerprint: String,
+    public_key: String,
+    /// Unknown for accounts registered before this was recorded.
+    created_at: Option<String>,
+}
+
+/// The key on file for an account, so a client can compare it with the one
+/// it thinks it registered. Armored, as `/pks/lookup` serves it, or with
+/// `Accept: application/json` as an `AccountKey` saying when the account was
+/// created as well.
+pub async fn handle_account_key(
+    State(pool): State<SqlitePool>,
+    headers: HeaderMap,
+    Query(query): Query<AccountKeyQuery>,
+) -> Result<Response, AppError> {
+    let key_id = key_id_from_text(&query.key_id)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    let row: Option<(String, Option<String>)> =
+        sqlx::query_as(r#"select public_key, created_at from users where uid = ?"#)
+            .bind(key_id_to_text(&key_id))
+            .fetch_optional(&pool)
+            .await
+            .map_err(internal_error)?;
+    let (public_key, created_at) =
+        row.ok_or((StatusCode::NOT_FOUND, "user not found".to_string()))?;
+    let key = parse_stored_key(&public_key).map_err(internal_error)?;
+    let armored = key
+        .to_armored_string(Default::default())
+        .map_err(internal_error)?;
+
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        return Ok(Json(AccountKey {
+            key_id: key_id_to_text(&key.key_id()),
+            fingerprint: key.fingerprint().to_string(),
+            public_key: armored,
+            created_at,
+        })
+        .into_response());
+    }
+    Ok(([(header::CONTENT_TYPE, "application/pgp-keys")], armored).into_response())
+}
+
+/// Whether the key carries a valid revocation signature from itself.
+pub fn is_revoked(key: &SignedPublicKey) -> bool {
+    key.details
+        .revocation_signatures
+        .iter()
+        .any(|signature| signature.verify_key(&key.primary_key).is_ok())
+}
+
+/// When the primary key stops being valid, going by its most recent
+/// self-signature. `None` means it never expires.
+pub fn expires_at(key: &SignedPublicKey) -> Option<DateTime<Utc>> {
+    let latest = key
+        .details
+        .direct_signatures
+        .iter()
+        .chain(key.details.users.iter().flat_map(|user| &user.signatures))
+        .filter(|signature| signature.created().is_some())
+        .max_by_key(|signature| signature.created())?;
+    let lifetime = latest.key_expiration_time()?;
+    if lifetime.is_zero() {
+        return None;
+    }
+    Some(*key.created_at() + *lifetime)
+}
+
+/// The key as it's stored: only what the key says about itself, so uploads
+/// of the same key with different certifications from others come out the
+/// same. Kept are the primary key; its direct and revocation signatures; each
+/// user id and user attribute the primary key certified, with only those
+/// certifications (and their revocations); and each subkey with its binding
+/// and revocation signatures from the primary key. Everything else, third
+/// party certifications above all, is dropped, as is any user id or subkey
+/// left with no signature.
+pub fn normalize(key: &SignedPublicKey) -> SignedPublicKey {
+    let primary = &key.primary_key;
+    let by_primary = |signature: &&pgp::packet::Signature| signature.verify_key(primary).is_ok();
+    let details = &key.details;
+    let users = details
+        .users
+        .iter()
+        .map(|user| {
+            let signatures = user.signatures.iter().filter(|signature| {
+                signature
+                    .verify_certification(primary, Tag::UserId, &user.id)
+                    .is_ok()
+            });
+            SignedUser::new(user.id.clone(), signatures.cloned().collect())
+        })
+        .filter(|user| !user.signatures.is_empty())
+        .collect();
+    let user_attributes = details
+        .user_attributes
+        .iter()
+        .map(|attribute| {
+            let signatures = attribute.signatures.iter().filter(|signature| {
+                signature
+                    .verify_certification(primary, Tag::UserAttribute, &attribute.attr)
+                    .is_ok()
+            });
+            SignedUserAttribute::new(attribute.attr.clone(), signatures.cloned().collect())
+        })
+        .filter(|attribute| !attribute.signatures.is_empty())
+        .collect();
+    let subkeys = key
+        .public_subkeys
+        .iter()
+        .map(|subkey| {
+            let signatures = subkey.signatures.iter().filter(|signature| {
+                signature
+                    .verify_subkey_binding(primary, &subkey.key)
+                    .is_ok()
+            });
+            SignedPublicSubKey::new(subkey.key.clone(), signatures.cloned().collect())
+        })
+        .filter(|subkey| !subkey.signatures.is_empty())
+        .collect();
+    SignedPublicKey::new(
+        primary.clone(),
+        SignedKeyDetails::new(
+            details
+                .revocation_signatures
+                .iter()
+                .filter(by_primary)
+                .cloned()
+                .collect(),
+            details
+                .direct_signatures
+                .iter()
+                .filter(by_primary)
+                .cloned()
+                .collect(),
+            users,
+            user_attributes,
+        ),
+        subkeys,
+    )
+}
+
+/// Why a key can't be registered.
+#[derive(Debug, Error)]
+pub enum UnusableKey {
+    #[error("key has no valid self-signature")]
+    NotSelfSigned,
+    #[error("key is revoked")]
+    Revoked,
+    #[error("key expired at {0}")]
+    Expired(DateTime<Utc>),
+}
+
+/// Checks that a key could be used at `now`: a user id, or the key itself,
+/// is certified by the primary key, and the key is neither revoked nor
+/// expired.
+pub fn check_usable(key: &SignedPublicKey, now: DateTime<Utc>) -> Result<(), UnusableKey> {
+    let primary = &key.primary_key;
+    let certified = key.details.users.iter().any(|user| {
+        user.signatures.iter().any(|signature| {
+            signature
+                .verify_certification(primary, Tag::UserId, &user.id)
+                .is_ok()
+        })
+    });
+    let directly_signed = key
+        .details
+        .direct_signatures
+        .iter()
+        .any(|signature| signature.verify_key(primary).is_ok());
+    if !certified && !directly_signed {
+        return Err(UnusableKey::NotSelfSigned);
+    }
+    if is_revoked(key) {
+        return Err(UnusableKey::Revoked);
+    }
+    match expires_at(key) {
+        Some(expires_at) if expires_at <= now => Err(UnusableKey::Expired(expires_at)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use chrono::SubsecRound;
+    use pgp::{
+        composed::{KeyType, MessageBuilder, SecretKeyParamsBuilder, SubkeyParamsBuilder},
+        crypto::hash::HashAlgorithm,
+        packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData},
+        ser::Serialize,
+        types::{Password, SecretKeyTrait},
+    };
+    use rand::thread_rng;
+    use serde_json::{Value, json};
+    use std::fs;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        insert_user, key_id_to_text,
+        test_util::{
+            TestClient, error_message, generate_key, get, post, register, send, sign, test_app,
+        },
+    };
+
+    async fn check(app: &axum::Router, body: Value) -> Value {
+        let request = Request::post("/keys/check")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (status, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    fn fixture(path: &str) -> SignedPublicKey {
+        let armored = fs::read(path).unwrap();
+        SignedPublicKey::from_armor_single(armored.as_slice())
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn test_check_key() {
+        let (app, pool) = test_app(Config::default()).await;
+        let valid = generate_key("valid <valid@example.com>");
+        register(&pool, &valid).await;
+        let revoked = fixture("test_revoked.asc");
+        let expired = fixture("test_expired.asc");
+        insert_user(&pool, &revoked, None).await.unwrap();
+        insert_user(&pool, &expired, None).await.unwrap();
+
+        let armored = valid
+            .signed_public_key()
+            .to_armored_string(Default::default())
+            .unwrap();
+        let result = check(&app, json!({ "key": armored })).await;
+        assert_eq!(
+            result,
+            json!({
+                "registered": true,
+                "revoked": false,
+                "expired": false,
+                "fingerprint": valid.fingerprint().to_string(),
+            })
+        );
+
+        let by_id = |key: &SignedPublicKey| json!({ "key_id": key_id_to_text(&key.key_id()) });
+        let result = check(&app, by_id(&revoked)).await;
+        assert_eq!(result["revoked"], true);
+        assert_eq!(result["expired"], false);
+        let result = check(&app, by_id(&expired)).await;
+        assert_eq!(result["revoked"], false);
+        assert_eq!(result["expired"], true);
+        assert_eq!(result["fingerprint"], expired.fingerprint().to_string());
+
+        let stranger = generate_key("stranger <stranger@example.com>");
+        let result = check(&app, by_id(&stranger.signed_public_key())).await;
+        assert_eq!(
+            result,
+            json!({ "registered": false, "revoked": false, "expired": false, "fingerprint": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contact_keyring_holds_share_partners() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let [alice, bob, carol, dave] = ["alice", "bob", "carol", "dave"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        for client in [&alice, &bob, &carol, &dave] {
+            client.create_account().await;
+        }
+        alice.create_shared_document("one", &[&bob]).await;
+        alice.create_shared_document("two", &[&bob, &carol]).await;
+        // shared with alice, not by her
+        dave.create_shared_document("three", &[&alice]).await;
+
+        let (status, keyring) = alice.post("/contacts/keys", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        // one armor block per key, as `gpg --import` takes them
+        let end = "-----END PGP PUBLIC KEY BLOCK-----";
+        let mut fingerprints: Vec<_> = keyring
+            .split_inclusive(end)
+            .map(|block| {
+                SignedPublicKey::from_string(block.trim_start())
+                    .unwrap()
+                    .0
+                    .fingerprint()
+            })
+            .collect();
+        fingerprints.sort_by_key(|fingerprint| fingerprint.to_string());
+        let mut expected = vec![bob.key.fingerprint(), carol.key.fingerprint()];
+        expected.sort_by_key(|fingerprint| fingerprint.to_string());
+        assert_eq!(fingerprints, expected);
+    }
+
+    #[test]
+    fn test_normalize_drops_third_party_certifications() {
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        let public_key = alice.signed_public_key();
+
+        let mut certified = public_key.clone();
+        let user = &mut certified.details.users[0];
+        let mut config = SignatureConfig::v4(
+            SignatureType::CertGeneric,
+            bob.primary_key.algorithm(),
+            HashAlgorithm::Sha256,
+        );
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::SignatureCreationTime(
+                Utc::now().trunc_subsecs(0),
+            ))
+            .unwrap(),
+            Subpacket::regular(SubpacketData::IssuerFingerprint(bob.fingerprint())).unwrap(),
+        ];
+        let certification = config
+            .sign_certification_third_party(
+                &bob.primary_key,
+                &Password::empty(),
+                &public_key.primary_key,
+                Tag::UserId,
+                &user.id,
+            )
+            .unwrap();
+        user.signatures.push(certification);
+        assert_ne!(certified, public_key);
+
+        let normalized = normalize(&certified);
+        assert_eq!(normalized, normalize(&public_key));
+        assert_eq!(
+            normalized.to_bytes().unwrap(),
+            normalize(&public_key).to_bytes().unwrap()
+        );
+        assert_eq!(normalized.details.users[0].signatures.len(), 1);
+        check_usable(&normalized, Utc::now()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hkp_lookup() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+
+        let key_id = format!("0x{}", key_id_to_text(&alice.key_id()).to_uppercase());
+        let fingerprint = alice.key.fingerprint().to_string();
+        for search in [key_id, fingerprint] {
+            let request = Request::get(format!("/pks/lookup?op=get&search={search}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/pgp-keys"
+            );
+            let armored = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let (key, _) = SignedPublicKey::from_armor_single(&armored[..]).unwrap();
+            assert_eq!(key.fingerprint(), alice.key.fingerprint());
+        }
+
+        let stranger = generate_key("stranger <stranger@example.com>");
+        for search in [
+            key_id_to_text(&stranger.key_id()),
+            stranger.fingerprint().to_string(),
+        ] {
+            let (status, _) = get(&app, &format!("/pks/lookup?op=get&search={search}")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+        let (status, _) = get(&app, "/pks/lookup?op=get&search=alice").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = get(&app, "/pks/lookup?op=index&search=alice").await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(error_message(&body), "unsupported op \"index\"");
+    }
+
+    #[tokio::test]
+    async fn test_account_key() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let uri = format!("/account/key?key_id={}", key_id_to_text(&alice.key_id()));
+
+        let (status, armored) = get(&app, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let (key, _) = SignedPublicKey::from_armor_single(armored.as_bytes()).unwrap();
+        assert_eq!(key, alice.key.signed_public_key());
+
+        let request = Request::get(&uri)
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let account: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(account["key_id"], key_id_to_text(&alice.key_id()));
+        assert_eq!(account["fingerprint"], alice.key.fingerprint().to_string());
+        assert_eq!(account["public_key"], armored);
+        let created_at = account["created_at"].as_str().unwrap();
+        assert!(
+            DateTime::parse_from_rfc3339(created_at).is_ok(),
+            "{created_at}"
+        );
+
+        let stranger = generate_key("stranger <stranger@example.com>");
+        let uri = format!("/account/key?key_id={}", key_id_to_text(&stranger.key_id()));
+        assert_eq!(get(&app, &uri).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(
+            get(&app, "/account/key?key_id=alice").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_required_signing_subkey() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let params = SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("alice <alice@example.com>".into())
+            .subkey(
+                SubkeyParamsBuilder::default()
+                    .key_type(KeyType::Ed25519Legacy)
+                    .can_sign(true)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let key = params
+            .generate(thread_rng())
+            .unwrap()
+            .sign(thread_rng(), &Password::empty())
+            .unwrap();
+        let alice = TestClient {
+            app: app.clone(),
+            key,
+        };
+        assert_eq!(alice.create_account().await, StatusCode::OK);
+        let subkey = &alice.key.secret_subkeys[0].key;
+        let sign_with = |key: &dyn SecretKeyTrait, uri: &'static str, mut payload: Value| {
+            payload["timestamp"] = chrono::Utc::now().timestamp().into();
+            let mut builder = MessageBuilder::from_bytes("", payload.to_string().into_bytes());
+            builder.sign(key, Password::empty(), HashAlgorithm::Sha256);
+            post(&app, uri, builder.to_vec(thread_rng()).unwrap())
+        };
+
+        // either key will do by default
+        let (status, _) = sign_with(&alice.key.primary_key, "/documents", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = sign_with(subkey, "/documents", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = alice
+            .post(
+                "/account/signing_subkey",
+                json!({ "fingerprint": alice.key.fingerprint().to_string() }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let require = json!({ "fingerprint": subkey.fingerprint().to_string() });
+        let (status, _) = alice.post("/account/signing_subkey", require).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = sign_with(&alice.key.primary_key, "/documents", json!({})).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (
+                StatusCode::UNAUTHORIZED,
+                "account requires its designated signing subkey"
+            )
+        );
+        let (status, _) = sign_with(subkey, "/documents", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        // nor can the primary key move the account to another key
+        let new_key = generate_key("alice <alice@example.com>")
+            .signed_public_key()
+            .to_bytes()
+            .unwrap();
+        let (status, body) = post(&app, "/account/rotate", sign(&alice.key, &new_key)).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (
+                StatusCode::UNAUTHORIZED,
+                "account requires its designated signing subkey"
+            )
+        );
+        let (status, _) = sign_with(
+            subkey,
+            "/account/signing_subkey",
+            json!({ "fingerprint": null }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = sign_with(&alice.key.primary_key, "/documents", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_keys_in_batch() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        let stranger = generate_key("stranger <stranger@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+
+        let key_ids = [&alice, &bob, &stranger].map(|key| key_id_to_text(&key.key_id()));
+        let fetch = |body: Value| {
+            Request::post("/keys/batch")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let (status, body) = send(&app, fetch(json!({ "key_ids": key_ids }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let result: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(result["unknown"], json!([key_ids[2]]));
+        let keys = result["keys"].as_object().unwrap();
+        assert_eq!(keys.len(), 2);
+        for (key, key_id) in [(&alice, &key_ids[0]), (&bob, &key_ids[1])] {
+            let (fetched, _) =
+                SignedPublicKey::from_armor_single(keys[key_id].as_str().unwrap().as_bytes())
+                    .unwrap();
+            assert_eq!(fetched.fingerprint(), key.fingerprint());
+        }
+
+        let too_many = vec![key_ids[0].clone(); MAX_KEY_BATCH + 1];
+        let (status, _) = send(&app, fetch(json!({ "key_ids": too_many }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
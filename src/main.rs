@@ -1,241 +1,3340 @@
+use anyhow::Context;
 use axum::{
-    Router,
+    Json, Router,
     body::{self},
-    extract::State,
+    extract::{DefaultBodyLimit, FromRef, Path, State},
     http::StatusCode,
-    routing::post,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
 };
 use pgp::{
     composed::{Deserializable, SignedPublicKey},
-    ser::Serialize,
     types::{KeyDetails, KeyId},
 };
-use sqlx::{Row, SqlitePool, sqlite::SqlitePoolOptions};
-use std::{fs::File, io};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{
+    Row, SqliteExecutor, SqlitePool,
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+};
+use std::{io, str::FromStr, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tower_http::trace::TraceLayer;
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-use crate::signature::{message_keyid, parse_message, verify_message};
+use crate::{
+    account_hook::{AccountHooks, CommandHook, NewAccount},
+    auth::{AdminRequest, SignedRequest},
+    client_ip::ClientIp,
+    config::Config,
+    error::{AppError, FieldErrors, Validate},
+    events::{DocumentEvents, EventKind},
+    metrics::Metrics,
+    rate_limit::{AccountCreations, RateLimiter},
+    shutdown::Drain,
+    signature::{
+        FreshMessage, check_fresh, fresh_message, message_keyid, parse_message,
+        verify_fresh_message,
+    },
+    storage::{SqliteStorage, Storage},
+    wkd::EmailIndex,
+};
 
+mod account_hook;
+mod audit;
+mod auth;
+mod body_limit;
+mod client_ip;
+mod config;
+mod content;
+mod cors;
+mod error;
+mod events;
+mod get_documents;
+mod import;
+mod keys;
+mod metrics;
+#[cfg(feature = "legacy-shares-migration")]
+mod migrate;
+mod nonce;
+mod rate_limit;
+mod request_log;
+mod server_key;
+mod session;
+mod shutdown;
 mod signature;
+mod storage;
+mod sweeper;
+mod sync;
+#[cfg(test)]
+mod test_util;
+mod wkd;
+
+#[derive(Clone)]
+struct AppState {
+    pool: SqlitePool,
+    storage: SqliteStorage,
+    config: Arc<Config>,
+    rate_limiter: Arc<RateLimiter>,
+    account_creations: Arc<AccountCreations>,
+    metrics: Arc<Metrics>,
+    events: DocumentEvents,
+    account_hooks: Option<AccountHooks>,
+    drain: Drain,
+}
+
+impl AppState {
+    fn new(pool: SqlitePool, config: Config) -> Self {
+        AppState {
+            storage: SqliteStorage::new(pool.clone()),
+            pool,
+            config: Arc::new(config),
+            rate_limiter: Arc::default(),
+            account_creations: Arc::default(),
+            metrics: Arc::default(),
+            events: DocumentEvents::default(),
+            account_hooks: None,
+            drain: Drain::default(),
+        }
+    }
+}
+
+impl FromRef<AppState> for SqlitePool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let pool = connect_db().await;
-    // build our application with a single route
-    let app = Router::new()
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+    let config = exit_on_error(Config::from_env());
+    if config.crypto_self_test {
+        exit_on_error(signature::self_test().context("crypto self-test failed"));
+    }
+    let pool = exit_on_error(connect_db(&config).await);
+    exit_on_error(
+        server_key::ensure_server_key(&pool)
+            .await
+            .context("loading server key"),
+    );
+    let account_hooks = config.account_hook_command.clone().map(|program| {
+        AccountHooks::spawn(
+            Arc::new(CommandHook { program }),
+            config.account_hook_retries,
+            Duration::from_secs(1),
+        )
+    });
+    let (stop_sweeper, sweeper_stopped) = oneshot::channel::<()>();
+    let sweeper = tokio::spawn(sweeper::sweep_periodically(
+        pool.clone(),
+        config.clone(),
+        async move {
+            let _ = sweeper_stopped.await;
+        },
+    ));
+    let drain_timeout = config.shutdown_drain_timeout;
+    let bind_addr = config.bind_addr.clone();
+    let mut state = AppState::new(pool.clone(), config);
+    state.account_hooks = account_hooks;
+    let drain = state.drain.clone();
+    let metrics_server = match state.config.metrics_addr.clone() {
+        Some(addr) => {
+            let listener = exit_on_error(
+                tokio::net::TcpListener::bind(&addr)
+                    .await
+                    .with_context(|| format!("Could not listen on {addr}")),
+            );
+            let metrics = Router::new()
+                .route("/metrics", get(metrics::handle_metrics))
+                .with_state(state.clone());
+            Some(tokio::spawn(axum::serve(listener, metrics).into_future()))
+        }
+        None => None,
+    };
+    let app = app(state);
+
+    let listener = exit_on_error(
+        tokio::net::TcpListener::bind(&bind_addr)
+            .await
+            .with_context(|| format!("Could not listen on {bind_addr}")),
+    );
+    shutdown::serve(
+        listener,
+        app,
+        drain,
+        shutdown::shutdown_signal(),
+        drain_timeout,
+    )
+    .await
+    .unwrap();
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    let _ = stop_sweeper.send(());
+    sweeper.await.unwrap();
+    pool.close().await;
+}
+
+fn app(state: AppState) -> Router {
+    let mut router = Router::new();
+    if state.config.metrics_addr.is_none() {
+        router = router.route("/metrics", get(metrics::handle_metrics));
+    }
+    router
         .route("/create_account", post(handle_create_account))
+        .route(
+            "/account/signing_subkey",
+            post(keys::handle_require_signing_subkey),
+        )
+        .route("/accounts/import", post(import::handle_import_accounts))
+        .route("/account/key", get(keys::handle_account_key))
+        .route("/account/delete", post(handle_delete_account))
+        .route("/account/rotate", post(handle_rotate_key))
         .route("/create_document", post(handle_create_document))
-        .with_state(pool.clone());
-
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("localhost:8000")
-        .await
-        .unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .route("/documents/create", post(handle_create_document))
+        .route("/documents/rename", post(handle_rename_document))
+        .route("/documents/delete", post(handle_delete_document))
+        .route("/documents/share", post(handle_share_document))
+        .route("/documents/unshare", post(handle_unshare_document))
+        .route("/documents/owners/add", post(handle_add_owner))
+        .route("/documents/owners/remove", post(handle_remove_owner))
+        .route("/policy", get(handle_policy))
+        .route("/capabilities", get(handle_capabilities))
+        .route("/ready", get(handle_ready))
+        .route("/health", get(handle_health))
+        .route(
+            "/documents",
+            get(get_documents::handle_get_documents).post(get_documents::handle_list_documents),
+        )
+        .route(
+            "/documents/events",
+            get(events::handle_get_document_events).post(events::handle_document_events),
+        )
+        .route(
+            "/documents/shared",
+            get(get_documents::handle_get_shared_documents)
+                .post(get_documents::handle_list_shared_documents),
+        )
+        .route("/documents/content", post(content::handle_download_content))
+        .route(
+            "/documents/content/upload_signed",
+            post(content::handle_upload_signed_content),
+        )
+        .route(
+            "/documents/content/verify",
+            post(content::handle_verify_content),
+        )
+        .route(
+            "/documents/{doc_id}/verify",
+            get(content::handle_get_verify_content),
+        )
+        .route(
+            "/documents/content/upload",
+            post(content::handle_upload_content),
+        )
+        .route(
+            "/documents/history",
+            get(content::handle_get_content_history).post(content::handle_content_history),
+        )
+        .route("/documents/revert", post(content::handle_revert_content))
+        .route(
+            "/audit",
+            get(audit::handle_get_audit).post(audit::handle_list_audit),
+        )
+        .route(
+            "/access",
+            get(get_documents::handle_get_access).post(get_documents::handle_list_access),
+        )
+        .route(
+            "/sessions",
+            get(session::handle_get_sessions).post(session::handle_list_sessions),
+        )
+        .route("/nonce", get(nonce::handle_new_nonce))
+        .route("/sessions/new", post(session::handle_new_session))
+        .route("/sessions/revoke", post(session::handle_revoke_sessions))
+        .route("/sessions/current", get(session::handle_current_session))
+        .route("/keys/check", post(keys::handle_check_key))
+        .route("/keys/batch", post(keys::handle_fetch_keys))
+        .route("/keys/lookup", get(wkd::handle_lookup_email))
+        .route("/pks/lookup", get(keys::handle_hkp_lookup))
+        .route(
+            "/.well-known/openpgpkey/{domain}/hu/{hash}",
+            get(wkd::handle_wkd_lookup),
+        )
+        .route(
+            "/contacts/keys",
+            get(keys::handle_get_contact_keys).post(keys::handle_contact_keys),
+        )
+        .route("/sync", post(sync::handle_sync))
+        .route("/server-key", get(server_key::handle_server_key))
+        .route(
+            "/admin/documents/{doc_id}/delete",
+            post(handle_admin_delete_document),
+        )
+        .route(
+            "/admin/rotate-server-key",
+            post(server_key::handle_rotate_server_key),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit::body_limit,
+        ))
+        // outermost, so limited clients are turned away before buffering
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_durations,
+        ))
+        .layer(DefaultBodyLimit::disable())
+        .fallback(error::handle_unknown_route)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            request_log::log_slow_requests,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shutdown::track_requests,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_log::make_span)
+                .on_response(request_log::on_response)
+                .on_failure(()),
+        )
+        // answers preflights before they reach rate limits or the fallback
+        .layer(cors::layer(&state.config))
+        .with_state(state)
 }
 
-async fn connect_db() -> SqlitePool {
-    // write file if not exists
-    let _file = File::create_new("data.db");
+/// Startup failures worth a message rather than a panic: bad settings, an
+/// unopenable database, a taken port.
+fn exit_on_error<T>(result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("md-pgp-server: {error:#}");
+        std::process::exit(1);
+    })
+}
 
+/// Opens the database in WAL mode, so readers don't block the writer, with a
+/// busy timeout so concurrent writers queue instead of failing with
+/// `database is locked`.
+async fn connect_db(config: &Config) -> anyhow::Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(&config.db_path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_secs(5))
+        .foreign_keys(true);
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("file:data.db")
+        .max_connections(config.max_connections)
+        .connect_with(options)
         .await
-        .unwrap();
+        .with_context(|| format!("Could not open database {}", config.db_path.display()))?;
+    init_db(&pool).await.context("Could not migrate database")?;
+    Ok(pool)
+}
 
-    // create tables if missing
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            uid TEXT PRIMARY KEY,
-            key_blob BLOB NOT NULL
-        );
-        CREATE TABLE IF NOT EXISTS documents (
-            doc_id TEXT PRIMARY KEY,
-            name TEXT,
-            user_id TEXT,
-            shared_with TEXT,
-            FOREIGN KEY (user_id) REFERENCES users(uid) 
-        );
-        "#,
+/// Schema changes from `migrations/`, in order of their numbers. A database
+/// at `user_version` N has had migrations 1 to N applied. That numbering
+/// predates the directory, so `init_db` applies them itself instead of
+/// through `Migrator::run`, which keeps its own table.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The `user_version` a database has once every migration has run.
+fn expected_schema_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+async fn init_db(pool: &SqlitePool) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    let version = schema_version(&mut *tx).await?;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|migration| migration.version > version)
+    {
+        #[cfg(feature = "legacy-shares-migration")]
+        if migration.version == migrate::DROP_SHARED_WITH {
+            migrate::migrate_legacy_shares(&mut tx).await?;
+        }
+        sqlx::query(&migration.sql).execute(&mut *tx).await?;
+    }
+    // pragmas can't take bound parameters
+    sqlx::query(&format!(
+        "pragma user_version = {}",
+        expected_schema_version()
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    armor_user_keys(pool).await?;
+    fingerprint_users(pool).await
+}
+
+/// Rewrites keys stored as binary blobs in armored form. Armor costs about a
+/// third more space, which is nothing next to being able to read the table.
+async fn armor_user_keys(pool: &SqlitePool) -> sqlx::Result<()> {
+    let rows =
+        sqlx::query(r#"select uid, public_key from users where typeof(public_key) = 'blob'"#)
+            .fetch_all(pool)
+            .await?;
+    for row in rows {
+        let uid: String = row.get("uid");
+        let bytes: Vec<u8> = row.get("public_key");
+        let armored = SignedPublicKey::from_bytes(io::Cursor::new(bytes))
+            .and_then(|key| key.to_armored_string(Default::default()));
+        match armored {
+            Ok(armored) => {
+                sqlx::query(r#"update users set public_key = ? where uid = ?"#)
+                    .bind(armored)
+                    .bind(&uid)
+                    .execute(pool)
+                    .await?;
+            }
+            Err(error) => tracing::warn!(uid, %error, "leaving unreadable key unarmored"),
+        }
+    }
+    Ok(())
+}
+
+/// Records the fingerprint and key algorithm of users registered before they
+/// were stored.
+async fn fingerprint_users(pool: &SqlitePool) -> sqlx::Result<()> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        r#"select uid, public_key from users
+            where (fingerprint is null or key_algorithm is null)
+                and typeof(public_key) = 'text'"#,
     )
-    .execute(&pool)
-    .await
-    .unwrap();
+    .fetch_all(pool)
+    .await?;
+    for (uid, public_key) in rows {
+        match parse_stored_key(&public_key) {
+            Ok(key) => {
+                sqlx::query(r#"update users set fingerprint = ?, key_algorithm = ? where uid = ?"#)
+                    .bind(key.fingerprint().to_string())
+                    .bind(u8::from(key.algorithm()))
+                    .bind(&uid)
+                    .execute(pool)
+                    .await?;
+            }
+            Err(error) => tracing::warn!(uid, %error, "leaving unreadable key without fingerprint"),
+        }
+    }
+    Ok(())
+}
+
+async fn schema_version(conn: impl SqliteExecutor<'_>) -> sqlx::Result<i64> {
+    let row = sqlx::query(r#"pragma user_version"#)
+        .fetch_one(conn)
+        .await?;
+    Ok(row.get(0))
+}
+
+/// Ready once the database answers and every migration for this build has run.
+async fn handle_ready(State(pool): State<SqlitePool>) -> (StatusCode, Json<Value>) {
+    let current = match schema_version(&pool).await {
+        Ok(version) => version,
+        Err(error) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "database": error.to_string() })),
+            );
+        }
+    };
+    let expected = expected_schema_version();
+    let status = if current >= expected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "database": "ok",
+            "schema_version": current,
+            "expected_schema_version": expected,
+            "migrations_pending": current < expected,
+        })),
+    )
+}
 
-    pool
+/// Healthy while the database answers a trivial query within
+/// `health_check_timeout`, so a wedged pool fails the check instead of
+/// hanging it.
+async fn handle_health(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let query = sqlx::query(r#"select 1"#).execute(&state.pool);
+    match tokio::time::timeout(state.config.health_check_timeout, query).await {
+        Ok(Ok(_)) => (StatusCode::OK, Json(json!({ "database": "ok" }))),
+        Ok(Err(error)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "database": error.to_string() })),
+        ),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "database": "timed out" })),
+        ),
+    }
+}
+
+/// Limits clients should know about before they hit them.
+async fn handle_policy(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({
+        "freshness_window_secs": state.config.freshness_window.as_secs(),
+        "max_shares_per_document": state.config.max_shares_per_document,
+        "default_share_permission": state.config.default_share_permission,
+        "max_listing_rows": state.config.max_listing_rows,
+    }))
+}
+
+/// Which optional features this server has turned on, so clients can adapt
+/// without guessing from error responses.
+async fn handle_capabilities(State(state): State<AppState>) -> Json<Value> {
+    let config = &state.config;
+    Json(json!({
+        "query_signed_reads": !config.require_signed_reads,
+        "admin": !config.admin_key_ids.is_empty(),
+        "account_creation": config.max_accounts_per_ip > 0,
+        "sharing": config.max_shares_per_document > 0,
+        "account_hook": config.account_hook_command.is_some(),
+        "strict_payloads": config.strict_payloads,
+        "legacy_shares_migration": cfg!(feature = "legacy-shares-migration"),
+    }))
 }
 
-fn parse_create_account(bytes: &[u8]) -> anyhow::Result<SignedPublicKey> {
+/// The body is the caller's public key, signed by that key. The key may be
+/// armored, which is what `gpg --sign key.asc` produces, and the message may
+/// be in any form `parse_message` reads. Keys that aren't self-signed, are
+/// revoked or have expired are refused with `keys::UnusableKey`. The
+/// signature must be fresh, as for signed requests, and name the submitted
+/// key as its issuer, or it's refused with `SignerMismatch`.
+fn parse_create_account(
+    bytes: &[u8],
+    freshness_window: Duration,
+) -> anyhow::Result<(SignedPublicKey, FreshMessage)> {
     let (signature, plaintext) = parse_message(bytes)?;
-    let key = SignedPublicKey::from_bytes(io::Cursor::new(plaintext.clone()))?;
-    verify_message(&signature, &key, &plaintext)?;
-    Ok(key)
+    let (key, _) = SignedPublicKey::from_reader_single(plaintext.as_slice())?;
+    if message_keyid(&signature)? != key.key_id() {
+        return Err(SignerMismatch.into());
+    }
+    let now = chrono::Utc::now();
+    let message = verify_fresh_message(&signature, &key, &plaintext, now, freshness_window)?;
+    keys::check_usable(&key, now)?;
+    Ok((key, message))
+}
+
+#[derive(Debug, Error)]
+#[error("signer does not match submitted key")]
+struct SignerMismatch;
+
+/// The current time as an RFC 3339 UTC string, as stored in timestamp columns.
+fn now_timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
 }
 
 fn key_id_to_text(key_id: &KeyId) -> String {
     hex::encode(key_id.as_ref())
 }
 
+#[derive(Clone, Debug, Error)]
+#[error("Invalid key id {0:?}. Expected 16 hex digits.")]
+struct InvalidKeyId(String);
+
+/// Accepts key ids the way people paste them: with or without a `0x` prefix,
+/// in either case, and with gpg-style spaces between groups.
 fn key_id_from_text(text: &str) -> anyhow::Result<KeyId> {
-    let bytes = hex::decode(text)?;
-    let octet = bytes.as_slice().try_into()?;
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let digits = compact
+        .strip_prefix("0x")
+        .or(compact.strip_prefix("0X"))
+        .unwrap_or(&compact);
+    let octet: [u8; 8] = hex::decode(digits.to_ascii_lowercase())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| InvalidKeyId(text.to_string()))?;
     Ok(KeyId::new(octet))
 }
 
+/// A user as a client names them: by key id, or by the full fingerprint of
+/// their primary key, which can't be confused with another key's.
+#[derive(Debug, PartialEq)]
+enum UserRef {
+    KeyId(KeyId),
+    Fingerprint(String),
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("Invalid user {0:?}. Expected a key id or a full fingerprint.")]
+struct InvalidUserRef(String);
+
+impl UserRef {
+    /// Takes the same spellings as `key_id_from_text`. 40 hex digits are a
+    /// v4 fingerprint and 64 a v6 one.
+    fn from_text(text: &str) -> anyhow::Result<Self> {
+        let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let digits = compact
+            .strip_prefix("0x")
+            .or(compact.strip_prefix("0X"))
+            .unwrap_or(&compact)
+            .to_ascii_lowercase();
+        match digits.len() {
+            40 | 64 if digits.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                Ok(UserRef::Fingerprint(digits))
+            }
+            40 | 64 => Err(InvalidUserRef(text.to_string()).into()),
+            _ => Ok(UserRef::KeyId(key_id_from_text(text)?)),
+        }
+    }
+
+    /// The key id of the user referred to. A fingerprint resolves only if it
+    /// is exactly that of a registered key; a key id is returned as is.
+    async fn resolve(&self, pool: &SqlitePool) -> sqlx::Result<Option<KeyId>> {
+        let fingerprint = match self {
+            UserRef::KeyId(key_id) => return Ok(Some(*key_id)),
+            UserRef::Fingerprint(fingerprint) => fingerprint,
+        };
+        let uid: Option<String> =
+            sqlx::query_scalar(r#"select uid from users where fingerprint = ?"#)
+                .bind(fingerprint)
+                .fetch_optional(pool)
+                .await?;
+        uid.map(|uid| key_id_from_text(&uid))
+            .transpose()
+            .map_err(|error| sqlx::Error::Decode(error.into()))
+    }
+}
+
+/// Resolves a user named in a request, as a response error if that fails.
+async fn resolve_user(pool: &SqlitePool, text: &str) -> Result<KeyId, (StatusCode, String)> {
+    let user =
+        UserRef::from_text(text).map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    user.resolve(pool)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "user not found".to_string()))
+}
+
 async fn handle_create_account(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    client_ip: ClientIp,
     body: body::Bytes,
-) -> Result<String, (StatusCode, String)> {
-    let key = match parse_create_account(&body) {
-        Ok(key) => key,
+) -> Result<String, AppError> {
+    let result = create_account(&state, client_ip, &body).await;
+    let outcome = result.as_ref().map_or_else(AppError::code, |_| "ok");
+    state
+        .metrics
+        .increment("create_account_total", &[("result", outcome)]);
+    result
+}
+
+async fn create_account(
+    state: &AppState,
+    ClientIp(ip): ClientIp,
+    body: &[u8],
+) -> Result<String, AppError> {
+    let (key, message) = match parse_create_account(body, state.config.freshness_window) {
+        Ok(parsed) => parsed,
+        Err(error) if error.is::<keys::UnusableKey>() || error.is::<SignerMismatch>() => {
+            return Err(AppError::Status(StatusCode::BAD_REQUEST, error.to_string()));
+        }
         Err(error) => {
-            return Err((
+            return Err(AppError::Status(
                 StatusCode::BAD_REQUEST,
                 format!("Error creating account:\n{error}"),
             ));
         }
     };
-    match insert_user(&pool, &key).await {
-        Ok(()) => Ok(format!("ok")),
-        Err(e) => {
-            let error_message = e.to_string();
-            if error_message.contains("UNIQUE constraint failed") {
-                Err((StatusCode::CONFLICT, "user already exists".to_string()))
-            } else {
-                Err((StatusCode::INTERNAL_SERVER_ERROR, error_message))
+    let config = &state.config;
+    if !state.account_creations.try_acquire(
+        ip,
+        config.max_accounts_per_ip,
+        config.account_creation_window,
+    ) {
+        return Err(AppError::Status(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many accounts created from this address".to_string(),
+        ));
+    }
+    let result = match state.storage.insert_user(&key, Some(&message)).await {
+        Ok(()) => {
+            if let Some(hooks) = &state.account_hooks {
+                hooks.account_created(NewAccount {
+                    key_id: key.key_id(),
+                    user_id: get_documents::primary_user_id(&key),
+                });
             }
+            Ok("ok".to_string())
         }
+        // a retried create that already went through is just a conflict
+        Err(error) if error.downcast_ref().is_some_and(is_unique_violation) => Err(
+            AppError::Status(StatusCode::CONFLICT, "user already exists".to_string()),
+        ),
+        Err(error) => match error.downcast::<nonce::NonceError>() {
+            Ok(error) => Err(error.into()),
+            Err(error) => Err(internal_error(error).into()),
+        },
+    };
+    if result.is_err() {
+        state.account_creations.release(ip);
     }
+    result
 }
 
-async fn insert_user(pool: &SqlitePool, key: &SignedPublicKey) -> anyhow::Result<()> {
+/// Whether an insert failed because the row already exists, going by the
+/// driver's error kind rather than the backend's message text.
+fn is_unique_violation(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .is_some_and(|error| matches!(error.kind(), sqlx::error::ErrorKind::UniqueViolation))
+}
+
+/// Registers `key`. `message`, the signed upload it came in, is recorded as
+/// used in the same transaction, so it's only used up once the account exists
+/// and can't bring it back after a delete.
+async fn insert_user(
+    pool: &SqlitePool,
+    key: &SignedPublicKey,
+    message: Option<&FreshMessage>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    insert_user_rows(&mut tx, key).await?;
+    if let Some(message) = message {
+        nonce::record_message(&mut *tx, message).await?;
+    }
     let key_id = key.key_id();
-    let key_blob = key.to_bytes()?;
-    sqlx::query(r#"insert into users (uid, key_blob) values (?, ?)"#)
-        .bind(key_id_to_text(&key_id))
-        .bind(key_blob)
-        .execute(pool)
-        .await?;
+    audit::record(
+        &mut *tx,
+        &key_id,
+        "create_account",
+        &key_id_to_text(&key_id),
+        "ok",
+    )
+    .await?;
+    tx.commit().await?;
     Ok(())
 }
 
-fn parse_create_document(bytes: &[u8]) -> anyhow::Result<(String, KeyId)> {
-    let (sig, plaintext) = parse_message(bytes)?;
-    let doc_name = String::from_utf8(plaintext)?;
-    Ok((doc_name, message_keyid(&sig)?))
+/// Adds the user and their signing subkeys. The key is stored as
+/// `keys::normalize` leaves it.
+async fn insert_user_rows(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &SignedPublicKey,
+) -> anyhow::Result<()> {
+    let key = &keys::normalize(key);
+    let key_id = key.key_id();
+    let armored = key.to_armored_string(Default::default())?;
+    let email = get_documents::primary_user_id(key)
+        .as_deref()
+        .and_then(EmailIndex::from_user_id);
+    sqlx::query(
+        r#"insert into users (uid, public_key, fingerprint, key_algorithm, email, email_domain,
+            wkd_hash, created_at)
+        values (?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(key_id_to_text(&key_id))
+    .bind(armored)
+    .bind(key.fingerprint().to_string())
+    .bind(u8::from(key.algorithm()))
+    .bind(email.as_ref().map(|email| &email.email))
+    .bind(email.as_ref().map(|email| &email.domain))
+    .bind(email.as_ref().map(|email| &email.wkd_hash))
+    .bind(now_timestamp())
+    .execute(&mut **tx)
+    .await?;
+    for subkey in keys::signing_subkeys(key) {
+        sqlx::query(r#"insert into user_subkeys (key_id, uid, fingerprint) values (?, ?, ?)"#)
+            .bind(key_id_to_text(&subkey.key.key_id()))
+            .bind(key_id_to_text(&key_id))
+            .bind(subkey.key.fingerprint().to_string())
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
 }
 
-async fn handle_create_document(
-    State(pool): State<SqlitePool>,
+/// The body is the replacement public key, signed by the account's current
+/// key the way a signed request would be: by its primary key or a signing
+/// subkey, or only by its designated subkey if it requires one. The new key
+/// must be usable, as at account creation, and the signature fresh and used
+/// only once.
+async fn handle_rotate_key(
+    State(state): State<AppState>,
     body: body::Bytes,
-) -> Result<String, (StatusCode, String)> {
-    let (doc_name, owner_id) = match parse_create_document(&body) {
-        Ok((doc_name, owner_id)) => (doc_name, owner_id),
-        Err(error) => {
+) -> Result<String, AppError> {
+    let (signature, plaintext) = parse_message(&body).map_err(auth::rejection)?;
+    let (old_key, signed_by) =
+        auth::authenticate_signer(&state.pool, &signature, &plaintext).await?;
+    let old_key_id = old_key.key_id();
+    let now = chrono::Utc::now();
+    let window = state.config.freshness_window;
+    check_fresh(&signature, now, window).map_err(auth::rejection)?;
+    let message =
+        fresh_message(&signature, &signed_by, &plaintext, window).map_err(auth::rejection)?;
+    let new_key = SignedPublicKey::from_reader_single(plaintext.as_slice())
+        .map_err(|error| (StatusCode::BAD_REQUEST, format!("Bad new key:\n{error}")))?
+        .0;
+    keys::check_usable(&new_key, now)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    nonce::record_message(&state.pool, &message).await?;
+
+    rotate_key(&state.pool, &old_key_id, &new_key).await?;
+    Ok("ok".to_string())
+}
+
+/// Moves an account from one key to another: the new key is registered,
+/// every ownership, share and tombstone of the old one passes to it, and the
+/// old key, its subkeys and its sessions are dropped.
+async fn rotate_key(
+    pool: &SqlitePool,
+    old_key_id: &KeyId,
+    new_key: &SignedPublicKey,
+) -> Result<(), (StatusCode, String)> {
+    let old_uid = key_id_to_text(old_key_id);
+    let new_uid = key_id_to_text(&new_key.key_id());
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+
+    match insert_user_rows(&mut tx, new_key).await {
+        Ok(()) => {}
+        Err(error) if error.downcast_ref().is_some_and(is_unique_violation) => {
             return Err((
-                StatusCode::BAD_REQUEST,
-                format!("Error creating document:\n{error}"),
+                StatusCode::CONFLICT,
+                "new key is already registered".to_string(),
             ));
         }
-    };
-    let uuid = create_document(&pool, &owner_id, &doc_name).await;
-    Ok(uuid.to_string())
+        Err(error) => return Err(internal_error(error)),
+    }
+    for query in [
+        // the account is as old as it was, whatever key it has now
+        r#"update users set created_at = (select created_at from users where uid = ?2)
+        where uid = ?1"#,
+        r#"update documents set user_id = ?1 where user_id = ?2"#,
+        r#"update document_owners set user_id = ?1 where user_id = ?2"#,
+        r#"update document_shares set user_id = ?1 where user_id = ?2"#,
+        r#"update tombstones set user_id = ?1 where user_id = ?2"#,
+    ] {
+        sqlx::query(query)
+            .bind(&new_uid)
+            .bind(&old_uid)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    }
+    for query in [
+        r#"delete from sessions where key_id = ?"#,
+        r#"delete from user_subkeys where uid = ?"#,
+        r#"delete from users where uid = ?"#,
+    ] {
+        sqlx::query(query)
+            .bind(&old_uid)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    }
+    audit::record(&mut *tx, old_key_id, "rotate_key", &new_uid, "ok")
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
 }
 
-async fn create_document(pool: &SqlitePool, owner_key_id: &KeyId, doc_name: &String) -> Uuid {
-    let id = Uuid::now_v7();
+#[derive(Deserialize)]
+struct DeleteAccount {}
+
+async fn handle_delete_account(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<DeleteAccount>,
+) -> Result<String, AppError> {
+    delete_account(&pool, &request.key_id).await?;
+    Ok("ok".to_string())
+}
+
+/// Removes an account and everything that names it. Documents only it owns
+/// are purged as if deleted; documents it co-owns pass to the remaining
+/// owners, as `remove_owner` does. Its shares, sessions, subkeys and
+/// tombstones go too.
+async fn delete_account(pool: &SqlitePool, caller: &KeyId) -> Result<(), (StatusCode, String)> {
+    let uid = key_id_to_text(caller);
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
 
-    sqlx::query(r#"insert into documents (doc_id, name, user_id) values (?, ?, ?)"#)
-        .bind(&id.to_string())
-        .bind(&doc_name)
-        .bind(key_id_to_text(owner_key_id))
-        .execute(pool)
+    let sole_owned: Vec<String> = sqlx::query_scalar(
+        r#"select doc_id from documents where user_id = ?1
+        union select doc_id from document_owners where user_id = ?1
+        except select doc_id from document_owners where user_id != ?1"#,
+    )
+    .bind(&uid)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    for doc_id in &sole_owned {
+        let doc_id = Uuid::parse_str(doc_id).map_err(internal_error)?;
+        purge_document(&mut tx, &doc_id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    for query in [
+        r#"delete from document_owners where user_id = ?"#,
+        r#"update documents set client_ref = null, user_id = (
+            select user_id from document_owners
+            where document_owners.doc_id = documents.doc_id order by rowid limit 1
+        )
+        where user_id = ?"#,
+        r#"delete from document_shares where user_id = ?"#,
+        r#"delete from tombstones where user_id = ?"#,
+        r#"delete from sessions where key_id = ?"#,
+        r#"delete from user_subkeys where uid = ?"#,
+        r#"delete from users where uid = ?"#,
+    ] {
+        sqlx::query(query)
+            .bind(&uid)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    }
+    audit::record(&mut *tx, caller, "delete_account", &uid, "ok")
         .await
-        .unwrap();
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
 
-    id
+async fn get_user_key(
+    pool: &SqlitePool,
+    key_id: &KeyId,
+) -> anyhow::Result<Option<SignedPublicKey>> {
+    let row = sqlx::query(r#"select public_key from users where uid = ?"#)
+        .bind(key_id_to_text(key_id))
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(row) => Ok(Some(parse_stored_key(row.get("public_key"))?)),
+        None => Ok(None),
+    }
 }
 
-async fn share_document(
+/// The key of the account whose primary key or registered signing subkey is
+/// `issuer`.
+async fn get_signer_key(
     pool: &SqlitePool,
-    doc_id: &Uuid,
-    owner_key_id: &KeyId,
-    user_key_id: &KeyId,
-) -> anyhow::Result<()> {
-    // get document from id
-    // check owner
-    let doc_row = sqlx::query(r#"select user_id from documents where doc_id = ?"#)
-        .bind(&doc_id.to_string())
-        .fetch_one(pool)
-        .await
-        .unwrap();
-    let owner_id_text: String = doc_row.get("user_id");
-    let owner_id = key_id_from_text(&owner_id_text)?;
-    if owner_id != *owner_key_id {
-        panic!("not owner");
+    issuer: &KeyId,
+) -> anyhow::Result<Option<SignedPublicKey>> {
+    let public_key: Option<String> = sqlx::query_scalar(
+        r#"select public_key from users
+        where uid = ?1 or uid = (select uid from user_subkeys where key_id = ?1)"#,
+    )
+    .bind(key_id_to_text(issuer))
+    .fetch_optional(pool)
+    .await?;
+    public_key.as_deref().map(parse_stored_key).transpose()
+}
+
+fn parse_stored_key(armored: &str) -> anyhow::Result<SignedPublicKey> {
+    let (key, _) = SignedPublicKey::from_armor_single(armored.as_bytes())?;
+    Ok(key)
+}
+
+#[derive(Deserialize)]
+struct CreateDocument {
+    #[serde(default)]
+    name: String,
+    /// Chosen by the client, unique per owner. Retrying a create with the
+    /// same reference returns the document made the first time.
+    client_ref: Option<String>,
+    /// Key ids or fingerprints to share the new document with. Recipients
+    /// without an account are skipped and reported back rather than failing
+    /// the create.
+    #[serde(default)]
+    share_with: Vec<String>,
+    /// What `share_with` recipients are granted. Defaults to the server's
+    /// `default_share_permission`.
+    share_permission: Option<SharePermission>,
+    /// Refuse content uploads that don't carry a detached signature from
+    /// one of the document's owners.
+    #[serde(default)]
+    require_signed_content: bool,
+}
+
+/// The document itself, as opposed to who it's shared with.
+struct NewDocument<'a> {
+    name: &'a str,
+    client_ref: Option<&'a str>,
+    require_signed_content: bool,
+}
+
+/// The response to a create that asked for `share_with`; plain creates
+/// still get back just the id.
+#[derive(Debug, Serialize)]
+struct CreatedDocument {
+    doc_id: Uuid,
+    skipped: Vec<String>,
+}
+
+async fn handle_create_document(
+    State(state): State<AppState>,
+    request: SignedRequest<CreateDocument>,
+) -> Result<Response, AppError> {
+    let payload = request.payload;
+    payload.validate().map_err(AppError::BadRequest)?;
+    let document = NewDocument {
+        name: &payload.name,
+        client_ref: payload.client_ref.as_deref(),
+        require_signed_content: payload.require_signed_content,
+    };
+    if payload.share_with.is_empty() {
+        let (uuid, _) = state
+            .storage
+            .create_document(
+                &request.key_id,
+                document,
+                &[],
+                SharePermission::Read,
+                0,
+                state.config.max_documents_per_user,
+            )
+            .await
+            .map_err(creation_error)?;
+        state.metrics.increment("documents_created_total", &[]);
+        state.events.publish(uuid, EventKind::Created);
+        return Ok(uuid.to_string().into_response());
     }
-    // check new user in users table
-    let users_row = sqlx::query(r#"select uid from users where uid = ?"#)
-        .bind(key_id_to_text(user_key_id))
-        .fetch_one(pool)
+
+    // fingerprints of no registered key are skipped like unknown key ids
+    let mut share_with = Vec::new();
+    let mut unknown = Vec::new();
+    for text in &payload.share_with {
+        let user = UserRef::from_text(text)
+            .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+        match user.resolve(&state.pool).await.map_err(internal_error)? {
+            Some(key_id) => share_with.push(key_id),
+            None => unknown.push(text.clone()),
+        }
+    }
+    let (doc_id, skipped) = state
+        .storage
+        .create_document(
+            &request.key_id,
+            document,
+            &share_with,
+            payload
+                .share_permission
+                .unwrap_or(state.config.default_share_permission),
+            state.config.max_shares_per_document,
+            state.config.max_documents_per_user,
+        )
         .await
-        .unwrap();
+        .map_err(creation_error)?;
+    state.metrics.increment("documents_created_total", &[]);
+    state.events.publish(doc_id, EventKind::Created);
+    Ok(Json(CreatedDocument {
+        doc_id,
+        skipped: unknown
+            .into_iter()
+            .chain(skipped.iter().map(key_id_to_text))
+            .collect(),
+    })
+    .into_response())
+}
 
-    let users = key_id_from_text(&users_row.get::<String, _>("uid"))?;
-    if users != *user_key_id {
-        panic!("user does not exist");
+/// Limits hit while creating a document are the caller's to fix; anything
+/// else is ours.
+fn creation_error(error: anyhow::Error) -> (StatusCode, String) {
+    if let Some(limit) = error.downcast_ref::<ShareLimitReached>() {
+        (StatusCode::FORBIDDEN, limit.to_string())
+    } else if let Some(quota) = error.downcast_ref::<DocumentQuotaReached>() {
+        (StatusCode::FORBIDDEN, quota.to_string())
+    } else {
+        internal_error(error)
     }
+}
 
-    // parse shared ids to vec
-    let mut shared_ids = [].to_vec();
-    let shared_row = sqlx::query(r#"select shared_with from documents where doc_id = ?"#)
-        .bind(&doc_id.to_string())
-        .fetch_one(pool)
-        .await
-        .unwrap();
-    let shared_with: String = shared_row.get("shared_with");
-    if shared_with.len() > 0 {
-        for id in shared_with.split(",") {
-            shared_ids.push(id.to_string());
+/// A plain, unshared document, for tests that just need one to exist.
+#[cfg(test)]
+async fn create_document(
+    pool: &SqlitePool,
+    owner_key_id: &KeyId,
+    doc_name: &str,
+    client_ref: Option<&str>,
+) -> Uuid {
+    let document = NewDocument {
+        name: doc_name,
+        client_ref,
+        require_signed_content: false,
+    };
+    let (id, _) = create_shared_document(
+        pool,
+        owner_key_id,
+        document,
+        &[],
+        SharePermission::Read,
+        0,
+        u32::MAX,
+    )
+    .await
+    .unwrap();
+    id
+}
+
+/// Creates a document and shares it in one transaction, so a failed share
+/// never leaves a half-shared document behind. Returns the document's id and
+/// the recipients skipped for not having an account.
+async fn create_shared_document(
+    pool: &SqlitePool,
+    owner_key_id: &KeyId,
+    document: NewDocument<'_>,
+    share_with: &[KeyId],
+    permission: SharePermission,
+    max_shares: u32,
+    max_documents: u32,
+) -> anyhow::Result<(Uuid, Vec<KeyId>)> {
+    let id = Uuid::now_v7();
+    let client_ref = document.client_ref;
+    let mut tx = begin_write(pool).await?;
+
+    let inserted = sqlx::query(
+        r#"insert into documents (doc_id, name, user_id, client_ref, require_signed_content,
+            created_at, last_updated, last_modified_by)
+        values (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?3)
+        on conflict (user_id, client_ref) do nothing"#,
+    )
+    .bind(id.to_string())
+    .bind(document.name)
+    .bind(key_id_to_text(owner_key_id))
+    .bind(client_ref)
+    .bind(document.require_signed_content)
+    .bind(now_timestamp())
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if inserted > 0 {
+        sqlx::query(r#"insert into document_owners (doc_id, user_id) values (?, ?)"#)
+            .bind(id.to_string())
+            .bind(key_id_to_text(owner_key_id))
+            .execute(&mut *tx)
+            .await?;
+        // counted under the write lock, so concurrent creates can't both fit
+        let owned: i64 =
+            sqlx::query_scalar(r#"select count(*) from document_owners where user_id = ?"#)
+                .bind(key_id_to_text(owner_key_id))
+                .fetch_one(&mut *tx)
+                .await?;
+        if owned > i64::from(max_documents) {
+            return Err(DocumentQuotaReached(max_documents).into());
         }
+        audit::record(
+            &mut *tx,
+            owner_key_id,
+            "create_document",
+            &id.to_string(),
+            "ok",
+        )
+        .await?;
     }
+    // a retried create gets the document made the first time
+    let doc_id: String = sqlx::query_scalar(
+        r#"select doc_id from documents where doc_id = ? or (user_id = ? and client_ref = ?)"#,
+    )
+    .bind(id.to_string())
+    .bind(key_id_to_text(owner_key_id))
+    .bind(client_ref)
+    .fetch_one(&mut *tx)
+    .await?;
 
-    // add to vec
-    shared_ids.push(user_key_id.to_string());
+    let mut skipped = Vec::new();
+    let mut added = 0;
+    for recipient in share_with {
+        let registered: bool =
+            sqlx::query_scalar(r#"select exists(select 1 from users where uid = ?)"#)
+                .bind(key_id_to_text(recipient))
+                .fetch_one(&mut *tx)
+                .await?;
+        if !registered {
+            skipped.push(*recipient);
+            continue;
+        }
+        let shared = sqlx::query(
+            r#"insert or ignore into document_shares (doc_id, user_id, permission)
+            values (?, ?, ?)"#,
+        )
+        .bind(&doc_id)
+        .bind(key_id_to_text(recipient))
+        .bind(permission.as_str())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if shared > 0 {
+            let target = format!("{doc_id} {}", key_id_to_text(recipient));
+            audit::record(&mut *tx, owner_key_id, "share_document", &target, "ok").await?;
+        }
+        added += shared;
+    }
+    // a retry of a document shared since shares nothing new, so it's only
+    // held to the limit when it adds to it
+    if added > 0 {
+        let shares: i64 =
+            sqlx::query_scalar(r#"select count(*) from document_shares where doc_id = ?"#)
+                .bind(&doc_id)
+                .fetch_one(&mut *tx)
+                .await?;
+        if shares > i64::from(max_shares) {
+            return Err(ShareLimitReached(max_shares).into());
+        }
+    }
 
-    // iter fold back to string
-    let shared_with_str = shared_ids.iter().fold(String::new(), |acc, x| {
-        if acc.len() == 0 {
-            x.to_string()
-        } else {
-            format!("{},{}", acc, x)
+    tx.commit().await?;
+    Ok((Uuid::parse_str(&doc_id)?, skipped))
+}
+
+/// Begins a transaction holding the write lock from the start, as `BEGIN
+/// IMMEDIATE` would. sqlx's deferred transactions read under a snapshot first,
+/// and upgrading to a write fails at once with `database is locked` if
+/// another writer committed in between; taking the lock up front makes
+/// concurrent writers wait out `busy_timeout` instead.
+async fn begin_write(pool: &SqlitePool) -> sqlx::Result<sqlx::Transaction<'static, sqlx::Sqlite>> {
+    let mut tx = pool.begin().await?;
+    // writes nothing, but a write statement takes the lock
+    sqlx::query(r#"delete from nonces where 0"#)
+        .execute(&mut *tx)
+        .await?;
+    Ok(tx)
+}
+
+/// Records that `by` just changed the document. `last_updated` only moves
+/// forward, so a clock stepping back can't reorder a listing.
+async fn touch_document(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    by: &KeyId,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"update documents set last_updated = max(coalesce(last_updated, ''), ?),
+            last_modified_by = ?
+        where doc_id = ?"#,
+    )
+    .bind(now_timestamp())
+    .bind(key_id_to_text(by))
+    .bind(doc_id.to_string())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Whether `key_id` is one of the document's owners, or `None` if there is
+/// no such document.
+async fn owner_status(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    key_id: &KeyId,
+) -> sqlx::Result<Option<bool>> {
+    let row = sqlx::query(
+        r#"select exists(
+            select 1 from document_owners where doc_id = ? and user_id = ?
+        ) as is_owner
+        from documents where doc_id = ?"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(key_id))
+    .bind(doc_id.to_string())
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|row| row.get("is_owner")))
+}
+
+const MAX_DOCUMENT_NAME_LEN: usize = 256;
+
+fn validate_document_name(name: &str, errors: &mut FieldErrors) {
+    if name.trim().is_empty() {
+        errors.insert("name", "must not be empty".to_string());
+    } else if name.chars().count() > MAX_DOCUMENT_NAME_LEN {
+        errors.insert(
+            "name",
+            format!("must be at most {MAX_DOCUMENT_NAME_LEN} characters"),
+        );
+    }
+}
+
+/// Turns collected field errors into a validation result.
+fn field_errors(errors: FieldErrors) -> Result<(), FieldErrors> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+impl Validate for CreateDocument {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::new();
+        validate_document_name(&self.name, &mut errors);
+        if let Some(bad) = self
+            .share_with
+            .iter()
+            .find(|user| UserRef::from_text(user).is_err())
+        {
+            errors.insert(
+                "share_with",
+                format!("{bad:?} is not a key id or fingerprint"),
+            );
         }
-    });
+        field_errors(errors)
+    }
+}
+
+impl Validate for RenameDocument {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::new();
+        validate_document_name(&self.name, &mut errors);
+        field_errors(errors)
+    }
+}
+
+#[derive(Deserialize)]
+struct RenameDocument {
+    doc_id: Uuid,
+    #[serde(default)]
+    name: String,
+}
+
+async fn handle_rename_document(
+    State(pool): State<SqlitePool>,
+    State(events): State<DocumentEvents>,
+    request: SignedRequest<RenameDocument>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    payload.validate().map_err(AppError::BadRequest)?;
+    rename_document(&pool, &payload.doc_id, &request.key_id, &payload.name).await?;
+    events.publish(payload.doc_id, EventKind::Renamed);
+    Ok("ok".to_string())
+}
+
+/// Logs the detail of a server-side failure and answers with a generic 500,
+/// so database and crypto errors never reach clients.
+fn internal_error(error: impl std::fmt::Display) -> (StatusCode, String) {
+    tracing::error!(%error, "internal error");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal error".to_string(),
+    )
+}
 
-    // update document
-    sqlx::query(r#"update documents set shared_with = ? where doc_id = ?"#)
-        .bind(&shared_with_str)
-        .bind(&doc_id.to_string())
-        .execute(pool)
+async fn rename_document(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    caller: &KeyId,
+    name: &str,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+
+    if require_reader(&mut *tx, doc_id, caller).await? != Access::Own {
+        audit::record(
+            &mut *tx,
+            caller,
+            "rename_document",
+            &doc_id.to_string(),
+            "forbidden",
+        )
         .await
-        .unwrap();
+        .map_err(internal_error)?;
+        tx.commit().await.map_err(internal_error)?;
+        return Err((
+            StatusCode::FORBIDDEN,
+            "only an owner can rename a document".to_string(),
+        ));
+    }
 
-    Ok(())
+    sqlx::query(r#"update documents set name = ? where doc_id = ?"#)
+        .bind(name)
+        .bind(doc_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+    touch_document(&mut *tx, doc_id, caller)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &mut *tx,
+        caller,
+        "rename_document",
+        &doc_id.to_string(),
+        "ok",
+    )
+    .await
+    .map_err(internal_error)?;
+
+    tx.commit().await.map_err(internal_error)
 }
 
-async fn get_user_docs(pool: &SqlitePool, key_id: &KeyId) -> Result<Vec<Uuid>, sqlx::Error> {
-    let mut doc_ids = [].to_vec();
-    let rows = sqlx::query(r#"select doc_id from documents where user_id = ?"#)
-        .bind(&key_id_to_text(key_id))
-        .fetch_all(pool)
-        .await?;
+#[derive(Deserialize)]
+struct DeleteDocument {
+    doc_id: Uuid,
+}
 
-    for row in rows {
-        let doc_id: String = row.get("doc_id");
-        doc_ids.push(Uuid::parse_str(&doc_id).unwrap());
+async fn handle_delete_document(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<DeleteDocument>,
+) -> Result<String, AppError> {
+    delete_document(&pool, &request.payload.doc_id, &request.key_id).await?;
+    Ok("ok".to_string())
+}
+
+async fn delete_document(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+    require_owner(&mut *tx, doc_id, caller).await?;
+
+    purge_document(&mut tx, doc_id)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &mut *tx,
+        caller,
+        "delete_document",
+        &doc_id.to_string(),
+        "ok",
+    )
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
+
+/// Removes a document and everything hanging off it, leaving tombstones for
+/// `/sync`.
+async fn purge_document(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    doc_id: &Uuid,
+) -> sqlx::Result<()> {
+    sync::record_tombstones(&mut **tx, doc_id).await?;
+    for query in [
+        r#"delete from document_shares where doc_id = ?"#,
+        r#"delete from document_owners where doc_id = ?"#,
+        r#"delete from document_versions where doc_id = ?"#,
+        r#"delete from documents where doc_id = ?"#,
+    ] {
+        sqlx::query(query)
+            .bind(doc_id.to_string())
+            .execute(&mut **tx)
+            .await?;
     }
+    Ok(())
+}
 
-    Ok(doc_ids)
+#[derive(Deserialize)]
+struct ForceDeleteDocument {
+    reason: String,
+}
+
+/// Deletes any document, whoever owns it, for abuse handling.
+async fn handle_admin_delete_document(
+    State(pool): State<SqlitePool>,
+    Path(doc_id): Path<Uuid>,
+    AdminRequest(request): AdminRequest<ForceDeleteDocument>,
+) -> Result<String, AppError> {
+    let reason = request.payload.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::Status(
+            StatusCode::BAD_REQUEST,
+            "reason is required".to_string(),
+        ));
+    }
+
+    let mut tx = begin_write(&pool).await.map_err(internal_error)?;
+    let exists: bool =
+        sqlx::query_scalar(r#"select exists(select 1 from documents where doc_id = ?)"#)
+            .bind(doc_id.to_string())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    if !exists {
+        return Err(document_not_found().into());
+    }
+    purge_document(&mut tx, &doc_id)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &mut *tx,
+        &request.key_id,
+        "admin_delete_document",
+        &format!("{doc_id} {reason}"),
+        "ok",
+    )
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok("ok".to_string())
+}
+
+#[derive(Deserialize)]
+struct ChangeOwner {
+    doc_id: Uuid,
+    key_id: String,
+}
+
+async fn handle_add_owner(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ChangeOwner>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    let new_owner = resolve_user(&pool, &payload.key_id).await?;
+    add_owner(&pool, &payload.doc_id, &request.key_id, &new_owner).await?;
+    Ok("ok".to_string())
+}
+
+async fn handle_remove_owner(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ChangeOwner>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    let owner = resolve_user(&pool, &payload.key_id).await?;
+    remove_owner(&pool, &payload.doc_id, &request.key_id, &owner).await?;
+    Ok("ok".to_string())
+}
+
+/// What a caller may do with a document.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+enum Access {
+    #[serde(rename = "read")]
+    Read,
+    /// Shared with `write`: may also replace the content.
+    #[serde(rename = "write")]
+    Write,
+    #[serde(rename = "owner")]
+    Own,
+}
+
+/// What a share grants its recipient. `write` lets them upload and revert
+/// content; renaming, sharing and the rest stay with the owners.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SharePermission {
+    Read,
+    Write,
+}
+
+impl SharePermission {
+    fn as_str(self) -> &'static str {
+        match self {
+            SharePermission::Read => "read",
+            SharePermission::Write => "write",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("Invalid share permission {0:?}. Expected \"read\" or \"write\".")]
+struct InvalidSharePermission(String);
+
+impl FromStr for SharePermission {
+    type Err = InvalidSharePermission;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "read" => Ok(SharePermission::Read),
+            "write" => Ok(SharePermission::Write),
+            _ => Err(InvalidSharePermission(text.to_string())),
+        }
+    }
+}
+
+/// The caller's access to a document, or `None` if it doesn't exist or
+/// the caller can't see it. The two are deliberately indistinguishable.
+async fn document_access(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> sqlx::Result<Option<Access>> {
+    let row = sqlx::query(
+        r#"select
+            exists(select 1 from document_owners where doc_id = ?1 and user_id = ?2) as is_owner,
+            (select permission from document_shares where doc_id = ?1 and user_id = ?2)
+                as share_permission"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(caller))
+    .fetch_one(conn)
+    .await?;
+    if row.get("is_owner") {
+        return Ok(Some(Access::Own));
+    }
+    Ok(row
+        .get::<Option<String>, _>("share_permission")
+        .map(|permission| match permission.parse() {
+            Ok(SharePermission::Write) => Access::Write,
+            _ => Access::Read,
+        }))
+}
+
+/// The response for a document the caller can't see, whether or not it
+/// exists, so that document ids can't be probed.
+fn document_not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "document not found".to_string())
+}
+
+/// Requires that the caller can at least read the document.
+async fn require_reader(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> Result<Access, (StatusCode, String)> {
+    document_access(conn, doc_id, caller)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(document_not_found)
+}
+
+/// Requires that the caller owns the document. Sharees already know the
+/// document exists, so they get a `403`; everyone else gets a `404`.
+async fn require_owner(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    match require_reader(conn, doc_id, caller).await? {
+        Access::Own => Ok(()),
+        Access::Read | Access::Write => Err((
+            StatusCode::FORBIDDEN,
+            "caller is not an owner of this document".to_string(),
+        )),
+    }
+}
+
+/// Requires that the caller owns the document or has it shared with
+/// `write`. Other sharees get a `403`, everyone else a `404`.
+async fn require_writer(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    caller: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    match require_reader(conn, doc_id, caller).await? {
+        Access::Own | Access::Write => Ok(()),
+        Access::Read => Err((
+            StatusCode::FORBIDDEN,
+            "caller may not write to this document".to_string(),
+        )),
+    }
+}
+
+async fn add_owner(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    caller: &KeyId,
+    new_owner: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+    require_owner(&mut *tx, doc_id, caller).await?;
+
+    let inserted = sqlx::query(
+        r#"insert or ignore into document_owners (doc_id, user_id)
+        select ?, uid from users where uid = ?"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(new_owner))
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?
+    .rows_affected();
+    if inserted == 0
+        && owner_status(&mut *tx, doc_id, new_owner)
+            .await
+            .map_err(internal_error)?
+            != Some(true)
+    {
+        return Err((StatusCode::NOT_FOUND, "user not found".to_string()));
+    }
+    if inserted > 0 {
+        touch_document(&mut *tx, doc_id, caller)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    let target = format!("{doc_id} {}", key_id_to_text(new_owner));
+    audit::record(&mut *tx, caller, "add_owner", &target, "ok")
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
+
+/// Removes an owner. A document always keeps at least one owner, so the last
+/// one has to add a successor before stepping down.
+async fn remove_owner(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    caller: &KeyId,
+    owner: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+    require_owner(&mut *tx, doc_id, caller).await?;
+
+    let row = sqlx::query(
+        r#"select count(*) as owners, coalesce(sum(user_id = ?), 0) as is_owner
+        from document_owners where doc_id = ?"#,
+    )
+    .bind(key_id_to_text(owner))
+    .bind(doc_id.to_string())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    if row.get::<i64, _>("is_owner") == 0 {
+        return Ok(());
+    }
+    if row.get::<i64, _>("owners") <= 1 {
+        return Err((
+            StatusCode::CONFLICT,
+            "cannot remove the last owner; add another owner first".to_string(),
+        ));
+    }
+
+    sqlx::query(r#"delete from document_owners where doc_id = ? and user_id = ?"#)
+        .bind(doc_id.to_string())
+        .bind(key_id_to_text(owner))
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+    touch_document(&mut *tx, doc_id, caller)
+        .await
+        .map_err(internal_error)?;
+    // hand primary ownership to the longest-standing remaining owner. The
+    // client reference belonged to the old primary owner's retries.
+    sqlx::query(
+        r#"update documents set client_ref = null, user_id = (
+            select user_id from document_owners where doc_id = ?1 order by rowid limit 1
+        )
+        where doc_id = ?1 and user_id = ?2"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(owner))
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+
+    let target = format!("{doc_id} {}", key_id_to_text(owner));
+    audit::record(&mut *tx, caller, "remove_owner", &target, "ok")
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("Document is already shared with the maximum of {0} users.")]
+struct ShareLimitReached(u32);
+
+#[derive(Clone, Debug, Error)]
+#[error("Account already owns the maximum of {0} documents.")]
+struct DocumentQuotaReached(u32);
+
+#[derive(Deserialize)]
+struct ShareDocument {
+    doc_id: Uuid,
+    key_id: String,
+    /// Defaults to the server's `default_share_permission`.
+    permission: Option<SharePermission>,
+}
+
+async fn handle_share_document(
+    State(state): State<AppState>,
+    request: SignedRequest<ShareDocument>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    let recipient = resolve_user(&state.pool, &payload.key_id).await?;
+    state
+        .storage
+        .share_document(
+            &payload.doc_id,
+            &request.key_id,
+            &recipient,
+            payload
+                .permission
+                .unwrap_or(state.config.default_share_permission),
+            state.config.max_shares_per_document,
+        )
+        .await?;
+    Ok("ok".to_string())
+}
+
+/// Shares a document the caller owns with another registered user. Sharing
+/// again with an existing recipient updates their permission.
+async fn share_document(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    owner_key_id: &KeyId,
+    user_key_id: &KeyId,
+    permission: SharePermission,
+    max_shares: u32,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+    require_owner(&mut *tx, doc_id, owner_key_id).await?;
+
+    let registered: bool =
+        sqlx::query_scalar(r#"select exists(select 1 from users where uid = ?)"#)
+            .bind(key_id_to_text(user_key_id))
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    if !registered {
+        return Err((StatusCode::NOT_FOUND, "user not found".to_string()));
+    }
+
+    // re-sharing with an existing recipient doesn't count against the limit
+    let counts = sqlx::query(
+        r#"select count(*) as shares, coalesce(sum(user_id = ?), 0) as existing
+        from document_shares where doc_id = ?"#,
+    )
+    .bind(key_id_to_text(user_key_id))
+    .bind(doc_id.to_string())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    let shares: i64 = counts.get("shares");
+    let existing: i64 = counts.get("existing");
+    if existing == 0 && shares >= i64::from(max_shares) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            ShareLimitReached(max_shares).to_string(),
+        ));
+    }
+
+    sqlx::query(
+        r#"insert into document_shares (doc_id, user_id, permission) values (?, ?, ?)
+        on conflict (doc_id, user_id) do update set permission = excluded.permission"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(user_key_id))
+    .bind(permission.as_str())
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    touch_document(&mut *tx, doc_id, owner_key_id)
+        .await
+        .map_err(internal_error)?;
+
+    let target = format!("{doc_id} {}", key_id_to_text(user_key_id));
+    audit::record(&mut *tx, owner_key_id, "share_document", &target, "ok")
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct UnshareDocument {
+    doc_id: Uuid,
+    key_id: String,
+}
+
+async fn handle_unshare_document(
+    State(state): State<AppState>,
+    request: SignedRequest<UnshareDocument>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    let recipient = resolve_user(&state.pool, &payload.key_id).await?;
+    state
+        .storage
+        .unshare_document(&payload.doc_id, &request.key_id, &recipient)
+        .await?;
+    Ok("ok".to_string())
+}
+
+/// Revokes a share. Unsharing someone the document isn't shared with is not
+/// an error, so retries are safe.
+async fn unshare_document(
+    pool: &SqlitePool,
+    doc_id: &Uuid,
+    owner_key_id: &KeyId,
+    user_key_id: &KeyId,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = begin_write(pool).await.map_err(internal_error)?;
+    require_owner(&mut *tx, doc_id, owner_key_id).await?;
+
+    let removed = sqlx::query(r#"delete from document_shares where doc_id = ? and user_id = ?"#)
+        .bind(doc_id.to_string())
+        .bind(key_id_to_text(user_key_id))
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?
+        .rows_affected();
+    if removed > 0 {
+        sync::record_tombstone(&mut *tx, doc_id, user_key_id)
+            .await
+            .map_err(internal_error)?;
+        touch_document(&mut *tx, doc_id, owner_key_id)
+            .await
+            .map_err(internal_error)?;
+        let target = format!("{doc_id} {}", key_id_to_text(user_key_id));
+        audit::record(&mut *tx, owner_key_id, "unshare_document", &target, "ok")
+            .await
+            .map_err(internal_error)?;
+    }
+    tx.commit().await.map_err(internal_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use pgp::{
+        composed::{CleartextSignedMessage, MessageBuilder, SignedSecretKey},
+        crypto::hash::HashAlgorithm,
+        packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData},
+        ser::Serialize,
+        types::{KeyDetails, Password},
+    };
+    use rand::thread_rng;
+    use std::fs::File;
+
+    use super::*;
+    use crate::test_util::{
+        TestClient, error_message, generate_key, get, memory_pool, post, register, sign, sign_json,
+        test_app,
+    };
+
+    #[tokio::test]
+    async fn test_share_limit() {
+        let (_app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        register(&pool, &owner).await;
+        let doc_id = create_document(&pool, &owner.key_id(), "notes", None).await;
+
+        let mut recipients = Vec::new();
+        for i in 0..3 {
+            let recipient = generate_key(&format!("user{i} <user{i}@example.com>"));
+            register(&pool, &recipient).await;
+            recipients.push(recipient.key_id());
+        }
+
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &recipients[0],
+            SharePermission::Read,
+            2,
+        )
+        .await
+        .unwrap();
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &recipients[1],
+            SharePermission::Read,
+            2,
+        )
+        .await
+        .unwrap();
+        // sharing again with someone who already has access is fine
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &recipients[1],
+            SharePermission::Read,
+            2,
+        )
+        .await
+        .unwrap();
+
+        let error = share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &recipients[2],
+            SharePermission::Read,
+            2,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error,
+            (StatusCode::FORBIDDEN, ShareLimitReached(2).to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_document_quota() {
+        let config = Config {
+            max_documents_per_user: 2,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let create = async |client_ref: &str| {
+            alice
+                .post(
+                    "/create_document",
+                    json!({ "name": "notes", "client_ref": client_ref }),
+                )
+                .await
+        };
+
+        let (status, first) = create("one").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(create("two").await.0, StatusCode::OK);
+        let (status, body) = create("three").await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (
+                StatusCode::FORBIDDEN,
+                "Account already owns the maximum of 2 documents."
+            )
+        );
+        assert_eq!(alice.list_documents().await.as_array().unwrap().len(), 2);
+        // retrying a create that went through isn't a new document
+        assert_eq!(create("one").await, (StatusCode::OK, first));
+    }
+
+    #[tokio::test]
+    async fn test_share_by_fingerprint() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        let reader = TestClient::new(&app, "reader <reader@example.com>");
+        owner.create_account().await;
+        reader.create_account().await;
+        let doc_id = owner.create_document("notes").await;
+        owner.upload_content(doc_id, "shared").await;
+
+        // same 64-bit key id as the reader's key, different key
+        let fingerprint = reader.key.fingerprint().to_string();
+        let lookalike = format!(
+            "{}{}",
+            if fingerprint.starts_with('0') {
+                "1"
+            } else {
+                "0"
+            },
+            &fingerprint[1..]
+        );
+        let (status, body) = owner
+            .post(
+                "/documents/share",
+                json!({ "doc_id": doc_id, "key_id": lookalike }),
+            )
+            .await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::NOT_FOUND, "user not found")
+        );
+        assert_eq!(
+            reader.download_content(doc_id).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let (status, body) = owner
+            .post(
+                "/documents/share",
+                json!({ "doc_id": doc_id, "key_id": fingerprint.to_uppercase() }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            reader.download_content(doc_id).await,
+            (StatusCode::OK, "shared".to_string())
+        );
+
+        let created = owner
+            .post_json(
+                "/create_document",
+                json!({ "name": "more", "share_with": [fingerprint, lookalike] }),
+            )
+            .await;
+        assert_eq!(created["skipped"], json!([lookalike]));
+    }
+
+    #[tokio::test]
+    async fn test_last_updated_tracks_every_change() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        let reader = TestClient::new(&app, "reader <reader@example.com>");
+        owner.create_account().await;
+        reader.create_account().await;
+        let doc_id = owner.create_document("notes").await;
+        let times = async || {
+            let listing = owner.list_documents().await;
+            let times = |field: &str| listing[0][field].as_str().unwrap().to_string();
+            (times("created_at"), times("last_updated"))
+        };
+        let (created_at, mut last_updated) = times().await;
+        assert_eq!(created_at, last_updated);
+
+        let share = json!({ "doc_id": doc_id, "key_id": key_id_to_text(&reader.key_id()) });
+        for (uri, payload) in [
+            ("/documents/share", share.clone()),
+            ("/documents/unshare", share),
+            (
+                "/documents/content/upload",
+                json!({ "doc_id": doc_id, "content": "hello" }),
+            ),
+        ] {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let (status, body) = owner.post(uri, payload).await;
+            assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+            let (unchanged, updated) = times().await;
+            assert_eq!(unchanged, created_at);
+            assert!(updated > last_updated, "{uri}");
+            last_updated = updated;
+        }
+
+        // a clock that steps back doesn't move it backwards
+        sqlx::query("update documents set last_updated = '2999-01-01T00:00:00.000Z'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        owner.upload_content(doc_id, "again").await;
+        assert_eq!(times().await.1, "2999-01-01T00:00:00.000Z");
+    }
+
+    #[tokio::test]
+    async fn test_share_endpoint_survives_bad_input() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let [owner, reader, stranger] = ["owner", "reader", "stranger"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        owner.create_account().await;
+        reader.create_account().await;
+        stranger.create_account().await;
+        let doc_id = owner.create_document("notes").await;
+        let share =
+            |doc_id: Uuid, recipient: String| json!({ "doc_id": doc_id, "key_id": recipient });
+        let unregistered = key_id_to_text(&generate_key("nobody <nobody@example.com>").key_id());
+
+        for (client, payload, expected) in [
+            (
+                &owner,
+                share(Uuid::now_v7(), key_id_to_text(&reader.key_id())),
+                StatusCode::NOT_FOUND,
+            ),
+            (&owner, share(doc_id, unregistered), StatusCode::NOT_FOUND),
+            (
+                &owner,
+                share(doc_id, "not hex".to_string()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (&owner, json!({ "doc_id": "nope" }), StatusCode::BAD_REQUEST),
+            (
+                &stranger,
+                share(doc_id, key_id_to_text(&stranger.key_id())),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                &owner,
+                share(doc_id, key_id_to_text(&reader.key_id())),
+                StatusCode::OK,
+            ),
+            (
+                &reader,
+                share(doc_id, key_id_to_text(&stranger.key_id())),
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            let (status, body) = client.post("/documents/share", payload).await;
+            assert_eq!(status, expected, "{body}");
+        }
+        owner.upload_content(doc_id, "shared").await;
+        assert_eq!(
+            reader.download_content(doc_id).await,
+            (StatusCode::OK, "shared".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unshare_revokes_access() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        let reader = TestClient::new(&app, "reader <reader@example.com>");
+        owner.create_account().await;
+        reader.create_account().await;
+        let doc_id = owner.create_shared_document("notes", &[&reader]).await;
+        owner.upload_content(doc_id, "for your eyes").await;
+        assert_eq!(reader.download_content(doc_id).await.0, StatusCode::OK);
+
+        let unshare = json!({ "doc_id": doc_id, "key_id": key_id_to_text(&owner.key_id()) });
+        let (status, _) = reader.post("/documents/unshare", unshare).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let unshare = json!({ "doc_id": doc_id, "key_id": key_id_to_text(&reader.key_id()) });
+        for _ in 0..2 {
+            let (status, body) = owner.post("/documents/unshare", unshare.clone()).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        assert_eq!(
+            reader.download_content(doc_id).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(reader.access().await, json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_policy_advertises_limits() {
+        let config = Config {
+            max_shares_per_document: 7,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+
+        let (status, body) = get(&app, "/policy").await;
+        assert_eq!(status, StatusCode::OK);
+        let policy: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(policy["max_shares_per_document"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_signed_protocol_end_to_end() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        let bob = TestClient::new(&app, "bob <bob@example.com>");
+        assert_eq!(alice.create_account().await, StatusCode::OK);
+        assert_eq!(bob.create_account().await, StatusCode::OK);
+        assert_eq!(alice.create_account().await, StatusCode::CONFLICT);
+
+        let private = alice.create_document("private").await;
+        let shared = alice.create_shared_document("shared", &[&bob]).await;
+        assert_eq!(
+            alice.upload_content(shared, "# Shared\n").await,
+            StatusCode::OK
+        );
+        assert_eq!(alice.list_documents().await.as_array().unwrap().len(), 2);
+
+        assert_eq!(
+            bob.download_content(shared).await,
+            (StatusCode::OK, "# Shared\n".to_string())
+        );
+        assert_eq!(bob.download_content(private).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(bob.upload_content(shared, "").await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            bob.access().await,
+            json!([{ "doc_id": shared, "permission": "read" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_shares_allow_uploads() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        let bob = TestClient::new(&app, "bob <bob@example.com>");
+        alice.create_account().await;
+        bob.create_account().await;
+        let doc_id = alice.create_shared_document("shared", &[&bob]).await;
+        assert_eq!(
+            bob.upload_content(doc_id, "read").await,
+            StatusCode::FORBIDDEN
+        );
+
+        let share = json!({
+            "doc_id": doc_id,
+            "key_id": key_id_to_text(&bob.key_id()),
+            "permission": "write",
+        });
+        assert_eq!(
+            alice.post("/documents/share", share).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            bob.access().await,
+            json!([{ "doc_id": doc_id, "permission": "write" }])
+        );
+        assert_eq!(bob.upload_content(doc_id, "# Bob\n").await, StatusCode::OK);
+        assert_eq!(
+            alice.download_content(doc_id).await,
+            (StatusCode::OK, "# Bob\n".to_string())
+        );
+
+        // writing the content doesn't make bob an owner
+        let rename = json!({ "doc_id": doc_id, "name": "bob's" });
+        assert_eq!(
+            bob.post("/documents/rename", rename).await.0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_force_delete() {
+        let admin_key = generate_key("admin <admin@example.com>");
+        let config = Config {
+            admin_key_ids: vec![admin_key.key_id()],
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let admin = TestClient {
+            app: app.clone(),
+            key: admin_key,
+        };
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        let bob = TestClient::new(&app, "bob <bob@example.com>");
+        for client in [&admin, &alice, &bob] {
+            client.create_account().await;
+        }
+        let doc_id = alice.create_shared_document("spam", &[&bob]).await;
+        let uri = format!("/admin/documents/{doc_id}/delete");
+
+        let (status, _) = bob.post(&uri, json!({ "reason": "spam" })).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = admin.post(&uri, json!({ "reason": " " })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(bob.access().await.as_array().unwrap().len(), 1);
+
+        let (status, _) = admin.post(&uri, json!({ "reason": "spam" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(alice.list_documents().await, json!([]));
+        assert_eq!(bob.access().await, json!([]));
+        let (status, _) = admin.post(&uri, json!({ "reason": "spam" })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (actor, target): (String, String) = sqlx::query_as(
+            r#"select actor_key_id, target from audit_log where action = 'admin_delete_document'"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(actor, key_id_to_text(&admin.key_id()));
+        assert_eq!(target, format!("{doc_id} spam"));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_follow_config() {
+        for require_signed_reads in [true, false] {
+            let config = Config {
+                require_signed_reads,
+                ..Config::default()
+            };
+            let (app, _pool) = test_app(config).await;
+
+            let (status, body) = get(&app, "/capabilities").await;
+            assert_eq!(status, StatusCode::OK);
+            let capabilities: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(capabilities["query_signed_reads"], !require_signed_reads);
+            assert_eq!(capabilities["admin"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_reports_pending_migrations() {
+        let pool = memory_pool().await;
+        let app = app(AppState::new(pool.clone(), Config::default()));
+
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let ready: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(ready["schema_version"], 0);
+        assert_eq!(ready["expected_schema_version"], expected_schema_version());
+        assert_eq!(ready["migrations_pending"], true);
+
+        init_db(&pool).await.unwrap();
+        let (status, body) = get(&app, "/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        let ready: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(ready["migrations_pending"], false);
+    }
+
+    #[tokio::test]
+    async fn test_old_databases_upgrade_to_the_fresh_schema() {
+        // compared column by column, as the stored sql keeps its whitespace
+        let schema = async |pool: &SqlitePool| -> Vec<(String, String, String, bool, i64)> {
+            sqlx::query_as(
+                r#"select m.name, c.name, c.type, c."notnull", c.pk
+                from sqlite_master m join pragma_table_info(m.name) c
+                where m.type = 'table'
+                union all
+                select tbl_name, name, '', 0, 0 from sqlite_master where type = 'index'
+                order by 1, 2"#,
+            )
+            .fetch_all(pool)
+            .await
+            .unwrap()
+        };
+        let fresh = memory_pool().await;
+        init_db(&fresh).await.unwrap();
+
+        // the tables as the first release created them, before any migration,
+        // with shares still in the CSV column
+        let old = memory_pool().await;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS users (
+                uid TEXT PRIMARY KEY,
+                key_blob BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS documents (
+                doc_id TEXT PRIMARY KEY,
+                name TEXT,
+                user_id TEXT,
+                shared_with TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(uid)
+            );
+            INSERT INTO users (uid, key_blob) VALUES
+                ('0123456789abcdef', x''),
+                ('fedcba9876543210', x''),
+                ('00000000000000ff', x'');
+            INSERT INTO documents (doc_id, name, user_id, shared_with) VALUES
+                ('doc-1', 'shared', '0123456789abcdef', 'fedcba9876543210,00000000000000ff'),
+                ('doc-2', 'private', '0123456789abcdef', NULL);
+            "#,
+        )
+        .execute(&old)
+        .await
+        .unwrap();
+        let expected: &[(&str, &str, &str)] = if cfg!(feature = "legacy-shares-migration") {
+            &[
+                ("doc-1", "00000000000000ff", "read"),
+                ("doc-1", "fedcba9876543210", "read"),
+            ]
+        } else {
+            &[]
+        };
+        for _ in 0..2 {
+            init_db(&old).await.unwrap();
+            assert_eq!(
+                schema_version(&old).await.unwrap(),
+                expected_schema_version()
+            );
+            assert_eq!(schema(&old).await, schema(&fresh).await);
+            let shares: Vec<(String, String, String)> = sqlx::query_as(
+                r#"select doc_id, user_id, permission from document_shares
+                order by doc_id, user_id"#,
+            )
+            .fetch_all(&old)
+            .await
+            .unwrap();
+            let shares: Vec<_> = shares
+                .iter()
+                .map(|(doc, user, permission)| (doc.as_str(), user.as_str(), permission.as_str()))
+                .collect();
+            assert_eq!(shares, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_fails_when_database_is_stuck() {
+        let config = Config {
+            health_check_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let (status, body) = get(&app, "/health").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        // the test pool has a single connection
+        let held = pool.acquire().await.unwrap();
+        let (status, body) = get(&app, "/health").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let health: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(health["database"], "timed out");
+        drop(held);
+
+        pool.close().await;
+        let (status, _) = get(&app, "/health").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_create_document_under_documents() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+
+        let (status, body) = alice
+            .post("/documents/create", json!({ "name": "notes" }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let doc_id = Uuid::parse_str(&body).unwrap();
+        let docs = alice.list_documents().await;
+        assert_eq!(docs[0]["doc_id"], json!(doc_id));
+        assert_eq!(docs[0]["name"], "notes");
+
+        let mallory = TestClient::new(&app, "mallory <mallory@example.com>");
+        let (status, _) = mallory
+            .post("/documents/create", json!({ "name": "notes" }))
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_create_document_client_ref_is_idempotent() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+
+        let create = |skey| sign_json(skey, json!({ "name": "notes", "client_ref": "retry-1" }));
+        let (status, first) = post(&app, "/create_document", create(&alice)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, second) = post(&app, "/create_document", create(&alice)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first, second);
+
+        // references are only unique per owner
+        let (_, other_owner) = post(&app, "/create_document", create(&bob)).await;
+        assert_ne!(first, other_owner);
+
+        // documents without a reference are never merged
+        let plain = || sign_json(&alice, json!({ "name": "notes" }));
+        let (_, third) = post(&app, "/create_document", plain()).await;
+        let (_, fourth) = post(&app, "/create_document", plain()).await;
+        assert_ne!(third, fourth);
+
+        let row = sqlx::query(r#"select count(*) as count from documents"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<i64, _>("count"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_retrying_a_create_after_sharing() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+
+        let create = || sign_json(&alice, json!({ "name": "notes", "client_ref": "retry-1" }));
+        let (status, doc_id) = post(&app, "/create_document", create()).await;
+        assert_eq!(status, StatusCode::OK);
+        let share = json!({ "doc_id": doc_id, "key_id": key_id_to_text(&bob.key_id()) });
+        let (status, body) = post(&app, "/documents/share", sign_json(&alice, share)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, retried) = post(&app, "/create_document", create()).await;
+        assert_eq!((status, retried), (StatusCode::OK, doc_id.clone()));
+        let owner = alice.key_id();
+        let doc_id = Uuid::parse_str(&doc_id).unwrap();
+        assert_eq!(
+            create_document(&pool, &owner, "notes", Some("retry-1")).await,
+            doc_id
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_document_pre_shared() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        let carol = generate_key("carol <carol@example.com>");
+        let nobody = generate_key("nobody <nobody@example.com>");
+        for key in [&alice, &bob, &carol] {
+            register(&pool, key).await;
+        }
+
+        let share_with: Vec<String> = [&bob, &carol, &nobody]
+            .iter()
+            .map(|key| key_id_to_text(&key.key_id()))
+            .collect();
+        let create = sign_json(&alice, json!({ "name": "plans", "share_with": share_with }));
+        let (status, body) = post(&app, "/create_document", create).await;
+        assert_eq!(status, StatusCode::OK);
+        let created: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            created["skipped"],
+            json!([key_id_to_text(&nobody.key_id())])
+        );
+
+        let doc_id = Uuid::parse_str(created["doc_id"].as_str().unwrap()).unwrap();
+        for key in [&bob, &carol] {
+            let access = document_access(&pool, &doc_id, &key.key_id())
+                .await
+                .unwrap();
+            assert_eq!(access, Some(Access::Read));
+        }
+
+        // over the share limit, nothing is created
+        let config = Config {
+            max_shares_per_document: 1,
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        for key in [&alice, &bob, &carol] {
+            register(&pool, key).await;
+        }
+        let create = sign_json(&alice, json!({ "name": "plans", "share_with": share_with }));
+        let (status, _) = post(&app, "/create_document", create).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let count: i64 = sqlx::query_scalar(r#"select count(*) from documents"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_default_share_permission() {
+        let config = Config {
+            default_share_permission: SharePermission::Write,
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+
+        let (_, policy) = get(&app, "/policy").await;
+        let policy: Value = serde_json::from_str(&policy).unwrap();
+        assert_eq!(policy["default_share_permission"], "write");
+
+        let bob_id = key_id_to_text(&bob.key_id());
+        let cases = [
+            (json!({ "name": "a", "share_with": [bob_id] }), "write"),
+            (
+                json!({ "name": "b", "share_with": [bob_id], "share_permission": "read" }),
+                "read",
+            ),
+        ];
+        for (payload, expected) in cases {
+            let (status, body) = post(&app, "/create_document", sign_json(&alice, payload)).await;
+            assert_eq!(status, StatusCode::OK);
+            let created: Value = serde_json::from_str(&body).unwrap();
+            let permission: String =
+                sqlx::query_scalar(r#"select permission from document_shares where doc_id = ?"#)
+                    .bind(created["doc_id"].as_str().unwrap())
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert_eq!(permission, expected);
+        }
+    }
+
+    #[test]
+    fn test_key_id_from_text() {
+        let expected = KeyId::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        for text in [
+            "0123456789abcdef",
+            "0123456789ABCDEF",
+            "0x0123456789abcdef",
+            "0X0123456789ABCDEF",
+            "0123 4567 89AB CDEF",
+            "  0x0123456789abcdef\n",
+        ] {
+            assert_eq!(key_id_from_text(text).unwrap(), expected, "{text:?}");
+        }
+
+        for text in [
+            "",
+            "0x",
+            "0123456789abcde",
+            "0123456789abcdef01",
+            "0123456789abcdeg",
+            "0x0x0123456789abcdef",
+        ] {
+            let error = key_id_from_text(text).unwrap_err();
+            assert!(error.downcast_ref::<InvalidKeyId>().is_some(), "{text:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rename_document() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let other = generate_key("other <other@example.com>");
+        register(&pool, &owner).await;
+        register(&pool, &other).await;
+        let doc_id = create_document(&pool, &owner.key_id(), "draft", None).await;
+
+        let rename = |skey, doc_id: Uuid, name: &str| {
+            sign_json(skey, json!({ "doc_id": doc_id, "name": name }))
+        };
+
+        let (status, _) = post(&app, "/documents/rename", rename(&owner, doc_id, "final")).await;
+        assert_eq!(status, StatusCode::OK);
+        let row = sqlx::query(r#"select name, last_modified_by from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("name"), "final");
+        assert_eq!(
+            row.get::<String, _>("last_modified_by"),
+            key_id_to_text(&owner.key_id())
+        );
+
+        // strangers can't tell the document exists
+        let (status, _) = post(&app, "/documents/rename", rename(&other, doc_id, "mine")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // sharees can see it but not rename it
+        share_document(
+            &pool,
+            &doc_id,
+            &owner.key_id(),
+            &other.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+        let (status, _) = post(&app, "/documents/rename", rename(&other, doc_id, "mine")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post(&app, "/documents/rename", rename(&owner, doc_id, "  ")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let long_name = "x".repeat(MAX_DOCUMENT_NAME_LEN + 1);
+        let (status, _) = post(
+            &app,
+            "/documents/rename",
+            rename(&owner, doc_id, &long_name),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let missing = Uuid::now_v7();
+        let (status, _) = post(&app, "/documents/rename", rename(&owner, missing, "x")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let audit: Vec<String> = sqlx::query(
+            r#"select result from audit_log where action = 'rename_document' order by id"#,
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.get("result"))
+        .collect();
+        assert_eq!(audit, ["ok", "forbidden"]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_document() {
+        let (app, pool) = test_app(Config::default()).await;
+        let [owner, reader, stranger] = ["owner", "reader", "stranger"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        for client in [&owner, &reader, &stranger] {
+            client.create_account().await;
+        }
+        let doc_id = owner.create_shared_document("doomed", &[&reader]).await;
+        assert_eq!(
+            owner.upload_content(doc_id, "contents").await,
+            StatusCode::OK
+        );
+        let (status, _) = reader.download_content(doc_id).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let delete = json!({ "doc_id": doc_id });
+        let (status, _) = reader.post("/documents/delete", delete.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = stranger.post("/documents/delete", delete.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = owner.post("/documents/delete", delete.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = reader.download_content(doc_id).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let shares: i64 = sqlx::query_scalar(r#"select count(*) from document_shares"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(shares, 0);
+        let (status, _) = owner.post("/documents/delete", delete).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_delete_account() {
+        let (app, pool) = test_app(Config::default()).await;
+        let [alice, bob, carol] = ["alice", "bob", "carol"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        for client in [&alice, &bob, &carol] {
+            client.create_account().await;
+        }
+        let own = alice.create_shared_document("alice's", &[&bob]).await;
+        let joint = alice.create_document("joint").await;
+        let add_carol = json!({ "doc_id": joint, "key_id": key_id_to_text(&carol.key_id()) });
+        let (status, _) = alice.post("/documents/owners/add", add_carol).await;
+        assert_eq!(status, StatusCode::OK);
+        let bobs = bob.create_shared_document("bob's", &[&alice]).await;
+
+        let (status, _) = alice.post("/account/delete", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = bob.download_content(own).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let primary: String =
+            sqlx::query_scalar(r#"select user_id from documents where doc_id = ?"#)
+                .bind(joint.to_string())
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(primary, key_id_to_text(&carol.key_id()));
+        let (status, _) = bob.download_content(bobs).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uid = key_id_to_text(&alice.key_id());
+        for (table, column) in [
+            ("users", "uid"),
+            ("documents", "user_id"),
+            ("document_owners", "user_id"),
+            ("document_shares", "user_id"),
+            ("tombstones", "user_id"),
+            ("sessions", "key_id"),
+        ] {
+            let left: i64 =
+                sqlx::query_scalar(&format!("select count(*) from {table} where {column} = ?"))
+                    .bind(&uid)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert_eq!(left, 0, "{table}");
+        }
+        let (status, _) = alice.post("/documents", json!({})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // an account with nothing to its name goes just as well
+        let (status, _) = carol.post("/account/delete", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let dave = TestClient::new(&app, "dave <dave@example.com>");
+        dave.create_account().await;
+        let (status, _) = dave.post("/account/delete", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_keeps_documents() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let [old, bob] = ["alice", "bob"]
+            .map(|name| TestClient::new(&app, &format!("{name} <{name}@example.com>")));
+        old.create_account().await;
+        bob.create_account().await;
+        let own = old.create_shared_document("alice's", &[&bob]).await;
+        let bobs = bob.create_shared_document("bob's", &[&old]).await;
+
+        let new = TestClient::new(&app, "alice <alice@example.com>");
+        let rotate = |by: &TestClient, to: &TestClient| {
+            let public_key = to.key.signed_public_key().to_bytes().unwrap();
+            sign(&by.key, &public_key)
+        };
+        let (status, body) = post(&app, "/account/rotate", rotate(&old, &new)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let documents = new.list_documents().await;
+        assert_eq!(documents[0]["doc_id"], own.to_string());
+        assert_eq!(documents[0]["owner_key_id"], key_id_to_text(&new.key_id()));
+        assert_eq!(new.upload_content(own, "still mine").await, StatusCode::OK);
+        let (status, _) = new.download_content(bobs).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = bob.download_content(own).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "still mine"));
+
+        let (status, _) = old.post("/documents", json!({})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post(&app, "/account/rotate", rotate(&old, &bob)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // someone else's key can't be taken over
+        let (status, _) = post(&app, "/account/rotate", rotate(&new, &bob)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_document_name_validation_errors() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        register(&pool, &owner).await;
+
+        let (status, body) = post(&app, "/create_document", sign_json(&owner, json!({}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "error": "validation",
+                "code": "bad_request",
+                "fields": { "name": "must not be empty" },
+            })
+        );
+
+        let long_name = "x".repeat(MAX_DOCUMENT_NAME_LEN + 1);
+        let rename = json!({ "doc_id": Uuid::now_v7(), "name": long_name });
+        let (status, body) = post(&app, "/documents/rename", sign_json(&owner, rename)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "validation");
+        assert_eq!(
+            body["fields"]["name"],
+            format!("must be at most {MAX_DOCUMENT_NAME_LEN} characters")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_co_owners() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        let carol = generate_key("carol <carol@example.com>");
+        for skey in [&alice, &bob, &carol] {
+            register(&pool, skey).await;
+        }
+        let doc_id = create_document(&pool, &alice.key_id(), "plans", None).await;
+        let change_owner = |skey, key_id: KeyId| {
+            sign_json(
+                skey,
+                json!({ "doc_id": doc_id, "key_id": key_id_to_text(&key_id) }),
+            )
+        };
+
+        // only owners can add owners
+        let (status, _) = post(
+            &app,
+            "/documents/owners/add",
+            change_owner(&bob, bob.key_id()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = post(
+            &app,
+            "/documents/owners/add",
+            change_owner(&alice, bob.key_id()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // a co-owner can rename and share
+        let rename = sign_json(&bob, json!({ "doc_id": doc_id, "name": "bob's plans" }));
+        let (status, _) = post(&app, "/documents/rename", rename).await;
+        assert_eq!(status, StatusCode::OK);
+        share_document(
+            &pool,
+            &doc_id,
+            &bob.key_id(),
+            &carol.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+
+        // the primary owner can step down once someone else owns the doc
+        let (status, _) = post(
+            &app,
+            "/documents/owners/remove",
+            change_owner(&bob, alice.key_id()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let row = sqlx::query(r#"select user_id from documents where doc_id = ?"#)
+            .bind(doc_id.to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            row.get::<String, _>("user_id"),
+            key_id_to_text(&bob.key_id())
+        );
+
+        // but the last owner can't leave without a successor
+        let (status, _) = post(
+            &app,
+            "/documents/owners/remove",
+            change_owner(&bob, bob.key_id()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            owner_status(&pool, &doc_id, &bob.key_id()).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            owner_status(&pool, &doc_id, &alice.key_id()).await.unwrap(),
+            Some(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_documents_look_missing() {
+        let (app, pool) = test_app(Config::default()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let stranger = generate_key("stranger <stranger@example.com>");
+        register(&pool, &owner).await;
+        register(&pool, &stranger).await;
+        let private = create_document(&pool, &owner.key_id(), "private", None).await;
+        let missing = Uuid::now_v7();
+
+        let endpoints = [
+            ("/documents/rename", json!({ "name": "x" })),
+            ("/documents/delete", json!({})),
+            (
+                "/documents/owners/add",
+                json!({ "key_id": key_id_to_text(&stranger.key_id()) }),
+            ),
+            (
+                "/documents/owners/remove",
+                json!({ "key_id": key_id_to_text(&owner.key_id()) }),
+            ),
+            ("/documents/content", json!({})),
+            ("/documents/content/upload", json!({ "content": "x" })),
+        ];
+        for (uri, mut payload) in endpoints {
+            let mut responses = Vec::new();
+            for doc_id in [private, missing] {
+                payload["doc_id"] = json!(doc_id);
+                responses.push(post(&app, uri, sign_json(&stranger, payload.clone())).await);
+            }
+            assert_eq!(responses[0].0, StatusCode::NOT_FOUND, "{uri}");
+            assert_eq!(responses[0], responses[1], "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_account_from_gpg() {
+        // the fixture was signed long before any run of this test
+        let config = Config {
+            freshness_window: Duration::from_secs(100 * 365 * 24 * 60 * 60),
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+
+        // `gpg --sign test.asc`: a compressed one-pass signed message over the
+        // armored key
+        let body = std::fs::read("test_create_account.gpg").unwrap();
+        let (status, _) = post(&app, "/create_account", body).await;
+        assert_eq!(status, StatusCode::OK);
+        let (public_key, _) =
+            SignedPublicKey::from_armor_single(File::open("test.asc").unwrap()).unwrap();
+        assert!(
+            get_user_key(&pool, &public_key.key_id())
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        // binary keys still work
+        let skey = generate_key("binary <binary@example.com>");
+        let public_key = skey.signed_public_key().to_bytes().unwrap();
+        let (status, _) = post(&app, "/create_account", sign(&skey, &public_key)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // `gpg --armor --sign` and `gpg --clearsign` over an armored key
+        let armored = generate_key("armored <armored@example.com>");
+        let public_key = armored
+            .signed_public_key()
+            .to_armored_bytes(Default::default())
+            .unwrap();
+        let mut builder = MessageBuilder::from_bytes("", public_key);
+        builder.sign(
+            &armored.primary_key,
+            Password::empty(),
+            HashAlgorithm::Sha256,
+        );
+        let body = builder
+            .to_armored_string(thread_rng(), Default::default())
+            .unwrap();
+        let (status, body) = post(&app, "/create_account", body.into_bytes()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let clearsigned = generate_key("clearsigned <clearsigned@example.com>");
+        let public_key = clearsigned
+            .signed_public_key()
+            .to_armored_string(Default::default())
+            .unwrap();
+        let body = CleartextSignedMessage::sign(
+            thread_rng(),
+            &public_key,
+            &clearsigned.primary_key,
+            &Password::empty(),
+        )
+        .and_then(|message| message.to_armored_string(Default::default()))
+        .unwrap();
+        let (status, body) = post(&app, "/create_account", body.into_bytes()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        for key in [&armored, &clearsigned] {
+            assert!(get_user_key(&pool, &key.key_id()).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unusable_keys_are_refused() {
+        let (app, pool) = test_app(Config::default()).await;
+
+        // made with `gpg --quick-gen-key ... seconds=2`
+        let (expired, _) =
+            SignedSecretKey::from_armor_single(File::open("test_expired_secret.asc").unwrap())
+                .unwrap();
+        let public_key = expired.signed_public_key().to_bytes().unwrap();
+        let (status, body) = post(&app, "/create_account", sign(&expired, &public_key)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            error_message(&body).starts_with("key expired at "),
+            "{body}"
+        );
+
+        let skey = generate_key("unbound <unbound@example.com>");
+        let mut unbound = skey.signed_public_key();
+        unbound.details.users[0].signatures.clear();
+        let public_key = unbound.to_bytes().unwrap();
+        let (status, body) = post(&app, "/create_account", sign(&skey, &public_key)).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::BAD_REQUEST, "key has no valid self-signature")
+        );
+
+        for key in [expired.signed_public_key(), unbound] {
+            assert!(get_user_key(&pool, &key.key_id()).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_keys_are_refused() {
+        let (app, pool) = test_app(Config::default()).await;
+        // gpg's own revocation certificate, imported before export
+        let (revoked, _) =
+            SignedSecretKey::from_armor_single(File::open("test_revoked_secret.asc").unwrap())
+                .unwrap();
+        let armored = revoked
+            .signed_public_key()
+            .to_armored_string(Default::default())
+            .unwrap();
+        let (status, body) =
+            post(&app, "/create_account", sign(&revoked, armored.as_bytes())).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::BAD_REQUEST, "key is revoked")
+        );
+        assert!(
+            get_user_key(&pool, &revoked.key_id())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_account_signer_must_match_key() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let mallory = generate_key("mallory <mallory@example.com>");
+        let public_key = alice
+            .signed_public_key()
+            .to_armored_string(Default::default())
+            .unwrap();
+
+        // signed by alice's key, but claiming to be from mallory's
+        let mut config = SignatureConfig::v4(
+            SignatureType::Text,
+            alice.primary_key.algorithm(),
+            HashAlgorithm::Sha256,
+        );
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::SignatureCreationTime(
+                chrono::SubsecRound::trunc_subsecs(chrono::Utc::now(), 0),
+            ))
+            .unwrap(),
+        ];
+        config.unhashed_subpackets =
+            vec![Subpacket::regular(SubpacketData::Issuer(mallory.key_id())).unwrap()];
+        let body = CleartextSignedMessage::new(
+            &public_key,
+            config,
+            &alice.primary_key,
+            &Password::empty(),
+        )
+        .unwrap()
+        .to_armored_string(Default::default())
+        .unwrap();
+
+        let (status, body) = post(&app, "/create_account", body.into_bytes()).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (
+                StatusCode::BAD_REQUEST,
+                "signer does not match submitted key"
+            )
+        );
+        assert!(
+            get_user_key(&pool, &alice.key_id())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_account_messages_are_single_use() {
+        let (app, pool) = test_app(Config::default()).await;
+        let (status, body) = post(
+            &app,
+            "/create_account",
+            std::fs::read("test_create_account.gpg").unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error_message(&body).contains("freshness window"), "{body}");
+
+        let skey = generate_key("alice <alice@example.com>");
+        let create = sign(&skey, &skey.signed_public_key().to_bytes().unwrap());
+        let (status, _) = post(&app, "/create_account", create.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post(&app, "/create_account", create.clone()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // once the account is gone, the old message can't bring it back
+        sqlx::query("delete from user_subkeys; delete from users")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, body) = post(&app, "/create_account", create).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::UNAUTHORIZED, "replayed message")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_db_creates_configured_file() {
+        let db_path = std::env::temp_dir().join(format!("md-pgp-server-{}.db", Uuid::now_v7()));
+        let config = Config {
+            db_path: db_path.clone(),
+            ..Config::default()
+        };
+        let pool = connect_db(&config).await.unwrap();
+        assert_eq!(
+            schema_version(&pool).await.unwrap(),
+            expected_schema_version()
+        );
+        pool.close().await;
+        assert!(db_path.exists());
+        std::fs::remove_file(db_path).unwrap();
+
+        let config = Config {
+            db_path: "/nonexistent/dir/data.db".into(),
+            ..Config::default()
+        };
+        let error = connect_db(&config).await.unwrap_err();
+        assert!(
+            error.to_string().contains("/nonexistent/dir/data.db"),
+            "{error:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_shares_dont_lock() {
+        let db_path = std::env::temp_dir().join(format!("md-pgp-server-{}.db", Uuid::now_v7()));
+        let config = Config {
+            db_path: db_path.clone(),
+            max_accounts_per_ip: 10,
+            ..Config::default()
+        };
+        let pool = connect_db(&config).await.unwrap();
+        let journal_mode: String = sqlx::query_scalar(r#"pragma journal_mode"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let app = app(AppState::new(pool.clone(), config));
+
+        let owner = TestClient::new(&app, "owner <owner@example.com>");
+        owner.create_account().await;
+        let doc_id = owner.create_document("popular").await;
+        let readers: Vec<_> = (0..8)
+            .map(|i| TestClient::new(&app, &format!("reader{i} <reader{i}@example.com>")))
+            .collect();
+        for reader in &readers {
+            assert_eq!(reader.create_account().await, StatusCode::OK);
+        }
+        let shares: Vec<_> = readers
+            .iter()
+            .map(|reader| {
+                let share = json!({ "doc_id": doc_id, "key_id": key_id_to_text(&reader.key_id()) });
+                let request = sign_json(&owner.key, share);
+                let app = app.clone();
+                tokio::spawn(async move { post(&app, "/documents/share", request).await })
+            })
+            .collect();
+        for share in shares {
+            let (status, body) = share.await.unwrap();
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        let shared: i64 = sqlx::query_scalar(r#"select count(*) from document_shares"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(shared, 8);
+
+        pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unique_violations_are_recognized() {
+        let (_app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>").signed_public_key();
+        insert_user(&pool, &alice, None).await.unwrap();
+
+        let error = insert_user(&pool, &alice, None).await.unwrap_err();
+        assert!(error.downcast_ref().is_some_and(is_unique_violation));
+
+        // other constraint failures aren't conflicts
+        let error = sqlx::query(r#"insert into users (uid, public_key) values ('x', null)"#)
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        assert!(!is_unique_violation(&error));
+    }
+
+    #[tokio::test]
+    async fn test_keys_stored_armored() {
+        let (_app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        register(&pool, &alice).await;
+
+        let stored: String = sqlx::query_scalar(r#"select public_key from users where uid = ?"#)
+            .bind(key_id_to_text(&alice.key_id()))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+        let key = get_user_key(&pool, &alice.key_id()).await.unwrap().unwrap();
+        assert_eq!(key, alice.signed_public_key());
+
+        // keys written in binary before the switch are armored on startup
+        let bob = generate_key("bob <bob@example.com>");
+        sqlx::query(r#"insert into users (uid, public_key) values (?, ?)"#)
+            .bind(key_id_to_text(&bob.key_id()))
+            .bind(bob.signed_public_key().to_bytes().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        init_db(&pool).await.unwrap();
+        let key = get_user_key(&pool, &bob.key_id()).await.unwrap().unwrap();
+        assert_eq!(key, bob.signed_public_key());
+    }
+
+    #[tokio::test]
+    async fn test_accounts_per_ip_capped() {
+        let window = Duration::from_secs(1);
+        let config = Config {
+            max_accounts_per_ip: 2,
+            account_creation_window: window,
+            ..Config::default()
+        };
+        let (app, _pool) = test_app(config).await;
+        let create = |skey: &SignedSecretKey| {
+            let public_key = skey.signed_public_key().to_bytes().unwrap();
+            sign(skey, &public_key)
+        };
+        let first = generate_key("first <first@example.com>");
+
+        let (status, _) = post(&app, "/create_account", create(&first)).await;
+        assert_eq!(status, StatusCode::OK);
+        // failed attempts don't use up the allowance
+        let (status, _) = post(&app, "/create_account", create(&first)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let second = generate_key("second <second@example.com>");
+        let (status, _) = post(&app, "/create_account", create(&second)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let third = generate_key("third <third@example.com>");
+        let upload = create(&third);
+        let (status, _) = post(&app, "/create_account", upload.clone()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // and the upload that was turned away can be sent again later
+        tokio::time::sleep(window).await;
+        let (status, body) = post(&app, "/create_account", upload).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
 }
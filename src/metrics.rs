@@ -0,0 +1,185 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::SqlitePool;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{AppError, AppState, internal_error};
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters and request durations since startup, served at `/metrics` in the
+/// Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// Keyed by name and rendered label set.
+    counters: Mutex<BTreeMap<(&'static str, String), u64>>,
+    durations: Mutex<BTreeMap<String, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or under each of `BUCKETS`, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &'static str, labels: &[(&str, &str)]) {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry((name, labels))
+            .or_default() += 1;
+    }
+
+    fn observe(&self, route: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations.entry(route.to_string()).or_default();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String) {
+        let mut last = None;
+        for ((name, labels), value) in self.counters.lock().unwrap().iter() {
+            if last != Some(name) {
+                let _ = writeln!(out, "# TYPE {name} counter");
+                last = Some(name);
+            }
+            if labels.is_empty() {
+                let _ = writeln!(out, "{name} {value}");
+            } else {
+                let _ = writeln!(out, "{name}{{{labels}}} {value}");
+            }
+        }
+
+        let name = "http_request_duration_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (route, histogram) in self.durations.lock().unwrap().iter() {
+            let route = escape(route);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let count = histogram.count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{route=\"{route}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(out, "{name}_sum{{route=\"{route}\"}} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{{route=\"{route}\"}} {count}");
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Times each request against the route it matched.
+pub async fn track_durations(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .observe(matched_path.as_str(), start.elapsed());
+    response
+}
+
+/// The counters and durations so far, plus gauges read at scrape time.
+/// Served on the main address, or only on `metrics_addr` when that's set.
+pub async fn handle_metrics(State(state): State<AppState>) -> Result<Response, AppError> {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+    gauges(&state.pool, &mut out)
+        .await
+        .map_err(internal_error)?;
+    let content_type = "text/plain; version=0.0.4; charset=utf-8";
+    Ok(([(header::CONTENT_TYPE, content_type)], out).into_response())
+}
+
+async fn gauges(pool: &SqlitePool, out: &mut String) -> sqlx::Result<()> {
+    let (accounts, documents): (i64, i64) =
+        sqlx::query_as(r#"select (select count(*) from users), (select count(*) from documents)"#)
+            .fetch_one(pool)
+            .await?;
+    for (name, value) in [
+        ("accounts", accounts as u64),
+        ("documents", documents as u64),
+        ("db_pool_connections", u64::from(pool.size())),
+        ("db_pool_idle_connections", pool.num_idle() as u64),
+    ] {
+        let _ = writeln!(out, "# TYPE {name} gauge\n{name} {value}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        config::Config,
+        test_util::{TestClient, get, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_metrics_count_accounts_and_requests() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        alice.create_account().await;
+        alice.create_document("notes").await;
+        let (status, _) = alice.post("/documents", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = get(&app, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        let lines: Vec<&str> = body.lines().collect();
+        for expected in [
+            r#"create_account_total{result="ok"} 1"#,
+            r#"create_account_total{result="conflict"} 1"#,
+            "documents_created_total 1",
+            r#"http_request_duration_seconds_count{route="/documents"} 1"#,
+            r#"http_request_duration_seconds_bucket{route="/create_account",le="+Inf"} 2"#,
+            "accounts 1",
+            "documents 1",
+        ] {
+            assert!(lines.contains(&expected), "{expected} missing from\n{body}");
+        }
+    }
+}
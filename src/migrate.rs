@@ -0,0 +1,184 @@
+use sqlx::{Row, Sqlite, Transaction};
+
+use crate::{key_id_from_text, key_id_to_text};
+
+/// The migration that drops `documents.shared_with`.
+pub const DROP_SHARED_WITH: i64 = 27;
+
+/// Copies sharing data from the legacy `documents.shared_with` CSV column into
+/// `document_shares`, ahead of the migration that drops the column. Copying
+/// twice changes nothing, and once the column is gone this does nothing.
+pub async fn migrate_legacy_shares(tx: &mut Transaction<'_, Sqlite>) -> sqlx::Result<()> {
+    if has_shared_with_column(tx).await? {
+        copy_legacy_shares(tx).await?;
+    }
+    Ok(())
+}
+
+async fn has_shared_with_column(tx: &mut Transaction<'_, Sqlite>) -> sqlx::Result<bool> {
+    let row = sqlx::query(
+        r#"select count(*) as count from pragma_table_info('documents') where name = 'shared_with'"#,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(row.get::<i64, _>("count") > 0)
+}
+
+async fn copy_legacy_shares(tx: &mut Transaction<'_, Sqlite>) -> sqlx::Result<()> {
+    let rows =
+        sqlx::query(r#"select doc_id, shared_with from documents where shared_with is not null"#)
+            .fetch_all(&mut **tx)
+            .await?;
+
+    for row in rows {
+        let doc_id: String = row.get("doc_id");
+        let shared_with: String = row.get("shared_with");
+        for user_id in parse_legacy_shared_with(&shared_with) {
+            // entries naming users that no longer exist are dropped
+            sqlx::query(
+                r#"insert or ignore into document_shares (doc_id, user_id)
+                select ?, uid from users where uid = ?"#,
+            )
+            .bind(&doc_id)
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a legacy CSV value into normalized key ids, skipping empty entries
+/// (trailing commas, `",,"`) and anything that isn't a valid key id.
+fn parse_legacy_shared_with(shared_with: &str) -> Vec<String> {
+    shared_with
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| key_id_from_text(id).ok())
+        .map(|key_id| key_id_to_text(&key_id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::test_util::memory_pool;
+
+    const ALICE: &str = "0123456789abcdef";
+    const BOB: &str = "fedcba9876543210";
+
+    async fn seed_legacy_schema(pool: &SqlitePool) {
+        sqlx::query(
+            r#"
+            CREATE TABLE users (
+                uid TEXT PRIMARY KEY,
+                key_blob BLOB NOT NULL
+            );
+            CREATE TABLE documents (
+                doc_id TEXT PRIMARY KEY,
+                name TEXT,
+                user_id TEXT,
+                shared_with TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(uid)
+            );
+            INSERT INTO users (uid, key_blob) VALUES
+                ('owner', x''),
+                ('0123456789abcdef', x''),
+                ('fedcba9876543210', x'');
+            INSERT INTO documents (doc_id, name, user_id, shared_with) VALUES
+                ('doc-1', 'both', 'owner', '0123456789abcdef,fedcba9876543210'),
+                ('doc-2', 'trailing', 'owner', 'fedcba9876543210,'),
+                ('doc-3', 'empty entries', 'owner', ',,0123456789abcdef,,'),
+                ('doc-4', 'blank', 'owner', ''),
+                ('doc-5', 'null', 'owner', NULL),
+                ('doc-6', 'garbage', 'owner', 'not-a-key-id,0123456789abcdef'),
+                ('doc-7', 'unknown user', 'owner', '1111111111111111,fedcba9876543210');
+            "#,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn shares(pool: &SqlitePool) -> Vec<(String, String)> {
+        sqlx::query(r#"select doc_id, user_id from document_shares order by doc_id, user_id"#)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.get("doc_id"), row.get("user_id")))
+            .collect()
+    }
+
+    fn expected_shares() -> Vec<(String, String)> {
+        [
+            ("doc-1", ALICE),
+            ("doc-1", BOB),
+            ("doc-2", BOB),
+            ("doc-3", ALICE),
+            ("doc-6", ALICE),
+            ("doc-7", BOB),
+        ]
+        .into_iter()
+        .map(|(doc, user)| (doc.to_string(), user.to_string()))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_copying_keeps_the_legacy_column() {
+        let pool = memory_pool().await;
+        seed_legacy_schema(&pool).await;
+        sqlx::query(
+            r#"CREATE TABLE document_shares (
+                doc_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                PRIMARY KEY (doc_id, user_id)
+            )"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        for _ in 0..2 {
+            migrate_legacy_shares(&mut tx).await.unwrap();
+        }
+        // dropping it is left to a later migration
+        assert!(has_shared_with_column(&mut tx).await.unwrap());
+        tx.commit().await.unwrap();
+        assert_eq!(shares(&pool).await, expected_shares());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_shares() {
+        let pool = memory_pool().await;
+        seed_legacy_schema(&pool).await;
+
+        crate::init_db(&pool).await.unwrap();
+
+        let expected = expected_shares();
+        assert_eq!(shares(&pool).await, expected);
+
+        let mut tx = pool.begin().await.unwrap();
+        assert!(!has_shared_with_column(&mut tx).await.unwrap());
+        tx.commit().await.unwrap();
+
+        // running again on an already-migrated database is a no-op
+        crate::init_db(&pool).await.unwrap();
+        assert_eq!(shares(&pool).await, expected);
+    }
+
+    #[test]
+    fn test_parse_legacy_shared_with() {
+        assert_eq!(parse_legacy_shared_with(""), Vec::<String>::new());
+        assert_eq!(parse_legacy_shared_with(",,"), Vec::<String>::new());
+        assert_eq!(
+            parse_legacy_shared_with(" 0123456789ABCDEF , fedcba9876543210,"),
+            vec![ALICE.to_string(), BOB.to_string()]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rand::{Rng, thread_rng};
+use serde::Serialize;
+use sqlx::{SqliteExecutor, SqlitePool};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::{AppError, AppState, internal_error, signature::FreshMessage, sweeper};
+
+/// Why a nonce in a signed request wasn't accepted.
+#[derive(Debug, Error)]
+pub enum NonceError {
+    #[error("unknown nonce")]
+    Unknown,
+    #[error("expired nonce")]
+    Expired,
+    #[error("replayed message")]
+    Replayed,
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+impl From<NonceError> for AppError {
+    fn from(error: NonceError) -> Self {
+        match error {
+            NonceError::Unknown | NonceError::Expired | NonceError::Replayed => {
+                AppError::Status(StatusCode::UNAUTHORIZED, error.to_string())
+            }
+            NonceError::Database(error) => internal_error(error).into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedNonce {
+    nonce: String,
+    expires_at: String,
+}
+
+/// Hands out a nonce that one signed request may carry within `nonce_ttl`,
+/// so it can't be replayed even inside the freshness window.
+pub async fn handle_new_nonce(
+    State(state): State<AppState>,
+) -> Result<Json<IssuedNonce>, AppError> {
+    let (nonce, expires_at) = issue(&state.pool, state.config.nonce_ttl, Utc::now())
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(IssuedNonce {
+        nonce,
+        expires_at: timestamp(expires_at),
+    }))
+}
+
+pub async fn issue(
+    pool: &SqlitePool,
+    ttl: Duration,
+    now: DateTime<Utc>,
+) -> sqlx::Result<(String, DateTime<Utc>)> {
+    let nonce = hex::encode(thread_rng().r#gen::<[u8; 16]>());
+    let expires_at = now + ttl;
+    sqlx::query("insert into nonces (nonce, issued_at, expires_at) values (?, ?, ?)")
+        .bind(&nonce)
+        .bind(timestamp(now))
+        .bind(timestamp(expires_at))
+        .execute(pool)
+        .await?;
+    Ok((nonce, expires_at))
+}
+
+/// Uses up `nonce`. Expired nonces are left for `purge_expired`.
+pub async fn consume(pool: &SqlitePool, nonce: &str, now: DateTime<Utc>) -> Result<(), NonceError> {
+    let now = timestamp(now);
+    let consumed = sqlx::query("delete from nonces where nonce = ? and expires_at > ?")
+        .bind(nonce)
+        .bind(&now)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if consumed == 1 {
+        return Ok(());
+    }
+    let exists: Option<(String,)> = sqlx::query_as("select nonce from nonces where nonce = ?")
+        .bind(nonce)
+        .fetch_optional(pool)
+        .await?;
+    Err(if exists.is_some() {
+        NonceError::Expired
+    } else {
+        NonceError::Unknown
+    })
+}
+
+/// Records a message as used, failing if it already was. Messages that carry
+/// no server-issued nonce, like account creation, are guarded this way; the
+/// caller records it in the transaction that acts on it, so a request that
+/// fails isn't used up.
+pub async fn record_message(
+    conn: impl SqliteExecutor<'_>,
+    message: &FreshMessage,
+) -> Result<(), NonceError> {
+    let recorded = sqlx::query(
+        "insert into seen_messages (digest, expires_at) values (?, ?) on conflict do nothing",
+    )
+    .bind(&message.digest)
+    .bind(timestamp(message.fresh_until))
+    .execute(conn)
+    .await?
+    .rows_affected();
+    if recorded == 1 {
+        Ok(())
+    } else {
+        Err(NonceError::Replayed)
+    }
+}
+
+/// Drops expired nonces, and used messages too old to be replayed anyway.
+pub async fn purge_expired(
+    pool: &SqlitePool,
+    now: DateTime<Utc>,
+    batch_size: u32,
+) -> sqlx::Result<u64> {
+    let now = timestamp(now);
+    let mut purged = 0;
+    for table in ["nonces", "seen_messages"] {
+        purged +=
+            sweeper::delete_in_batches(pool, table, "expires_at <= ?", &now, batch_size).await?;
+    }
+    Ok(purged)
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_util::{TestClient, error_message, get, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_expired_nonces_are_rejected_and_purged() {
+        let (app, pool) = test_app(Config::default()).await;
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let ttl = Duration::from_secs(60);
+        let (fresh, _) = issue(&pool, ttl, start).await.unwrap();
+        let (stale, _) = issue(&pool, ttl, start).await.unwrap();
+
+        let later = start + Duration::from_secs(30);
+        consume(&pool, &fresh, later).await.unwrap();
+        assert!(matches!(
+            consume(&pool, &fresh, later).await,
+            Err(NonceError::Unknown)
+        ));
+
+        let expired = start + Duration::from_secs(90);
+        let rejection: AppError = consume(&pool, &stale, expired).await.unwrap_err().into();
+        assert_eq!(
+            rejection,
+            AppError::Status(StatusCode::UNAUTHORIZED, "expired nonce".to_string())
+        );
+        assert_eq!(purge_expired(&pool, later, 100).await.unwrap(), 0);
+        assert_eq!(purge_expired(&pool, expired, 100).await.unwrap(), 1);
+        assert!(matches!(
+            consume(&pool, &stale, expired).await,
+            Err(NonceError::Unknown)
+        ));
+
+        // and through a signed request
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        let (status, body) = get(&app, "/nonce").await;
+        assert_eq!(status, StatusCode::OK);
+        let nonce: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let request = json!({ "nonce": nonce["nonce"] });
+        let (status, _) = alice.post("/documents", request.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = alice.post("/documents", request).await;
+        assert_eq!(
+            (status, error_message(&body).as_str()),
+            (StatusCode::UNAUTHORIZED, "unknown nonce")
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use pgp::types::KeyId;
+
+use crate::{AppError, AppState, client_ip::client_ip, config::Config, key_id_to_text};
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// Expired windows are only swept once this many clients are being tracked.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+/// Fixed one-minute windows of request counts, per route and client.
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<(String, String), Window>>,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    /// Counts a request, or returns how long until the client may retry.
+    pub fn check(&self, route: &str, client: &str, limit: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= SWEEP_THRESHOLD {
+            windows.retain(|_, window| now.duration_since(window.start) < WINDOW);
+        }
+
+        let window = windows
+            .entry((route.to_string(), client.to_string()))
+            .or_insert(Window {
+                start: now,
+                count: 0,
+            });
+        if now.duration_since(window.start) >= WINDOW {
+            *window = Window {
+                start: now,
+                count: 0,
+            };
+        }
+        if window.count >= limit {
+            return Err(WINDOW - now.duration_since(window.start));
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
+
+/// Recent account creations per client address. Entries age out of the window
+/// one by one, so a client regains capacity gradually.
+#[derive(Default)]
+pub struct AccountCreations {
+    by_ip: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl AccountCreations {
+    /// Reserves a creation for `ip` unless it already made `cap` in `window`.
+    pub fn try_acquire(&self, ip: IpAddr, cap: u32, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut by_ip = self.by_ip.lock().unwrap();
+        if by_ip.len() >= SWEEP_THRESHOLD {
+            by_ip.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|t| now.duration_since(*t) < window)
+            });
+        }
+
+        let times = by_ip.entry(ip).or_default();
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= cap as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Gives back a reservation for a creation that didn't go through.
+    pub fn release(&self, ip: IpAddr) {
+        if let Some(times) = self.by_ip.lock().unwrap().get_mut(&ip) {
+            times.pop_back();
+        }
+    }
+}
+
+pub async fn rate_limit(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.as_str();
+    let limit = route_limit(&state.config, route);
+    let (parts, body) = request.into_parts();
+    let client = client_ip(&parts, &state.config.trusted_proxies).to_string();
+    let request = Request::from_parts(parts, body);
+
+    match state.rate_limiter.check(route, &client, limit) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => AppError::RateLimited(retry_after).into_response(),
+    }
+}
+
+/// Counts a verified request against its signer's own budget for `route`,
+/// the same size as each address gets. A key can't escape its limit by
+/// spreading requests over many addresses.
+pub fn check_key(state: &AppState, route: &str, key_id: &KeyId) -> Result<(), AppError> {
+    let limit = route_limit(&state.config, route);
+    let client = format!("key {}", key_id_to_text(key_id));
+    state
+        .rate_limiter
+        .check(route, &client, limit)
+        .map_err(AppError::RateLimited)
+}
+
+fn route_limit(config: &Config, route: &str) -> u32 {
+    config
+        .rate_limits
+        .get(route)
+        .copied()
+        .unwrap_or(config.default_rate_limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{StatusCode, header},
+    };
+    use serde_json::json;
+    use std::{collections::HashMap, net::SocketAddr};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_util::{generate_key, get, post, register, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_per_route_limits() {
+        let config = Config {
+            default_rate_limit: 5,
+            rate_limits: HashMap::from([("/create_document".to_string(), 2)]),
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let skey = generate_key("alice <alice@example.com>");
+        register(&pool, &skey).await;
+
+        for _ in 0..2 {
+            let body = sign_json(&skey, json!({ "name": "notes" }));
+            let (status, _) = post(&app, "/create_document", body).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let body = sign_json(&skey, json!({ "name": "notes" }));
+        let (status, _) = post(&app, "/create_document", body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // reads have their own, looser budget
+        for _ in 0..5 {
+            let (status, _) = get(&app, "/policy").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = get(&app, "/policy").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_limited_across_addresses() {
+        let config = Config {
+            rate_limits: HashMap::from([("/create_document".to_string(), 2)]),
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        let skey = generate_key("alice <alice@example.com>");
+        register(&pool, &skey).await;
+        let create_from = |peer: &str| {
+            let peer: SocketAddr = format!("{peer}:4000").parse().unwrap();
+            let request = Request::post("/create_document")
+                .extension(ConnectInfo(peer))
+                .body(Body::from(sign_json(&skey, json!({ "name": "notes" }))))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        for peer in ["10.0.0.1", "10.0.0.2"] {
+            let response = create_from(peer).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = create_from("10.0.0.3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+    }
+
+    #[test]
+    fn test_account_creations_decay() {
+        let creations = AccountCreations::default();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let window = Duration::from_millis(50);
+        assert!(creations.try_acquire(ip, 2, window));
+        assert!(creations.try_acquire(ip, 2, window));
+        assert!(!creations.try_acquire(ip, 2, window));
+        creations.release(ip);
+        assert!(creations.try_acquire(ip, 2, window));
+
+        std::thread::sleep(window);
+        assert!(creations.try_acquire(ip, 2, window));
+    }
+
+    #[test]
+    fn test_limits_are_per_client() {
+        let limiter = RateLimiter::default();
+        assert!(limiter.check("/route", "1.2.3.4", 1).is_ok());
+        assert!(limiter.check("/route", "1.2.3.4", 1).is_err());
+        assert!(limiter.check("/route", "5.6.7.8", 1).is_ok());
+        assert!(limiter.check("/other", "1.2.3.4", 1).is_ok());
+    }
+}
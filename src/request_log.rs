@@ -0,0 +1,207 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::time::{Duration, Instant};
+use tracing::{Span, field::Empty};
+
+use crate::AppState;
+
+/// The span each request runs in. `key_id` is filled in once a signature or
+/// session has said who the caller is. The query string is left out because
+/// query-signed reads carry their signature there.
+pub fn make_span<B>(request: &axum::http::Request<B>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        key_id = Empty,
+    )
+}
+
+/// Logs how each request ended: client errors as warnings, server errors as
+/// errors, the rest at debug.
+pub fn on_response<B>(response: &axum::http::Response<B>, latency: Duration, _span: &Span) {
+    let status = response.status().as_u16();
+    let latency_ms = latency.as_millis() as u64;
+    if response.status().is_server_error() {
+        tracing::error!(status, latency_ms, "request failed");
+    } else if response.status().is_client_error() {
+        tracing::warn!(status, latency_ms, "request rejected");
+    } else {
+        tracing::debug!(status, latency_ms, "request finished");
+    }
+}
+
+/// Names the caller on the current request's span.
+pub fn record_caller(key_id: &pgp::types::KeyId) {
+    Span::current().record("key_id", crate::key_id_to_text(key_id));
+}
+
+/// Warns about requests that take longer than `slow_request_threshold`, so
+/// stalls in signature checks or the database stand out without logging
+/// every request.
+pub async fn log_slow_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    if elapsed > state.config.slow_request_threshold {
+        tracing::warn!(
+            %method,
+            path,
+            status = response.status().as_u16(),
+            duration_ms = elapsed.as_millis() as u64,
+            "slow request"
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware, routing::get};
+    use std::{
+        fmt::Debug,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tracing::{
+        Event, Subscriber,
+        field::{Field, Visit},
+        span,
+    };
+    use tracing_subscriber::{
+        Registry,
+        layer::{Context, Layer, SubscriberExt},
+    };
+
+    use super::*;
+    use crate::{
+        config::Config,
+        key_id_to_text,
+        test_util::{TestClient, get as get_request, memory_pool, test_app},
+    };
+
+    /// Collects the fields of every event, formatted as `name=value` after
+    /// the event's level, and separately the fields of every span.
+    #[derive(Clone, Default)]
+    struct Events {
+        events: Arc<Mutex<Vec<String>>>,
+        spans: Arc<Mutex<Vec<(span::Id, String)>>>,
+    }
+
+    impl Events {
+        fn install(&self) -> tracing::subscriber::DefaultGuard {
+            tracing::subscriber::set_default(Registry::default().with(self.clone()))
+        }
+
+        fn span_fields(&self) -> Vec<String> {
+            let spans = self.spans.lock().unwrap();
+            spans.iter().map(|(_, fields)| fields.clone()).collect()
+        }
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push_str(&format!("{}={value:?} ", field.name()));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Events {
+        fn on_new_span(&self, attributes: &span::Attributes<'_>, id: &span::Id, _: Context<'_, S>) {
+            let mut fields = Fields(String::new());
+            attributes.record(&mut fields);
+            self.spans.lock().unwrap().push((id.clone(), fields.0));
+        }
+        fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _: Context<'_, S>) {
+            let mut fields = Fields(String::new());
+            values.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            if let Some((_, recorded)) = spans.iter_mut().rev().find(|(span, _)| span == id) {
+                recorded.push_str(&fields.0);
+            }
+        }
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let mut fields = Fields(format!("level={} ", event.metadata().level()));
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_requests_are_logged() {
+        let config = Config {
+            slow_request_threshold: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let state = AppState::new(memory_pool().await, config);
+        let app = Router::new()
+            .route("/fast", get(|| async { "fast" }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    "slow"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                log_slow_requests,
+            ))
+            .with_state(state);
+
+        let events = Events::default();
+        let _guard = events.install();
+        get_request(&app, "/fast").await;
+        assert!(events.events.lock().unwrap().is_empty());
+
+        get_request(&app, "/slow").await;
+        let logged = events.events.lock().unwrap();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].contains("message=slow request"), "{}", logged[0]);
+        assert!(logged[0].contains("path=\"/slow\""), "{}", logged[0]);
+        assert!(logged[0].contains("status=200"), "{}", logged[0]);
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_traced_with_caller() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+
+        let events = Events::default();
+        let _guard = events.install();
+        alice.list_documents().await;
+        get_request(&app, "/does/not/exist?signature=secret").await;
+
+        let spans = events.span_fields();
+        assert!(spans[0].contains("method=POST"), "{spans:?}");
+        assert!(spans[0].contains("path=\"/documents\""), "{spans:?}");
+        let key_id = format!("key_id=\"{}\"", key_id_to_text(&alice.key_id()));
+        assert!(spans.iter().any(|span| span.contains(&key_id)), "{spans:?}");
+        assert!(
+            !spans.iter().any(|span| span.contains("secret")),
+            "{spans:?}"
+        );
+        let logged = events.events.lock().unwrap();
+        let finished: Vec<_> = logged
+            .iter()
+            .filter(|event| event.contains("latency_ms="))
+            .collect();
+        assert_eq!(finished.len(), 2, "{logged:?}");
+        assert!(finished[0].starts_with("level=DEBUG"), "{}", finished[0]);
+        assert!(finished[0].contains("status=200"), "{}", finished[0]);
+        assert!(finished[1].starts_with("level=WARN"), "{}", finished[1]);
+        assert!(finished[1].contains("status=404"), "{}", finished[1]);
+    }
+}
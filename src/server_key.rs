@@ -0,0 +1,180 @@
+use anyhow::Context;
+use axum::{Json, extract::State, http::StatusCode};
+use pgp::{
+    composed::{
+        ArmorOptions, Deserializable, KeyType, SecretKeyParamsBuilder, SignedPublicKey,
+        SignedSecretKey,
+    },
+    ser::Serialize,
+    types::{KeyDetails, Password},
+};
+use rand::thread_rng;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::{Row, SqlitePool};
+use std::io;
+
+use crate::{AppError, AppState, audit, auth::AdminRequest, internal_error, now_timestamp};
+
+/// A fresh signing key for the server itself.
+fn generate_server_key() -> anyhow::Result<SignedSecretKey> {
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::Ed25519Legacy)
+        .can_certify(true)
+        .can_sign(true)
+        .primary_user_id("md-pgp-server".into())
+        .build()?;
+    let key = params
+        .generate(thread_rng())?
+        .sign(thread_rng(), &Password::empty())?;
+    Ok(key)
+}
+
+async fn insert_server_key(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &SignedSecretKey,
+) -> anyhow::Result<()> {
+    sqlx::query(r#"insert into server_keys (secret_key, public_key, created_at) values (?, ?, ?)"#)
+        .bind(key.to_bytes()?)
+        .bind(key.signed_public_key().to_bytes()?)
+        .bind(now_timestamp())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Generates the first server key if there isn't a current one yet.
+pub async fn ensure_server_key(pool: &SqlitePool) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query(r#"select count(*) as count from server_keys where retired_at is null"#)
+        .fetch_one(&mut *tx)
+        .await?;
+    if row.get::<i64, _>("count") == 0 {
+        insert_server_key(&mut tx, &generate_server_key()?).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Retires the current server key and replaces it with a fresh one. Retired
+/// keys stay in the table so signatures made just before a rotation can still
+/// be checked.
+pub async fn rotate_server_key(pool: &SqlitePool) -> anyhow::Result<SignedPublicKey> {
+    let key = generate_server_key()?;
+    let mut tx = pool.begin().await?;
+    sqlx::query(r#"update server_keys set retired_at = ? where retired_at is null"#)
+        .bind(now_timestamp())
+        .execute(&mut *tx)
+        .await?;
+    insert_server_key(&mut tx, &key).await?;
+    tx.commit().await?;
+    Ok(key.signed_public_key())
+}
+
+fn armor(public_key: Vec<u8>) -> anyhow::Result<String> {
+    let key = SignedPublicKey::from_bytes(io::Cursor::new(public_key))?;
+    Ok(key.to_armored_string(ArmorOptions::default())?)
+}
+
+/// The current server key, plus rotated-out keys still inside the grace window.
+pub async fn handle_server_key(State(state): State<AppState>) -> Result<Json<Value>, AppError> {
+    let grace_start = chrono::Utc::now() - state.config.server_key_grace;
+    let rows = sqlx::query(
+        r#"select public_key, retired_at from server_keys
+        where retired_at is null or retired_at > ?
+        order by id desc"#,
+    )
+    .bind(grace_start.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal_error)?;
+
+    let mut current = None;
+    let mut previous = Vec::new();
+    for row in rows {
+        let armored = armor(row.get("public_key")).map_err(internal_error)?;
+        match row.get::<Option<String>, _>("retired_at") {
+            None => current = Some(armored),
+            Some(retired_at) => {
+                previous.push(json!({ "public_key": armored, "retired_at": retired_at }))
+            }
+        }
+    }
+    let current = current.ok_or((StatusCode::NOT_FOUND, "no server key".to_string()))?;
+
+    Ok(Json(json!({ "current": current, "previous": previous })))
+}
+
+#[derive(Deserialize)]
+pub struct RotateServerKey {}
+
+pub async fn handle_rotate_server_key(
+    State(pool): State<SqlitePool>,
+    AdminRequest(request): AdminRequest<RotateServerKey>,
+) -> Result<String, AppError> {
+    let key = rotate_server_key(&pool)
+        .await
+        .context("Failed to rotate server key")
+        .map_err(internal_error)?;
+    let fingerprint = key.fingerprint().to_string();
+    audit::record(
+        &pool,
+        &request.key_id,
+        "rotate_server_key",
+        &fingerprint,
+        "ok",
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_util::{generate_key, get, post, register, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_rotated_key_published_during_grace() {
+        let admin = generate_key("admin <admin@example.com>");
+        let user = generate_key("user <user@example.com>");
+        let config = Config {
+            admin_key_ids: vec![admin.key_id()],
+            ..Config::default()
+        };
+        let (app, pool) = test_app(config).await;
+        register(&pool, &admin).await;
+        register(&pool, &user).await;
+        ensure_server_key(&pool).await.unwrap();
+
+        let (status, body) = get(&app, "/server-key").await;
+        assert_eq!(status, StatusCode::OK);
+        let before: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(before["previous"], json!([]));
+
+        let (status, _) = post(
+            &app,
+            "/admin/rotate-server-key",
+            sign_json(&user, json!({})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post(
+            &app,
+            "/admin/rotate-server-key",
+            sign_json(&admin, json!({})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, body) = get(&app, "/server-key").await;
+        let after: Value = serde_json::from_str(&body).unwrap();
+        assert_ne!(after["current"], before["current"]);
+        assert_eq!(after["previous"][0]["public_key"], before["current"]);
+    }
+}
@@ -0,0 +1,314 @@
+use axum::{
+    Json,
+    extract::{FromRequestParts, State},
+    http::{StatusCode, header, request::Parts},
+};
+use chrono::{SecondsFormat, Utc};
+use pgp::types::KeyId;
+use rand::{Rng, thread_rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{
+    AppError, AppState,
+    auth::{SignedQuery, SignedRequest, SingleUse},
+    begin_write, internal_error, key_id_from_text, key_id_to_text, nonce, now_timestamp,
+    request_log,
+};
+
+/// A bearer token, as handed out once at issue. Only its hash is stored.
+#[derive(Debug, Serialize)]
+pub struct IssuedSession {
+    token_id: Uuid,
+    token: String,
+    expires_at: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SessionInfo {
+    token_id: String,
+    issued_at: String,
+    expires_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewSession {}
+
+/// Trades a signed request for a bearer token that stands in for the key on
+/// the `GET` reads, as `SignedQuery` describes, until it expires or is
+/// revoked. The request is single use, or whoever saw it could trade it for
+/// a token of their own.
+pub async fn handle_new_session(
+    State(state): State<AppState>,
+    SingleUse { request, message }: SingleUse<NewSession>,
+) -> Result<Json<IssuedSession>, AppError> {
+    let token = hex::encode(thread_rng().r#gen::<[u8; 32]>());
+    let token_id = Uuid::now_v7();
+    let expires_at =
+        (Utc::now() + state.config.session_lifetime).to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut tx = begin_write(&state.pool).await.map_err(internal_error)?;
+    sqlx::query(
+        r#"insert into sessions (token_id, token_sha256, key_id, issued_at, expires_at)
+        values (?, ?, ?, ?, ?)"#,
+    )
+    .bind(token_id.to_string())
+    .bind(token_hash(&token))
+    .bind(key_id_to_text(&request.key_id))
+    .bind(now_timestamp())
+    .bind(&expires_at)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    if let Some(message) = &message {
+        nonce::record_message(&mut *tx, message).await?;
+    }
+    tx.commit().await.map_err(internal_error)?;
+    Ok(Json(IssuedSession {
+        token_id,
+        token,
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ListSessions {}
+
+/// The signer's sessions that are neither expired nor revoked.
+pub async fn handle_list_sessions(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<ListSessions>,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    Ok(Json(active_sessions(&pool, &request.key_id).await?))
+}
+
+/// As `handle_list_sessions`, signed in the query string.
+pub async fn handle_get_sessions(
+    State(pool): State<SqlitePool>,
+    SignedQuery(request): SignedQuery<ListSessions>,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    Ok(Json(active_sessions(&pool, &request.key_id).await?))
+}
+
+async fn active_sessions(
+    pool: &SqlitePool,
+    key_id: &KeyId,
+) -> Result<Vec<SessionInfo>, (StatusCode, String)> {
+    sqlx::query_as(
+        r#"select token_id, issued_at, expires_at from sessions
+        where key_id = ? and revoked_at is null and expires_at > ?
+        order by token_id"#,
+    )
+    .bind(key_id_to_text(key_id))
+    .bind(now_timestamp())
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)
+}
+
+/// Revokes one session, or with `all`, every session of the signer.
+#[derive(Deserialize)]
+pub struct RevokeSessions {
+    token_id: Option<Uuid>,
+    #[serde(default)]
+    all: bool,
+}
+
+pub async fn handle_revoke_sessions(
+    State(pool): State<SqlitePool>,
+    request: SignedRequest<RevokeSessions>,
+) -> Result<String, AppError> {
+    let payload = request.payload;
+    let token_id = match (payload.token_id, payload.all) {
+        (Some(token_id), false) => Some(token_id.to_string()),
+        (None, true) => None,
+        _ => {
+            return Err(AppError::Status(
+                StatusCode::BAD_REQUEST,
+                "give either token_id or all".to_string(),
+            ));
+        }
+    };
+    let revoked = sqlx::query(
+        r#"update sessions set revoked_at = ?1
+        where key_id = ?2 and revoked_at is null and (?3 is null or token_id = ?3)"#,
+    )
+    .bind(now_timestamp())
+    .bind(key_id_to_text(&request.key_id))
+    .bind(&token_id)
+    .execute(&pool)
+    .await
+    .map_err(internal_error)?
+    .rows_affected();
+    if token_id.is_some() && revoked == 0 {
+        return Err(AppError::Status(
+            StatusCode::NOT_FOUND,
+            "session not found".to_string(),
+        ));
+    }
+    Ok(revoked.to_string())
+}
+
+/// A request carrying `Authorization: Bearer <token>` for a live session.
+pub struct Session {
+    pub key_id: KeyId,
+    pub token_id: Uuid,
+}
+
+impl FromRequestParts<AppState> for Session {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = || (StatusCode::UNAUTHORIZED, "invalid session".to_string());
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(unauthorized)?;
+        let row: Option<(String, String)> = sqlx::query_as(
+            r#"select token_id, key_id from sessions
+            where token_sha256 = ? and revoked_at is null and expires_at > ?"#,
+        )
+        .bind(token_hash(token.trim()))
+        .bind(now_timestamp())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(internal_error)?;
+        let (token_id, key_id) = row.ok_or_else(unauthorized)?;
+        let key_id = key_id_from_text(&key_id).map_err(internal_error)?;
+        request_log::record_caller(&key_id);
+        Ok(Session {
+            key_id,
+            token_id: Uuid::parse_str(&token_id).map_err(internal_error)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CurrentSession {
+    key_id: String,
+    token_id: Uuid,
+}
+
+/// Who a bearer token belongs to.
+pub async fn handle_current_session(session: Session) -> Json<CurrentSession> {
+    Json(CurrentSession {
+        key_id: key_id_to_text(&session.key_id),
+        token_id: session.token_id,
+    })
+}
+
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_util::{TestClient, fresh_nonce, send, test_app},
+    };
+
+    async fn whoami(app: &axum::Router, token: &str) -> StatusCode {
+        let request = Request::get("/sessions/current")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        send(app, request).await.0
+    }
+
+    #[tokio::test]
+    async fn test_revoked_sessions_stop_authenticating() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+
+        let laptop = alice.post_json("/sessions/new", json!({})).await;
+        // a second device in the same second needs a nonce to tell it apart
+        let phone = alice
+            .post_json("/sessions/new", json!({ "nonce": fresh_nonce(&app).await }))
+            .await;
+        let token = |session: &Value| session["token"].as_str().unwrap().to_string();
+        assert_eq!(whoami(&app, &token(&laptop)).await, StatusCode::OK);
+        assert_eq!(whoami(&app, &token(&phone)).await, StatusCode::OK);
+        assert_eq!(
+            alice
+                .post_json("/sessions", json!({}))
+                .await
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let (status, _) = alice
+            .post("/sessions/revoke", json!({ "token_id": phone["token_id"] }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(whoami(&app, &token(&laptop)).await, StatusCode::OK);
+        assert_eq!(whoami(&app, &token(&phone)).await, StatusCode::UNAUTHORIZED);
+        let active = alice.post_json("/sessions", json!({})).await;
+        assert_eq!(
+            active,
+            json!([{
+                "token_id": laptop["token_id"],
+                "issued_at": active[0]["issued_at"],
+                "expires_at": laptop["expires_at"],
+            }])
+        );
+
+        let (status, body) = alice.post("/sessions/revoke", json!({ "all": true })).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "1"));
+        assert_eq!(
+            whoami(&app, &token(&laptop)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(whoami(&app, "made-up").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_authenticate_get_reads() {
+        let (app, _pool) = test_app(Config::default()).await;
+        let alice = TestClient::new(&app, "alice <alice@example.com>");
+        alice.create_account().await;
+        alice.create_document("notes").await;
+
+        let session = alice.post_json("/sessions/new", json!({})).await;
+        let token = session["token"].as_str().unwrap();
+        let list = |token: String| {
+            let app = app.clone();
+            async move {
+                let request = Request::get("/documents?limit=10")
+                    .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap();
+                send(&app, request).await
+            }
+        };
+        let (status, body) = list(token.to_string()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let documents: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(documents[0]["name"], "notes");
+
+        alice
+            .post(
+                "/sessions/revoke",
+                json!({ "token_id": session["token_id"] }),
+            )
+            .await;
+        assert_eq!(list(token.to_string()).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            list("made-up".to_string()).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    future::{Future, IntoFuture},
+    io,
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{oneshot, watch},
+};
+
+use crate::{AppError, AppState};
+
+/// Requests in flight, and the switch that cuts them off once shutdown has
+/// waited long enough.
+#[derive(Clone)]
+pub struct Drain {
+    active: Arc<AtomicUsize>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Drain {
+            active: Arc::default(),
+            closed: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Drain {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Completes once shutdown has stopped waiting for requests, for
+    /// responses that stream on after their handler returns.
+    pub async fn closed(&self) {
+        let _ = self.closed.subscribe().wait_for(|closed| *closed).await;
+    }
+
+    fn force_close(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+/// Decrements the active count however the request ends.
+struct Active(Arc<AtomicUsize>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts the request as in flight, answering `503` instead if shutdown
+/// gives up on it.
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let drain = &state.drain;
+    drain.active.fetch_add(1, Ordering::SeqCst);
+    let _active = Active(drain.active.clone());
+    let mut closed = drain.closed.subscribe();
+
+    tokio::select! {
+        response = next.run(request) => response,
+        _ = closed.wait_for(|closed| *closed) => {
+            AppError::Status(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server shutting down".to_string(),
+            )
+            .into_response()
+        }
+    }
+}
+
+/// Serves `app` until `signal` completes, then stops accepting connections
+/// and gives requests in flight up to `drain_timeout` to finish before
+/// cutting them off.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    drain: Drain,
+    signal: impl Future<Output = ()> + Send + 'static,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    let (started, shutdown_started) = oneshot::channel();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        signal.await;
+        let _ = started.send(());
+    });
+    let mut server = pin!(server.into_future());
+
+    tokio::select! {
+        result = &mut server => return result,
+        _ = shutdown_started => {}
+    }
+    tracing::info!(active = drain.active(), "shutting down");
+    if let Ok(result) = tokio::time::timeout(drain_timeout, &mut server).await {
+        return result;
+    }
+    tracing::warn!(
+        active = drain.active(),
+        "drain timeout passed, cutting off remaining requests"
+    );
+    drain.force_close();
+    server.await
+}
+
+/// Completes on Ctrl-C or, on Unix, `SIGTERM`.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Path, middleware, routing::get};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{config::Config, test_util::memory_pool};
+
+    /// A bare HTTP/1.1 request, returning the status line.
+    async fn request(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_cuts_off_slow_requests() {
+        let state = AppState::new(memory_pool().await, Config::default());
+        let drain = state.drain.clone();
+        let app = Router::new()
+            .route(
+                "/sleep/{millis}",
+                get(|Path(millis): Path<u64>| async move {
+                    tokio::time::sleep(Duration::from_millis(millis)).await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                track_requests,
+            ))
+            .with_state(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            drain.clone(),
+            async move {
+                let _ = signal.await;
+            },
+            Duration::from_millis(300),
+        ));
+
+        let quick = tokio::spawn(request(addr, "/sleep/100"));
+        let slow = tokio::spawn(request(addr, "/sleep/10000"));
+        while drain.active() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        stop.send(()).unwrap();
+
+        assert_eq!(quick.await.unwrap(), "HTTP/1.1 200 OK");
+        assert_eq!(slow.await.unwrap(), "HTTP/1.1 503 Service Unavailable");
+        server.await.unwrap().unwrap();
+        assert_eq!(drain.active(), 0);
+    }
+}
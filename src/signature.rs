@@ -1,60 +1,299 @@
-use anyhow::{Context, Result};
-use pgp::composed::{Message, SignedPublicKey};
+use chrono::{DateTime, Utc};
+use pgp::composed::{
+    CleartextSignedMessage, Deserializable, DetachedSignature, KeyType, Message, MessageBuilder,
+    SecretKeyParamsBuilder, SignedPublicKey,
+};
+use pgp::crypto::hash::HashAlgorithm;
 use pgp::packet::Signature;
-use pgp::types::KeyId;
-use std::io::Cursor;
+use pgp::types::{Fingerprint, KeyDetails, KeyId, Password, PublicKeyTrait};
+use rand::thread_rng;
+use sha2::{Digest, Sha256};
+use std::{io::Cursor, time::Duration};
 use thiserror::Error;
 
-#[derive(Clone, Debug, Error)]
-#[error("Message was not the correct type. Expected signed.")]
-struct MessageNotSigned;
+use crate::keys::signing_subkeys;
 
-#[derive(Clone, Debug, Error)]
-#[error("Message had the wrong number of issues. Expected one, got {0:?}")]
-struct MessageBadIssuers(Vec<KeyId>);
+/// Why a signed message was rejected.
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("Message was not the correct type. Expected signed.")]
+    NotSigned,
+    #[error("Message had the wrong number of issuers. Expected one, got {0:?}")]
+    BadIssuers(Vec<KeyId>),
+    #[error("Message could not be parsed: {0}")]
+    Parse(#[source] pgp::errors::Error),
+    #[error("Signature did not verify: {0}")]
+    Verify(#[source] pgp::errors::Error),
+    #[error("Signature uses the weak hash algorithm {0:?}")]
+    WeakHash(HashAlgorithm),
+    #[error("Signature has expired")]
+    Expired,
+    #[error("Signature was not made within the freshness window")]
+    Stale,
+    #[error("Signature is dated {0}, ahead of the server's clock")]
+    FutureDated(DateTime<Utc>),
+}
+
+pub type Result<T> = std::result::Result<T, SignatureError>;
 
+/// Splits a signed message into its signature and the data it covers. The
+/// message may be binary, ASCII-armored (`gpg --armor --sign`) or cleartext
+/// signed (`gpg --clearsign`); for the last the data is the text as it was
+/// hashed, with `\r\n` line endings.
 pub fn parse_message(message: &[u8]) -> Result<(Signature, Vec<u8>)> {
-    let mut message = Message::from_bytes(Cursor::new(message))?;
+    let text = std::str::from_utf8(message).map(str::trim_start);
+    match text {
+        Ok(text) if text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") => {
+            parse_cleartext_message(text)
+        }
+        Ok(text) if text.starts_with("-----BEGIN PGP MESSAGE-----") => {
+            let (message, _) = Message::from_string(text).map_err(SignatureError::Parse)?;
+            signed_contents(message)
+        }
+        _ => {
+            let message =
+                Message::from_bytes(Cursor::new(message)).map_err(SignatureError::Parse)?;
+            signed_contents(message)
+        }
+    }
+}
+
+fn parse_cleartext_message(text: &str) -> Result<(Signature, Vec<u8>)> {
+    let (message, _) = CleartextSignedMessage::from_string(text).map_err(SignatureError::Parse)?;
+    let [signature] = message.signatures() else {
+        return Err(SignatureError::BadIssuers(
+            message
+                .signatures()
+                .iter()
+                .flat_map(|signature| signature.issuer())
+                .copied()
+                .collect(),
+        ));
+    };
+    Ok((signature.clone(), message.signed_text().into_bytes()))
+}
 
-    let data = message.as_data_vec()?;
+fn signed_contents(mut message: Message<'_>) -> Result<(Signature, Vec<u8>)> {
+    // gpg compresses by default, wrapping the whole signed message, and
+    // nothing stops a client from wrapping it more than once
+    while message.is_compressed() {
+        message = message.decompress().map_err(SignatureError::Parse)?;
+    }
+
+    let data = message
+        .as_data_vec()
+        .map_err(|error| SignatureError::Parse(error.into()))?;
 
     let signature = if let Message::Signed { reader, .. } = message {
         reader.signature().clone()
     } else if let Message::SignedOnePass { reader, .. } = message {
-        reader
-            .signature()
-            .ok_or(MessageNotSigned)
-            .with_context(|| "Message was SignedOnePass but missing signature packet.")?
-            .clone()
+        // SignedOnePass without a trailing signature packet
+        reader.signature().ok_or(SignatureError::NotSigned)?.clone()
     } else {
-        return Err(MessageNotSigned.into());
+        return Err(SignatureError::NotSigned);
     };
 
     Ok((signature, data))
 }
 
+/// The key id of the one key that made `sig`, going by its Issuer and
+/// Issuer Fingerprint subpackets. Either may be missing (v6 signatures carry
+/// only the fingerprint), but between them they must name exactly one key.
 pub fn message_keyid(sig: &Signature) -> Result<KeyId> {
-    let issuers = sig.issuer();
+    let mut issuers: Vec<KeyId> = sig.issuer().into_iter().copied().collect();
+    issuers.extend(
+        sig.issuer_fingerprint()
+            .into_iter()
+            .filter_map(fingerprint_key_id),
+    );
+    issuers.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    issuers.dedup();
     if let [id] = issuers.as_slice() {
-        Ok((*id).clone())
+        Ok(*id)
     } else {
-        Err(MessageBadIssuers(issuers.into_iter().cloned().collect()).into())
+        Err(SignatureError::BadIssuers(issuers))
+    }
+}
+
+/// The key id a fingerprint implies: its low 64 bits for v4 keys, its high
+/// 64 bits from v5 on.
+fn fingerprint_key_id(fingerprint: &Fingerprint) -> Option<KeyId> {
+    let bytes = fingerprint.as_bytes();
+    let id = match fingerprint {
+        Fingerprint::V4(_) => &bytes[bytes.len() - 8..],
+        Fingerprint::V5(_) | Fingerprint::V6(_) => &bytes[..8],
+        _ => return None,
+    };
+    Some(KeyId::new(id.try_into().ok()?))
+}
+
+pub fn verify_message(signature: &Signature, key: &impl PublicKeyTrait, data: &[u8]) -> Result<()> {
+    if let Some(hash_alg) = signature.hash_alg()
+        && is_weak(hash_alg)
+    {
+        return Err(SignatureError::WeakHash(hash_alg));
+    }
+    signature
+        .verify(key, data)
+        .map_err(SignatureError::Verify)?;
+    if let (Some(created), Some(lifetime)) =
+        (signature.created(), signature.signature_expiration_time())
+        && !lifetime.is_zero()
+        && *created + *lifetime <= Utc::now()
+    {
+        return Err(SignatureError::Expired);
+    }
+    Ok(())
+}
+
+/// As `verify_message`, against the part of `key` the signature names as its
+/// issuer: one of its signing subkeys, or otherwise the primary key. Returns
+/// the fingerprint of the part that signed.
+pub fn verify_signed_by(
+    signature: &Signature,
+    key: &SignedPublicKey,
+    data: &[u8],
+) -> Result<Fingerprint> {
+    let issuer = message_keyid(signature)?;
+    if let Some(subkey) = signing_subkeys(key).find(|subkey| subkey.key.key_id() == issuer) {
+        verify_message(signature, &subkey.key, data)?;
+        return Ok(subkey.key.fingerprint());
     }
+    verify_message(signature, key, data)?;
+    Ok(key.fingerprint())
 }
 
-pub fn verify_message(signature: &Signature, key: &SignedPublicKey, data: &[u8]) -> Result<()> {
-    signature.verify(key, data)?;
+/// How far ahead of the server's clock a signature may be dated, allowing for
+/// clients whose clocks run a little fast.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Rejects a signature dated more than `skew` after `now`. `verify_message`
+/// only checks the cryptography, so callers that care when a message was
+/// made check this as well.
+pub fn check_not_future(signature: &Signature, now: DateTime<Utc>, skew: Duration) -> Result<()> {
+    if let Some(created) = signature.created()
+        && created.timestamp() - now.timestamp() > skew.as_secs() as i64
+    {
+        return Err(SignatureError::FutureDated(*created));
+    }
+    Ok(())
+}
+
+/// A verified message whose signature was made within the freshness window.
+#[derive(Debug)]
+pub struct FreshMessage {
+    /// Names the message by signer, creation time and data, none of which
+    /// can change without breaking the signature, unlike its bytes.
+    pub digest: String,
+    /// After this the message is too old to pass as fresh anyway.
+    pub fresh_until: DateTime<Utc>,
+}
+
+/// As `verify_message`, and the signature's creation time must be within
+/// `window` of `now`. Callers record the returned digest to turn away a
+/// second use of the same message.
+pub fn verify_fresh_message<K: PublicKeyTrait + KeyDetails>(
+    signature: &Signature,
+    key: &K,
+    data: &[u8],
+    now: DateTime<Utc>,
+    window: Duration,
+) -> Result<FreshMessage> {
+    verify_message(signature, key, data)?;
+    check_fresh(signature, now, window)?;
+    fresh_message(signature, &key.fingerprint(), data, window)
+}
+
+/// Rejects a signature whose creation time isn't within `window` of `now`,
+/// before or after.
+pub fn check_fresh(signature: &Signature, now: DateTime<Utc>, window: Duration) -> Result<()> {
+    let created = signature.created().ok_or(SignatureError::Stale)?;
+    if created.timestamp().abs_diff(now.timestamp()) > window.as_secs() {
+        return Err(SignatureError::Stale);
+    }
     Ok(())
 }
 
+/// Names a message already verified as signed by the key with fingerprint
+/// `signer`, as `verify_fresh_message` does, without checking its age.
+pub fn fresh_message(
+    signature: &Signature,
+    signer: &Fingerprint,
+    data: &[u8],
+    window: Duration,
+) -> Result<FreshMessage> {
+    let created = *signature.created().ok_or(SignatureError::Stale)?;
+    let mut digest = Sha256::new();
+    digest.update(signer.to_string());
+    digest.update(created.timestamp().to_be_bytes());
+    digest.update(data);
+    Ok(FreshMessage {
+        digest: hex::encode(digest.finalize()),
+        fresh_until: created + window,
+    })
+}
+
+/// Reads a detached signature, as `gpg --detach-sign` makes, armored or not,
+/// and pairs it with the data it's over, the way `parse_message` returns an
+/// embedded one. Checking it is left to `verify_message` as usual.
+pub fn parse_detached(signature: &[u8], data: &[u8]) -> Result<(Signature, Vec<u8>)> {
+    let armored = std::str::from_utf8(signature).is_ok_and(|text| {
+        text.trim_start()
+            .starts_with("-----BEGIN PGP SIGNATURE-----")
+    });
+    let signature = if armored {
+        DetachedSignature::from_armor_single(signature).map(|(signature, _)| signature)
+    } else {
+        DetachedSignature::from_bytes(signature)
+    }
+    .map_err(SignatureError::Parse)?;
+    Ok((signature.signature, data.to_vec()))
+}
+
+/// Signs a message with a throwaway key and checks it the way requests are
+/// checked, to catch a broken crypto build before serving anything.
+pub fn self_test() -> anyhow::Result<()> {
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::Ed25519Legacy)
+        .can_sign(true)
+        .primary_user_id("self-test".into())
+        .build()?;
+    let key = params
+        .generate(thread_rng())?
+        .sign(thread_rng(), &Password::empty())?;
+
+    let plaintext = b"md-pgp-server self-test";
+    let mut builder = MessageBuilder::from_bytes("", plaintext.to_vec());
+    builder.sign(&key.primary_key, Password::empty(), HashAlgorithm::Sha256);
+    let message = builder.to_vec(thread_rng())?;
+
+    let (signature, data) = parse_message(&message)?;
+    anyhow::ensure!(
+        message_keyid(&signature)? == key.key_id(),
+        "signature names the wrong issuer"
+    );
+    anyhow::ensure!(data == plaintext, "signed data did not round-trip");
+    verify_message(&signature, &key.signed_public_key(), &data)?;
+    Ok(())
+}
+
+fn is_weak(hash_alg: HashAlgorithm) -> bool {
+    matches!(
+        hash_alg,
+        HashAlgorithm::Md5 | HashAlgorithm::Sha1 | HashAlgorithm::Ripemd160
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    use anyhow::{Context, Result};
     use pgp::types::KeyDetails;
     use rand::thread_rng;
 
+    use chrono::SubsecRound;
     use pgp::composed::{Deserializable, MessageBuilder, SignedPublicKey, SignedSecretKey};
-    use pgp::crypto::hash::HashAlgorithm;
-    use pgp::types::Password;
+    use pgp::packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData};
+    use pgp::types::{CompressionAlgorithm, Password};
     use std::{fs, io::Cursor, path::Path};
 
     use super::*;
@@ -101,4 +340,219 @@ mod tests {
         assert_eq!(data, plaintext);
         Ok(())
     }
+
+    fn test_keys() -> (SignedSecretKey, SignedPublicKey) {
+        (
+            read_skey_file("test_secret.asc").unwrap(),
+            read_pkey_file("test.asc").unwrap(),
+        )
+    }
+
+    fn sign_bytes(skey: &SignedSecretKey, data: &[u8]) -> Vec<u8> {
+        let mut builder = MessageBuilder::from_bytes("", data.to_vec());
+        builder.sign(&skey.primary_key, Password::empty(), HashAlgorithm::Sha256);
+        builder.to_vec(thread_rng()).unwrap()
+    }
+
+    /// Signs `data` with exactly the given hashed subpackets and no issuer.
+    fn raw_signature(skey: &SignedSecretKey, hashed: Vec<SubpacketData>, data: &[u8]) -> Signature {
+        let mut config = SignatureConfig::v4(
+            SignatureType::Binary,
+            skey.primary_key.algorithm(),
+            HashAlgorithm::Sha256,
+        );
+        config.hashed_subpackets = hashed
+            .into_iter()
+            .map(|data| Subpacket::regular(data).unwrap())
+            .collect();
+        config
+            .sign(&skey.primary_key, &Password::empty(), data)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_self_test_passes() {
+        self_test().unwrap();
+    }
+
+    #[test]
+    fn test_signature_errors() {
+        let (skey, pkey) = test_keys();
+
+        let unsigned = MessageBuilder::from_bytes("", b"hello".to_vec())
+            .to_vec(thread_rng())
+            .unwrap();
+        assert!(matches!(
+            parse_message(&unsigned),
+            Err(SignatureError::NotSigned)
+        ));
+        assert!(matches!(
+            parse_message(b"definitely not pgp"),
+            Err(SignatureError::Parse(_))
+        ));
+
+        let (signature, _) = parse_message(&sign_bytes(&skey, b"hello")).unwrap();
+        assert!(matches!(
+            verify_message(&signature, &pkey, b"tampered"),
+            Err(SignatureError::Verify(_))
+        ));
+
+        // `gpg --digest-algo SHA1 --sign` with an RSA key; ed25519 won't sign SHA-1
+        let (signature, data) = parse_message(&fs::read("test_sha1.gpg").unwrap()).unwrap();
+        assert!(matches!(
+            verify_message(&signature, &pkey, &data),
+            Err(SignatureError::WeakHash(HashAlgorithm::Sha1))
+        ));
+
+        let created = SubpacketData::SignatureCreationTime(Utc::now().trunc_subsecs(0));
+        let signature = raw_signature(&skey, vec![created], b"hello");
+        assert!(matches!(
+            message_keyid(&signature),
+            Err(SignatureError::BadIssuers(issuers)) if issuers.is_empty()
+        ));
+
+        let created = Utc::now().trunc_subsecs(0) - chrono::Duration::hours(1);
+        let signature = raw_signature(
+            &skey,
+            vec![
+                SubpacketData::SignatureCreationTime(created),
+                SubpacketData::SignatureExpirationTime(chrono::Duration::seconds(60)),
+            ],
+            b"hello",
+        );
+        assert!(matches!(
+            verify_message(&signature, &pkey, b"hello"),
+            Err(SignatureError::Expired)
+        ));
+
+        let window = Duration::from_secs(5 * 60);
+        let now = Utc::now();
+        let (signature, data) = parse_message(&sign_bytes(&skey, b"hello")).unwrap();
+        let fresh = verify_fresh_message(&signature, &pkey, &data, now, window).unwrap();
+        let again = verify_fresh_message(&signature, &pkey, &data, now, window).unwrap();
+        assert_eq!(fresh.digest, again.digest);
+        assert!(matches!(
+            verify_fresh_message(&signature, &pkey, &data, now + window * 2, window),
+            Err(SignatureError::Stale)
+        ));
+        assert!(matches!(
+            verify_fresh_message(&signature, &pkey, &data, now - window * 2, window),
+            Err(SignatureError::Stale)
+        ));
+    }
+
+    #[test]
+    fn test_future_dated_signature() {
+        let (skey, pkey) = test_keys();
+        let now = Utc::now().trunc_subsecs(0);
+        let dated = |created| {
+            raw_signature(
+                &skey,
+                vec![SubpacketData::SignatureCreationTime(created)],
+                b"hello",
+            )
+        };
+
+        let forged = dated(now + chrono::Duration::hours(1));
+        // the signature itself is sound, only its date gives it away
+        verify_message(&forged, &pkey, b"hello").unwrap();
+        assert!(matches!(
+            check_not_future(&forged, now, MAX_CLOCK_SKEW),
+            Err(SignatureError::FutureDated(created)) if created == now + chrono::Duration::hours(1)
+        ));
+
+        let fast_clock = dated(now + chrono::Duration::minutes(4));
+        check_not_future(&fast_clock, now, MAX_CLOCK_SKEW).unwrap();
+        check_not_future(&dated(now - chrono::Duration::days(1)), now, MAX_CLOCK_SKEW).unwrap();
+    }
+
+    #[test]
+    fn test_parse_compressed() {
+        let (skey, pkey) = test_keys();
+        let plaintext = b"squeezed before it was sent";
+        for algorithm in [CompressionAlgorithm::ZIP, CompressionAlgorithm::ZLIB] {
+            let mut builder = MessageBuilder::from_bytes("", plaintext.to_vec());
+            builder.compression(algorithm);
+            builder.sign(&skey.primary_key, Password::empty(), HashAlgorithm::Sha256);
+            let message = builder.to_vec(thread_rng()).unwrap();
+
+            let (signature, data) = parse_message(&message).unwrap();
+            assert_eq!(data, plaintext);
+            assert_eq!(message_keyid(&signature).unwrap(), skey.key_id());
+            verify_message(&signature, &pkey, &data).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_detached() {
+        let (skey, pkey) = test_keys();
+        let data = b"a payload sent alongside its signature";
+        let signature = DetachedSignature::sign_binary_data(
+            thread_rng(),
+            &skey.primary_key,
+            &Password::empty(),
+            HashAlgorithm::Sha256,
+            &data[..],
+        )
+        .unwrap();
+        let armored = signature.to_armored_bytes(Default::default()).unwrap();
+        let binary = pgp::ser::Serialize::to_bytes(&signature).unwrap();
+
+        for encoded in [armored, binary] {
+            let (signature, parsed) = parse_detached(&encoded, data).unwrap();
+            assert_eq!(parsed, data);
+            assert_eq!(message_keyid(&signature).unwrap(), skey.key_id());
+            verify_message(&signature, &pkey, &parsed).unwrap();
+            assert!(matches!(
+                verify_message(&signature, &pkey, b"something else"),
+                Err(SignatureError::Verify(_))
+            ));
+        }
+        assert!(matches!(
+            parse_detached(b"not a signature", data),
+            Err(SignatureError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_issuer_from_fingerprint() {
+        let params = SecretKeyParamsBuilder::default()
+            .version(pgp::types::KeyVersion::V6)
+            .key_type(KeyType::Ed25519)
+            .can_sign(true)
+            .primary_user_id("v6 <v6@example.com>".into())
+            .build()
+            .unwrap();
+        let v6 = params
+            .generate(thread_rng())
+            .unwrap()
+            .sign(thread_rng(), &Password::empty())
+            .unwrap();
+        let (signature, data) = parse_message(&sign_bytes(&v6, b"hello")).unwrap();
+        assert!(signature.issuer().is_empty());
+        assert_eq!(message_keyid(&signature).unwrap(), v6.key_id());
+        verify_message(&signature, &v6.signed_public_key(), &data).unwrap();
+
+        // a v4 signature naming its key only by fingerprint
+        let (skey, _) = test_keys();
+        let created = SubpacketData::SignatureCreationTime(Utc::now().trunc_subsecs(0));
+        let fingerprint = SubpacketData::IssuerFingerprint(skey.fingerprint());
+        let signature = raw_signature(&skey, vec![created.clone(), fingerprint.clone()], b"hi");
+        assert_eq!(message_keyid(&signature).unwrap(), skey.key_id());
+
+        // agreeing subpackets are one issuer, disagreeing ones are ambiguous
+        let issuer = SubpacketData::Issuer(skey.key_id());
+        let signature = raw_signature(
+            &skey,
+            vec![created.clone(), issuer, fingerprint.clone()],
+            b"hi",
+        );
+        assert_eq!(message_keyid(&signature).unwrap(), skey.key_id());
+        let other = SubpacketData::Issuer(v6.key_id());
+        let signature = raw_signature(&skey, vec![created, other, fingerprint], b"hi");
+        assert!(matches!(
+            message_keyid(&signature),
+            Err(SignatureError::BadIssuers(issuers)) if issuers.len() == 2
+        ));
+    }
 }
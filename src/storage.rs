@@ -0,0 +1,251 @@
+use axum::http::StatusCode;
+use pgp::{composed::SignedPublicKey, types::KeyId};
+use sqlx::SqlitePool;
+use std::future::Future;
+use uuid::Uuid;
+
+use crate::{
+    NewDocument, SharePermission,
+    get_documents::{self, DocumentSummary, ListingOrder},
+    signature::FreshMessage,
+};
+
+/// Where accounts, documents and shares are kept. Handlers reach them through
+/// `AppState::storage` rather than the pool, so another database only needs
+/// another implementation of this.
+pub trait Storage: Clone + Send + Sync + 'static {
+    /// Registers `key`, using up `message`, the signed upload it came in.
+    fn insert_user(
+        &self,
+        key: &SignedPublicKey,
+        message: Option<&FreshMessage>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// The key registered under `key_id`, if any.
+    fn get_user_key(
+        &self,
+        key_id: &KeyId,
+    ) -> impl Future<Output = anyhow::Result<Option<SignedPublicKey>>> + Send;
+
+    /// Creates a document shared with `share_with`. Returns its id and the
+    /// recipients skipped for not having an account.
+    fn create_document(
+        &self,
+        owner_key_id: &KeyId,
+        document: NewDocument<'_>,
+        share_with: &[KeyId],
+        permission: SharePermission,
+        max_shares: u32,
+        max_documents: u32,
+    ) -> impl Future<Output = anyhow::Result<(Uuid, Vec<KeyId>)>> + Send;
+
+    /// Documents `key_id` owns, a page at a time.
+    fn get_user_docs(
+        &self,
+        key_id: &KeyId,
+        after: Option<Uuid>,
+        limit: u32,
+        offset: u32,
+        ordering: ListingOrder,
+        search: Option<&str>,
+    ) -> impl Future<Output = anyhow::Result<Vec<DocumentSummary>>> + Send;
+
+    /// Documents shared with `key_id`, a page at a time.
+    fn get_shared_docs(
+        &self,
+        key_id: &KeyId,
+        after: Option<Uuid>,
+        limit: u32,
+        include_owner_key: bool,
+    ) -> impl Future<Output = anyhow::Result<Vec<DocumentSummary>>> + Send;
+
+    /// Shares a document the caller owns, or updates an existing share.
+    fn share_document(
+        &self,
+        doc_id: &Uuid,
+        owner_key_id: &KeyId,
+        user_key_id: &KeyId,
+        permission: SharePermission,
+        max_shares: u32,
+    ) -> impl Future<Output = Result<(), (StatusCode, String)>> + Send;
+
+    /// Revokes a share, if there is one.
+    fn unshare_document(
+        &self,
+        doc_id: &Uuid,
+        owner_key_id: &KeyId,
+        user_key_id: &KeyId,
+    ) -> impl Future<Output = Result<(), (StatusCode, String)>> + Send;
+}
+
+/// The SQLite database everything else in the server also uses.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteStorage { pool }
+    }
+}
+
+impl Storage for SqliteStorage {
+    async fn insert_user(
+        &self,
+        key: &SignedPublicKey,
+        message: Option<&FreshMessage>,
+    ) -> anyhow::Result<()> {
+        crate::insert_user(&self.pool, key, message).await
+    }
+
+    async fn get_user_key(&self, key_id: &KeyId) -> anyhow::Result<Option<SignedPublicKey>> {
+        crate::get_user_key(&self.pool, key_id).await
+    }
+
+    async fn create_document(
+        &self,
+        owner_key_id: &KeyId,
+        document: NewDocument<'_>,
+        share_with: &[KeyId],
+        permission: SharePermission,
+        max_shares: u32,
+        max_documents: u32,
+    ) -> anyhow::Result<(Uuid, Vec<KeyId>)> {
+        crate::create_shared_document(
+            &self.pool,
+            owner_key_id,
+            document,
+            share_with,
+            permission,
+            max_shares,
+            max_documents,
+        )
+        .await
+    }
+
+    async fn get_user_docs(
+        &self,
+        key_id: &KeyId,
+        after: Option<Uuid>,
+        limit: u32,
+        offset: u32,
+        ordering: ListingOrder,
+        search: Option<&str>,
+    ) -> anyhow::Result<Vec<DocumentSummary>> {
+        get_documents::get_user_docs(&self.pool, key_id, after, limit, offset, ordering, search)
+            .await
+    }
+
+    async fn get_shared_docs(
+        &self,
+        key_id: &KeyId,
+        after: Option<Uuid>,
+        limit: u32,
+        include_owner_key: bool,
+    ) -> anyhow::Result<Vec<DocumentSummary>> {
+        get_documents::get_shared_docs(&self.pool, key_id, after, limit, include_owner_key).await
+    }
+
+    async fn share_document(
+        &self,
+        doc_id: &Uuid,
+        owner_key_id: &KeyId,
+        user_key_id: &KeyId,
+        permission: SharePermission,
+        max_shares: u32,
+    ) -> Result<(), (StatusCode, String)> {
+        crate::share_document(
+            &self.pool,
+            doc_id,
+            owner_key_id,
+            user_key_id,
+            permission,
+            max_shares,
+        )
+        .await
+    }
+
+    async fn unshare_document(
+        &self,
+        doc_id: &Uuid,
+        owner_key_id: &KeyId,
+        user_key_id: &KeyId,
+    ) -> Result<(), (StatusCode, String)> {
+        crate::unshare_document(&self.pool, doc_id, owner_key_id, user_key_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pgp::types::KeyDetails;
+
+    use super::*;
+    use crate::{
+        init_db,
+        test_util::{generate_key, memory_pool},
+    };
+
+    /// Shares and unshares through nothing but the trait, as handlers do.
+    async fn share_round_trip(storage: &impl Storage) {
+        let alice = generate_key("alice <alice@example.com>").signed_public_key();
+        let bob = generate_key("bob <bob@example.com>").signed_public_key();
+        storage.insert_user(&alice, None).await.unwrap();
+        storage.insert_user(&bob, None).await.unwrap();
+        assert!(storage.get_user_key(&bob.key_id()).await.unwrap().is_some());
+
+        let document = NewDocument {
+            name: "notes",
+            client_ref: None,
+            require_signed_content: false,
+        };
+        let (doc_id, skipped) = storage
+            .create_document(
+                &alice.key_id(),
+                document,
+                &[],
+                SharePermission::Read,
+                10,
+                10,
+            )
+            .await
+            .unwrap();
+        assert!(skipped.is_empty());
+        let owned = storage
+            .get_user_docs(&alice.key_id(), None, 10, 0, ListingOrder::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(owned[0].doc_id, doc_id);
+
+        let shared = async || {
+            storage
+                .get_shared_docs(&bob.key_id(), None, 10, false)
+                .await
+                .unwrap()
+        };
+        assert!(shared().await.is_empty());
+        storage
+            .share_document(
+                &doc_id,
+                &alice.key_id(),
+                &bob.key_id(),
+                SharePermission::Read,
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(shared().await[0].name, "notes");
+        storage
+            .unshare_document(&doc_id, &alice.key_id(), &bob.key_id())
+            .await
+            .unwrap();
+        assert!(shared().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        let pool = memory_pool().await;
+        init_db(&pool).await.unwrap();
+        share_round_trip(&SqliteStorage::new(pool)).await;
+    }
+}
@@ -0,0 +1,243 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::SqlitePool;
+use std::{future::Future, pin::pin};
+use uuid::Uuid;
+
+use crate::{config::Config, nonce, purge_document, sync};
+
+/// What one sweep removed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Swept {
+    pub documents: u64,
+    pub shares: u64,
+    pub tombstones: u64,
+    pub nonces: u64,
+}
+
+/// Removes everything past its expiry: documents and shares with an
+/// `expires_at`, tombstones older than `sync_retention`, and unused nonces.
+/// Rows go `sweep_batch_size` at a time, each batch in its own transaction,
+/// so a large backlog never holds the write lock for long.
+pub async fn sweep(pool: &SqlitePool, config: &Config, now: DateTime<Utc>) -> sqlx::Result<Swept> {
+    let batch_size = config.sweep_batch_size;
+    Ok(Swept {
+        documents: expire_documents(pool, now, batch_size).await?,
+        shares: expire_shares(pool, now, batch_size).await?,
+        tombstones: sync::purge_tombstones(pool, now - config.sync_retention, batch_size).await?,
+        nonces: nonce::purge_expired(pool, now, batch_size).await?,
+    })
+}
+
+/// Sweeps every `sweep_interval` until `stop` completes. A sweep already
+/// under way finishes its current batch first.
+pub async fn sweep_periodically(pool: SqlitePool, config: Config, stop: impl Future<Output = ()>) {
+    let mut interval = tokio::time::interval(config.sweep_interval);
+    let mut stop = pin!(stop);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop => return,
+        }
+        tokio::select! {
+            result = sweep(&pool, &config, Utc::now()) => match result {
+                Ok(swept) if swept != Swept::default() => tracing::info!(
+                    documents = swept.documents,
+                    shares = swept.shares,
+                    tombstones = swept.tombstones,
+                    nonces = swept.nonces,
+                    "swept expired rows"
+                ),
+                Ok(_) => {}
+                Err(error) => tracing::error!(%error, "sweep failed"),
+            },
+            _ = &mut stop => return,
+        }
+    }
+}
+
+/// Deletes rows of `table` matching `condition`, which binds `value` once,
+/// `batch_size` at a time. Returns how many went.
+pub async fn delete_in_batches(
+    pool: &SqlitePool,
+    table: &str,
+    condition: &str,
+    value: &str,
+    batch_size: u32,
+) -> sqlx::Result<u64> {
+    let sql = format!(
+        "delete from {table} where rowid in (select rowid from {table} where {condition} limit ?)"
+    );
+    let mut deleted = 0;
+    loop {
+        let batch = sqlx::query(&sql)
+            .bind(value)
+            .bind(batch_size)
+            .execute(pool)
+            .await?
+            .rows_affected();
+        deleted += batch;
+        if batch < u64::from(batch_size) {
+            return Ok(deleted);
+        }
+    }
+}
+
+async fn expire_documents(
+    pool: &SqlitePool,
+    now: DateTime<Utc>,
+    batch_size: u32,
+) -> sqlx::Result<u64> {
+    let now = timestamp(now);
+    let mut expired = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let doc_ids: Vec<String> = sqlx::query_scalar(
+            r#"select doc_id from documents where expires_at <= ? order by rowid limit ?"#,
+        )
+        .bind(&now)
+        .bind(batch_size)
+        .fetch_all(&mut *tx)
+        .await?;
+        for doc_id in &doc_ids {
+            let doc_id =
+                Uuid::parse_str(doc_id).map_err(|error| sqlx::Error::Decode(error.into()))?;
+            purge_document(&mut tx, &doc_id).await?;
+        }
+        tx.commit().await?;
+        expired += doc_ids.len() as u64;
+        if doc_ids.len() < batch_size as usize {
+            return Ok(expired);
+        }
+    }
+}
+
+/// Expired shares leave a tombstone for the sharee, as if the document had
+/// been deleted for them.
+async fn expire_shares(
+    pool: &SqlitePool,
+    now: DateTime<Utc>,
+    batch_size: u32,
+) -> sqlx::Result<u64> {
+    let now = timestamp(now);
+    let mut expired = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"insert or replace into tombstones (doc_id, user_id, deleted_at)
+            select doc_id, user_id, ?1 from document_shares
+            where expires_at <= ?1 order by rowid limit ?2"#,
+        )
+        .bind(&now)
+        .bind(batch_size)
+        .execute(&mut *tx)
+        .await?;
+        let batch = sqlx::query(
+            r#"delete from document_shares where rowid in (
+                select rowid from document_shares where expires_at <= ?1 order by rowid limit ?2
+            )"#,
+        )
+        .bind(&now)
+        .bind(batch_size)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        expired += batch;
+        if batch < u64::from(batch_size) {
+            return Ok(expired);
+        }
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use pgp::types::KeyDetails;
+    use sqlx::Row;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        SharePermission, create_document, key_id_to_text, share_document,
+        test_util::{generate_key, register, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_sweep_removes_only_expired_rows() {
+        let config = Config {
+            sweep_batch_size: 1,
+            ..Config::default()
+        };
+        let (_app, pool) = test_app(config.clone()).await;
+        let owner = generate_key("owner <owner@example.com>");
+        let reader = generate_key("reader <reader@example.com>");
+        register(&pool, &owner).await;
+        register(&pool, &reader).await;
+
+        let now = Utc::now();
+        let past = timestamp(now - Duration::from_secs(60));
+        let future = timestamp(now + Duration::from_secs(60));
+        let mut docs = Vec::new();
+        for (name, expires_at) in [
+            ("expired", Some(&past)),
+            ("also expired", Some(&past)),
+            ("live", Some(&future)),
+            ("forever", None),
+        ] {
+            let doc_id = create_document(&pool, &owner.key_id(), name, None).await;
+            share_document(
+                &pool,
+                &doc_id,
+                &owner.key_id(),
+                &reader.key_id(),
+                SharePermission::Read,
+                10,
+            )
+            .await
+            .unwrap();
+            sqlx::query("update documents set expires_at = ? where doc_id = ?")
+                .bind(expires_at)
+                .bind(doc_id.to_string())
+                .execute(&pool)
+                .await
+                .unwrap();
+            docs.push(doc_id);
+        }
+        // the share of "forever" lapses even though the document doesn't
+        sqlx::query("update document_shares set expires_at = ? where doc_id = ?")
+            .bind(&past)
+            .bind(docs[3].to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let swept = sweep(&pool, &config, now).await.unwrap();
+        assert_eq!(swept.documents, 2);
+        assert_eq!(swept.shares, 1);
+
+        let remaining: Vec<String> = sqlx::query_scalar("select name from documents order by name")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, ["forever", "live"]);
+        let shared: Vec<String> = sqlx::query_scalar("select doc_id from document_shares")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(shared, [docs[2].to_string()]);
+        let tombstone = sqlx::query("select user_id from tombstones where doc_id = ?")
+            .bind(docs[3].to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            tombstone.get::<String, _>("user_id"),
+            key_id_to_text(&reader.key_id())
+        );
+
+        assert_eq!(sweep(&pool, &config, now).await.unwrap().documents, 0);
+    }
+}
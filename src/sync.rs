@@ -0,0 +1,173 @@
+use axum::{Json, extract::State};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{Row, SqliteExecutor, SqlitePool};
+use std::time::Duration;
+use uuid::Uuid;
+
+use chrono::{DateTime, Utc};
+use pgp::types::KeyId;
+
+use crate::{
+    AppError, AppState, auth::SignedRequest, internal_error, key_id_to_text, now_timestamp, sweeper,
+};
+
+/// Remembers that `doc_id` went away for everyone who could see it, so
+/// mirroring clients learn to drop their copies on the next `/sync`.
+pub async fn record_tombstones(conn: impl SqliteExecutor<'_>, doc_id: &Uuid) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"insert or replace into tombstones (doc_id, user_id, deleted_at)
+        select doc_id, user_id, ?1 from document_owners where doc_id = ?2
+        union
+        select doc_id, user_id, ?1 from document_shares where doc_id = ?2"#,
+    )
+    .bind(now_timestamp())
+    .bind(doc_id.to_string())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Remembers that `doc_id` went away for `user_id` alone, as when a share is
+/// revoked.
+pub async fn record_tombstone(
+    conn: impl SqliteExecutor<'_>,
+    doc_id: &Uuid,
+    user_id: &KeyId,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"insert or replace into tombstones (doc_id, user_id, deleted_at) values (?, ?, ?)"#,
+    )
+    .bind(doc_id.to_string())
+    .bind(key_id_to_text(user_id))
+    .bind(now_timestamp())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Forgets tombstones from before `horizon`, the start of the retention
+/// window.
+pub async fn purge_tombstones(
+    pool: &SqlitePool,
+    horizon: DateTime<Utc>,
+    batch_size: u32,
+) -> sqlx::Result<u64> {
+    let horizon = horizon.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    sweeper::delete_in_batches(pool, "tombstones", "deleted_at < ?", &horizon, batch_size).await
+}
+
+fn retention_horizon(retention: Duration) -> String {
+    let horizon = chrono::Utc::now() - retention;
+    horizon.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[derive(Deserialize)]
+pub struct SyncRequest {
+    /// The `server_time` from the previous sync response.
+    since: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Tombstone {
+    doc_id: String,
+    deleted_at: String,
+}
+
+pub async fn handle_sync(
+    State(state): State<AppState>,
+    request: SignedRequest<SyncRequest>,
+) -> Result<Json<Value>, AppError> {
+    let server_time = now_timestamp();
+    let since = request.payload.since.unwrap_or_default();
+    // tombstones before the horizon may already be gone
+    let full_resync = since < retention_horizon(state.config.sync_retention);
+
+    let tombstones: Vec<Tombstone> = sqlx::query(
+        r#"select doc_id, deleted_at from tombstones
+        where user_id = ? and deleted_at > ?
+        order by deleted_at"#,
+    )
+    .bind(key_id_to_text(&request.key_id))
+    .bind(&since)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .map(|row| Tombstone {
+        doc_id: row.get("doc_id"),
+        deleted_at: row.get("deleted_at"),
+    })
+    .collect();
+
+    Ok(Json(json!({
+        "server_time": server_time,
+        "full_resync": full_resync,
+        "tombstones": tombstones,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use pgp::types::KeyDetails;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        SharePermission,
+        config::Config,
+        create_document, share_document,
+        test_util::{generate_key, post, register, sign_json, test_app},
+    };
+
+    #[tokio::test]
+    async fn test_deleted_document_appears_in_sync() {
+        let (app, pool) = test_app(Config::default()).await;
+        let alice = generate_key("alice <alice@example.com>");
+        let bob = generate_key("bob <bob@example.com>");
+        register(&pool, &alice).await;
+        register(&pool, &bob).await;
+        let doc_id = create_document(&pool, &alice.key_id(), "old", None).await;
+        share_document(
+            &pool,
+            &doc_id,
+            &alice.key_id(),
+            &bob.key_id(),
+            SharePermission::Read,
+            10,
+        )
+        .await
+        .unwrap();
+
+        let (_, body) = post(&app, "/sync", sign_json(&bob, json!({}))).await;
+        let first: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(first["tombstones"], json!([]));
+        assert_eq!(first["full_resync"], true);
+
+        let delete = sign_json(&alice, json!({ "doc_id": doc_id }));
+        let (status, _) = post(&app, "/documents/delete", delete).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let since = first["server_time"].clone();
+        let (status, body) = post(&app, "/sync", sign_json(&bob, json!({ "since": since }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let second: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(second["full_resync"], false);
+        assert_eq!(second["tombstones"][0]["doc_id"], doc_id.to_string());
+
+        // nothing new since the second sync
+        let since = second["server_time"].clone();
+        let (_, body) = post(&app, "/sync", sign_json(&bob, json!({ "since": since }))).await;
+        let third: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(third["tombstones"], json!([]));
+
+        assert_eq!(
+            purge_tombstones(&pool, Utc::now() - Duration::from_secs(3600), 100)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(purge_tombstones(&pool, Utc::now(), 1).await.unwrap(), 2);
+    }
+}
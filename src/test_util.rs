@@ -0,0 +1,183 @@
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    http::{Request, StatusCode},
+};
+use pgp::{
+    composed::{KeyType, MessageBuilder, SecretKeyParamsBuilder, SignedSecretKey},
+    crypto::hash::HashAlgorithm,
+    ser::Serialize,
+    types::{KeyDetails, KeyId, Password},
+};
+use rand::thread_rng;
+use serde_json::{Value, json};
+use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::{AppState, config::Config, init_db, insert_user_rows, key_id_to_text};
+
+/// A private in-memory database. Limited to one connection because every
+/// `:memory:` connection would otherwise get its own empty database.
+pub async fn memory_pool() -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+/// An initialized in-memory database behind the full router.
+pub async fn test_app(config: Config) -> (Router, SqlitePool) {
+    let pool = memory_pool().await;
+    init_db(&pool).await.unwrap();
+    let app = crate::app(AppState::new(pool.clone(), config));
+    (app, pool)
+}
+
+pub fn generate_key(user_id: &str) -> SignedSecretKey {
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::Ed25519Legacy)
+        .can_certify(true)
+        .can_sign(true)
+        .primary_user_id(user_id.into())
+        .build()
+        .unwrap();
+    params
+        .generate(thread_rng())
+        .unwrap()
+        .sign(thread_rng(), &Password::empty())
+        .unwrap()
+}
+
+/// Adds an account straight to the database, without the audit entry a real
+/// signup leaves.
+pub async fn register(pool: &SqlitePool, skey: &SignedSecretKey) {
+    let mut tx = pool.begin().await.unwrap();
+    insert_user_rows(&mut tx, &skey.signed_public_key())
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+}
+
+pub fn sign(skey: &SignedSecretKey, plaintext: &[u8]) -> Vec<u8> {
+    let mut builder = MessageBuilder::from_bytes("", plaintext.to_vec());
+    builder.sign(&skey.primary_key, Password::empty(), HashAlgorithm::Sha256);
+    builder.to_vec(thread_rng()).unwrap()
+}
+
+/// Signs `payload` with a current `timestamp` added, as `SignedRequest` expects.
+pub fn sign_json(skey: &SignedSecretKey, mut payload: Value) -> Vec<u8> {
+    payload["timestamp"] = chrono::Utc::now().timestamp().into();
+    sign(skey, payload.to_string().as_bytes())
+}
+
+/// A nonce from `/nonce`, for a single-use request that repeats an earlier one.
+pub async fn fresh_nonce(app: &Router) -> String {
+    let (status, body) = get(app, "/nonce").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let issued: Value = serde_json::from_str(&body).unwrap();
+    issued["nonce"].as_str().unwrap().to_string()
+}
+
+pub async fn post(app: &Router, uri: &str, body: Vec<u8>) -> (StatusCode, String) {
+    send(app, Request::post(uri).body(Body::from(body)).unwrap()).await
+}
+
+pub async fn get(app: &Router, uri: &str) -> (StatusCode, String) {
+    send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// The `error` of a JSON error response.
+pub fn error_message(body: &str) -> String {
+    let body: Value = serde_json::from_str(body).unwrap();
+    body["error"].as_str().unwrap().to_string()
+}
+
+pub async fn send(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// One user talking to the server through the signed-request protocol.
+pub struct TestClient {
+    pub app: Router,
+    pub key: SignedSecretKey,
+}
+
+impl TestClient {
+    pub fn new(app: &Router, user_id: &str) -> Self {
+        TestClient {
+            app: app.clone(),
+            key: generate_key(user_id),
+        }
+    }
+
+    pub fn key_id(&self) -> KeyId {
+        self.key.key_id()
+    }
+
+    /// Posts `payload` to `uri`, signed and timestamped.
+    pub async fn post(&self, uri: &str, payload: Value) -> (StatusCode, String) {
+        post(&self.app, uri, sign_json(&self.key, payload)).await
+    }
+
+    /// Like `post`, for endpoints that answer with JSON.
+    pub async fn post_json(&self, uri: &str, payload: Value) -> Value {
+        let (status, body) = self.post(uri, payload).await;
+        assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    pub async fn create_account(&self) -> StatusCode {
+        let public_key = self.key.signed_public_key().to_bytes().unwrap();
+        post(&self.app, "/create_account", sign(&self.key, &public_key))
+            .await
+            .0
+    }
+
+    pub async fn create_document(&self, name: &str) -> Uuid {
+        let (status, body) = self.post("/create_document", json!({ "name": name })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        Uuid::parse_str(&body).unwrap()
+    }
+
+    /// Creates a document shared with `recipients` from the start.
+    pub async fn create_shared_document(&self, name: &str, recipients: &[&TestClient]) -> Uuid {
+        let share_with: Vec<String> = recipients
+            .iter()
+            .map(|recipient| key_id_to_text(&recipient.key_id()))
+            .collect();
+        let created = self
+            .post_json(
+                "/create_document",
+                json!({ "name": name, "share_with": share_with }),
+            )
+            .await;
+        Uuid::parse_str(created["doc_id"].as_str().unwrap()).unwrap()
+    }
+
+    pub async fn upload_content(&self, doc_id: Uuid, content: &str) -> StatusCode {
+        self.post(
+            "/documents/content/upload",
+            json!({ "doc_id": doc_id, "content": content }),
+        )
+        .await
+        .0
+    }
+
+    pub async fn download_content(&self, doc_id: Uuid) -> (StatusCode, String) {
+        self.post("/documents/content", json!({ "doc_id": doc_id }))
+            .await
+    }
+
+    pub async fn list_documents(&self) -> Value {
+        self.post_json("/documents", json!({})).await
+    }
+
+    pub async fn access(&self) -> Value {
+        self.post_json("/access", json!({})).await
+    }
+}
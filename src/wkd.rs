@@ -0,0 +1,204 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use pgp::ser::Serialize as _;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{AppError, AppState, get_documents::primary_user_id, internal_error, parse_stored_key};
+
+/// How a registered user can be found by address. `email` keeps the local
+/// part as given; only the domain, which is never case-sensitive, is
+/// lowercased.
+#[derive(Debug)]
+pub struct EmailIndex {
+    pub email: String,
+    pub domain: String,
+    pub wkd_hash: String,
+}
+
+impl EmailIndex {
+    /// Indexes the address in a user id like `Name <local@Domain>` or a bare
+    /// `local@Domain`.
+    pub fn from_user_id(user_id: &str) -> Option<Self> {
+        let address = match (user_id.rfind('<'), user_id.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &user_id[start + 1..end],
+            _ => user_id,
+        };
+        let (local, domain) = address.trim().rsplit_once('@')?;
+        if local.is_empty() || domain.is_empty() {
+            return None;
+        }
+        let domain = domain.to_lowercase();
+        Some(EmailIndex {
+            email: format!("{local}@{domain}"),
+            wkd_hash: wkd_hash(local),
+            domain,
+        })
+    }
+}
+
+/// The Web Key Directory hash of a local part: z-base-32 of its SHA-1, taken
+/// after lowercasing as the spec requires.
+pub fn wkd_hash(local: &str) -> String {
+    zbase32(&Sha1::digest(local.to_lowercase().as_bytes()))
+}
+
+fn zbase32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+#[derive(Deserialize)]
+pub struct LookupEmail {
+    email: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FoundUser {
+    key_id: String,
+    user_id: Option<String>,
+}
+
+/// Finds registered keys by email address. The domain always matches
+/// case-insensitively; the local part does too when
+/// `lowercase_email_local_part` is set.
+pub async fn handle_lookup_email(
+    State(state): State<AppState>,
+    Query(query): Query<LookupEmail>,
+) -> Result<Json<Vec<FoundUser>>, AppError> {
+    let Some(index) = EmailIndex::from_user_id(&query.email) else {
+        return Err(AppError::Status(
+            StatusCode::BAD_REQUEST,
+            "not an email address".to_string(),
+        ));
+    };
+    let sql = if state.config.lowercase_email_local_part {
+        "select uid, public_key from users where lower(email) = lower(?) order by uid"
+    } else {
+        "select uid, public_key from users where email = ? order by uid"
+    };
+    let rows: Vec<(String, String)> = sqlx::query_as(sql)
+        .bind(&index.email)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(internal_error)?;
+    let users = rows
+        .into_iter()
+        .map(|(key_id, public_key)| {
+            Ok(FoundUser {
+                key_id,
+                user_id: primary_user_id(&parse_stored_key(&public_key)?),
+            })
+        })
+        .collect::<anyhow::Result<_>>()
+        .map_err(internal_error)?;
+    Ok(Json(users))
+}
+
+/// The Web Key Directory "advanced" lookup: the binary public key for
+/// `hash` at `domain`.
+pub async fn handle_wkd_lookup(
+    State(state): State<AppState>,
+    Path((domain, hash)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let public_key: Option<String> = sqlx::query_scalar(
+        "select public_key from users where email_domain = ? and wkd_hash = ? order by uid",
+    )
+    .bind(domain.to_lowercase())
+    .bind(hash)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal_error)?;
+    let Some(public_key) = public_key else {
+        return Err(AppError::Status(
+            StatusCode::NOT_FOUND,
+            "no key for that address".to_string(),
+        ));
+    };
+    let key = parse_stored_key(&public_key)
+        .and_then(|key| Ok(key.to_bytes()?))
+        .map_err(internal_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], key))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use pgp::{
+        composed::{Deserializable, SignedPublicKey},
+        types::KeyDetails,
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        key_id_to_text,
+        test_util::{TestClient, get, test_app},
+    };
+
+    #[test]
+    fn test_wkd_hash() {
+        // the example from the WKD draft
+        assert_eq!(wkd_hash("Joe.Doe"), "iy9q119eutrkn8s1mk4r39qejnbu3n5q");
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_emails_are_findable() {
+        for lowercase_email_local_part in [false, true] {
+            let config = Config {
+                lowercase_email_local_part,
+                ..Config::default()
+            };
+            let (app, _pool) = test_app(config).await;
+            let joe = TestClient::new(&app, "Joe Doe <Joe.Doe@Example.ORG>");
+            joe.create_account().await;
+            let lookup = async |email: &str| -> Value {
+                let (status, body) = get(&app, &format!("/keys/lookup?email={email}")).await;
+                assert_eq!(status, StatusCode::OK, "{body}");
+                serde_json::from_str(&body).unwrap()
+            };
+
+            let found = lookup("Joe.Doe@EXAMPLE.org").await;
+            assert_eq!(found[0]["key_id"], key_id_to_text(&joe.key_id()));
+            assert_eq!(found[0]["user_id"], "Joe Doe <Joe.Doe@Example.ORG>");
+            let found = lookup("joe.doe@example.org").await;
+            assert_eq!(
+                found.as_array().unwrap().len(),
+                usize::from(lowercase_email_local_part)
+            );
+
+            let uri = format!(
+                "/.well-known/openpgpkey/example.org/hu/{}",
+                wkd_hash("joe.doe")
+            );
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let key = SignedPublicKey::from_bytes(&bytes[..]).unwrap();
+            assert_eq!(key.fingerprint(), joe.key.fingerprint());
+        }
+    }
+}